@@ -0,0 +1,35 @@
+//! Reseed the random number generator of the kernel with randomness from the host.
+//!
+//! All VMs restored from the same snapshot start out with the same state of the random number generator, so they would
+//! hand out the same random numbers until the kernel reseeds it on its own.
+
+use std::{fs::File, io, os::fd::AsRawFd};
+
+/// `_IOW('R', 0x03, int[2])`, adds entropy and credits it, see random(4).
+const RNDADDENTROPY: u32 = 0x4008_5203;
+/// `_IO('R', 0x07)`, reseeds the CRNG from the entropy pool right away.
+const RNDRESEEDCRNG: u32 = 0x5207;
+
+/// Mix `seed` into the entropy pool, crediting it fully, and reseed the CRNG from it.
+pub fn reseed(seed: &[u8]) -> io::Result<()> {
+    let len = i32::try_from(seed.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Seed too large"))?;
+    // struct rand_pool_info { int entropy_count; int buf_size; __u32 buf[]; }
+    let mut info = Vec::with_capacity(8 + seed.len());
+    info.extend_from_slice(&(len * 8).to_ne_bytes());
+    info.extend_from_slice(&len.to_ne_bytes());
+    info.extend_from_slice(seed);
+
+    let random = File::options().write(true).open("/dev/random")?;
+    // SAFETY: Both ioctls are given a valid file descriptor, RNDADDENTROPY reads a rand_pool_info with `buf_size` bytes
+    // of payload from `info`, which lives until the call returns.
+    unsafe {
+        if libc::ioctl(random.as_raw_fd(), RNDADDENTROPY as _, info.as_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(random.as_raw_fd(), RNDRESEEDCRNG as _) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
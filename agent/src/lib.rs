@@ -0,0 +1,203 @@
+//! Protocol spoken between codepot on the host and the agent running inside of every VM.
+//!
+//! The host opens one vsock connection per operation and sends a single [`Request`], which the agent answers with one
+//! or more [`Response`]s. Both directions use frames made up of a JSON header and a binary payload (e.g. the output of
+//! a command or the contents of a file), each prefixed by its length as big endian `u32`.
+
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Vsock port the agent listens on.
+pub const PORT: u32 = 52;
+
+/// Upper bound for the header of a frame, to not allocate arbitrary amounts of memory for a broken frame.
+const MAX_HEADER_LEN: u32 = 64 * 1024;
+/// Upper bound for the payload of a frame.
+const MAX_DATA_LEN: u32 = 64 * 1024 * 1024;
+
+/// Sent by the host to start an operation, or while a command runs to feed its stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// Check that the agent is alive, answered with [`Response::Pong`].
+    Ping,
+    /// Run a command. Answered with [`Response::Stdout`] and [`Response::Stderr`] frames carrying the output as payload
+    /// while it runs, and [`Response::Exited`] once it exited. The host may send [`Request::Stdin`] and
+    /// [`Request::CloseStdin`] in the meantime.
+    Exec(ExecRequest),
+    /// Input for the running command, carried as payload.
+    Stdin,
+    /// Close the stdin of the running command.
+    CloseStdin,
+    /// Write the payload to a file, answered with [`Response::Done`].
+    WriteFile {
+        path: PathBuf,
+        mode: u32,
+        /// User and group owning the file, root if not set.
+        owner: Option<(u32, u32)>,
+    },
+    /// Read a file, answered with [`Response::File`] carrying its contents as payload.
+    ReadFile { path: PathBuf },
+    /// Mix the payload into the entropy pool of the guest and reseed its random number generator, answered with
+    /// [`Response::Done`].
+    Reseed,
+}
+
+/// A command to run inside of the guest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecRequest {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Variables added to the environment of the agent.
+    pub env: Vec<(String, String)>,
+    /// Working directory, the one of the agent if not set.
+    pub cwd: Option<PathBuf>,
+    /// User and group to run the command as, root if not set.
+    pub user: Option<(u32, u32)>,
+}
+
+/// Sent by the agent in reply to a [`Request`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Pong,
+    /// Output of the running command, carried as payload.
+    Stdout,
+    /// Error output of the running command, carried as payload.
+    Stderr,
+    /// The command exited, either with an exit code or killed by a signal.
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// The operation succeeded.
+    Done,
+    /// Contents of a file, carried as payload.
+    File,
+    /// The operation failed.
+    Error {
+        message: String,
+    },
+}
+
+/// Write a frame with the given header and payload.
+pub fn write_frame(
+    writer: &mut impl Write,
+    header: &impl Serialize,
+    data: &[u8],
+) -> io::Result<()> {
+    let header = serde_json::to_vec(header)?;
+    let mut frame = Vec::with_capacity(8 + header.len() + data.len());
+    frame.extend_from_slice(&len_prefix(header.len())?);
+    frame.extend_from_slice(&len_prefix(data.len())?);
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(data);
+    // A single write keeps frames intact when multiple threads share a connection.
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Read a frame, returning `None` if the connection was closed before it started.
+pub fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> io::Result<Option<(T, Vec<u8>)>> {
+    let mut lens = [0; 8];
+    let mut read = 0;
+    while read < lens.len() {
+        match reader.read(&mut lens[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    let header_len = u32::from_be_bytes(lens[..4].try_into().unwrap());
+    let data_len = u32::from_be_bytes(lens[4..].try_into().unwrap());
+    if header_len > MAX_HEADER_LEN || data_len > MAX_DATA_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame too large ({header_len} bytes header, {data_len} bytes payload)"),
+        ));
+    }
+
+    let mut header = vec![0; header_len as usize];
+    reader.read_exact(&mut header)?;
+    let mut data = vec![0; data_len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Some((serde_json::from_slice(&header)?, data)))
+}
+
+fn len_prefix(len: usize) -> io::Result<[u8; 4]> {
+    u32::try_from(len)
+        .map(u32::to_be_bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Frame too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(header_len: u32, data_len: u32, rest: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&header_len.to_be_bytes());
+        frame.extend_from_slice(&data_len.to_be_bytes());
+        frame.extend_from_slice(rest);
+        frame
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let request = Request::Exec(ExecRequest {
+            program: "sh".to_owned(),
+            args: vec!["-c".to_owned(), "cat".to_owned()],
+            env: vec![("HOME".to_owned(), "/root".to_owned())],
+            cwd: Some("/tmp".into()),
+            user: Some((1000, 1000)),
+        });
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &request, b"").unwrap();
+        write_frame(&mut buf, &Request::Stdin, b"\0binary\xff").unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            Some((request, Vec::new()))
+        );
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            Some((Request::Stdin, b"\0binary\xff".to_vec()))
+        );
+        assert_eq!(read_frame::<Request>(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        for (header_len, data_len) in [(MAX_HEADER_LEN + 1, 0), (2, MAX_DATA_LEN + 1)] {
+            let frame = frame(header_len, data_len, b"{}");
+            let err = read_frame::<Response>(&mut frame.as_slice()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let header = br#"{"type":"done"}"#;
+        let frame = frame(header.len() as u32, 0, header);
+        assert_eq!(
+            read_frame(&mut frame.as_slice()).unwrap(),
+            Some((Response::Done, Vec::new()))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &Response::Stdout, b"output").unwrap();
+        // Only a connection closed before a frame starts is a clean end, anything later is an error.
+        for len in 1..buf.len() {
+            let err = read_frame::<Response>(&mut &buf[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{len} bytes");
+        }
+        assert_eq!(read_frame::<Response>(&mut &buf[..0]).unwrap(), None);
+    }
+}
@@ -0,0 +1,221 @@
+//! Agent running inside of every VM, which runs commands and transfers files for the host over vsock.
+//!
+//! Every connection is handled on its own thread. Errors are reported to the host where possible and logged to stderr,
+//! which OpenRC redirects to a log file.
+//!
+//! Run as `codepot-agent metadata <path>`, it prints a value of the metadata published by the host instead.
+
+mod entropy;
+mod metadata;
+
+use std::{
+    fs::{File, Permissions},
+    io::{self, Read, Write},
+    os::unix::{
+        fs::{chown, OpenOptionsExt, PermissionsExt},
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::Path,
+    process::{ChildStdin, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use codepot_agent::{read_frame, write_frame, ExecRequest, Request, Response, PORT};
+use vsock::{VsockListener, VsockStream, VMADDR_CID_ANY};
+
+/// Size of the chunks the output of commands is forwarded in.
+const OUTPUT_CHUNK_SIZE: usize = 16 * 1024;
+
+fn main() -> io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    if let Some(command) = args.next() {
+        return match (command.as_str(), args.next()) {
+            ("metadata", Some(path)) => metadata::print(&path),
+            _ => Err(io::Error::other("Usage: codepot-agent [metadata <path>]")),
+        };
+    }
+
+    let listener = VsockListener::bind_with_cid_port(VMADDR_CID_ANY, PORT)?;
+    eprintln!("Listening on vsock port {PORT}");
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Could not accept connection: {err}");
+                continue;
+            }
+        };
+        thread::spawn(move || {
+            if let Err(err) = handle(stream) {
+                eprintln!("Could not handle connection: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Handle a single operation of the host.
+fn handle(mut stream: VsockStream) -> io::Result<()> {
+    let Some((request, data)) = read_frame::<Request>(&mut stream)? else {
+        return Ok(());
+    };
+    match request {
+        Request::Ping => write_frame(&mut stream, &Response::Pong, &[]),
+        Request::Exec(request) => exec(stream, request),
+        Request::WriteFile { path, mode, owner } => match write_file(&path, &data, mode, owner) {
+            Ok(()) => write_frame(&mut stream, &Response::Done, &[]),
+            Err(err) => reply_error(
+                &mut stream,
+                format!("Could not write {}: {err}", path.display()),
+            ),
+        },
+        Request::ReadFile { path } => match std::fs::read(&path) {
+            Ok(contents) => write_frame(&mut stream, &Response::File, &contents),
+            Err(err) => reply_error(
+                &mut stream,
+                format!("Could not read {}: {err}", path.display()),
+            ),
+        },
+        Request::Reseed => match entropy::reseed(&data) {
+            Ok(()) => write_frame(&mut stream, &Response::Done, &[]),
+            Err(err) => reply_error(&mut stream, format!("Could not reseed: {err}")),
+        },
+        Request::Stdin | Request::CloseStdin => {
+            reply_error(&mut stream, "No command running".to_owned())
+        }
+    }
+}
+
+fn reply_error(stream: &mut VsockStream, message: String) -> io::Result<()> {
+    write_frame(stream, &Response::Error { message }, &[])
+}
+
+fn write_file(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> io::Result<()> {
+    let mut file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    file.write_all(contents)?;
+    if let Some((uid, gid)) = owner {
+        chown(path, Some(uid), Some(gid))?;
+    }
+    // The mode given when opening only applies to new files and is masked by the umask, and changing the owner clears
+    // the setuid and setgid bits.
+    file.set_permissions(Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+/// Run a command, forwarding its stdin, stdout and stderr over the connection until it exits.
+fn exec(mut stream: VsockStream, request: ExecRequest) -> io::Result<()> {
+    let mut command = Command::new(&request.program);
+    command
+        .args(&request.args)
+        .envs(request.env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(cwd) = &request.cwd {
+        command.current_dir(cwd);
+    }
+    if let Some((uid, gid)) = request.user {
+        command.uid(uid).gid(gid);
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            return reply_error(
+                &mut stream,
+                format!("Could not run {}: {err}", request.program),
+            );
+        }
+    };
+
+    // Stdin is fed until the host closes it or the connection. The thread is not joined, it ends once the connection is
+    // shut down below.
+    {
+        let stream = stream.try_clone()?;
+        let stdin = child.stdin.take();
+        thread::spawn(move || forward_stdin(stream, stdin));
+    }
+
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let forwarders: Vec<_> = [
+        child
+            .stdout
+            .take()
+            .map(|o| Box::new(o) as Box<dyn Read + Send>),
+        child
+            .stderr
+            .take()
+            .map(|o| Box::new(o) as Box<dyn Read + Send>),
+    ]
+    .into_iter()
+    .zip([Response::Stdout, Response::Stderr])
+    .filter_map(|(output, header)| {
+        let writer = writer.clone();
+        output.map(|output| thread::spawn(move || forward_output(output, header, &writer)))
+    })
+    .collect();
+    for forwarder in forwarders {
+        if let Err(err) = forwarder.join().unwrap() {
+            eprintln!("Could not read output of {}: {err}", request.program);
+        }
+    }
+
+    let status = child.wait()?;
+    write_frame(
+        &mut *writer.lock().unwrap(),
+        &Response::Exited {
+            code: status.code(),
+            signal: status.signal(),
+        },
+        &[],
+    )?;
+    stream.shutdown(std::net::Shutdown::Both)
+}
+
+fn forward_stdin(mut stream: VsockStream, mut stdin: Option<ChildStdin>) {
+    while let Ok(Some((request, data))) = read_frame::<Request>(&mut stream) {
+        match request {
+            Request::Stdin => {
+                if let Some(input) = &mut stdin {
+                    // The command might not read its input, which is not an error.
+                    if input.write_all(&data).is_err() {
+                        stdin = None;
+                    }
+                }
+            }
+            Request::CloseStdin => stdin = None,
+            _ => eprintln!("Unexpected request while a command runs: {request:?}"),
+        }
+    }
+}
+
+/// Forward the output of a command until it closes it. If the host went away, the output is drained anyway, so that the
+/// command does not block on a full pipe.
+fn forward_output(
+    mut output: Box<dyn Read + Send>,
+    header: Response,
+    writer: &Mutex<VsockStream>,
+) -> io::Result<()> {
+    let mut buffer = vec![0; OUTPUT_CHUNK_SIZE];
+    let mut connected = true;
+    loop {
+        let len = output.read(&mut buffer)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if connected && write_frame(&mut *writer.lock().unwrap(), &header, &buffer[..len]).is_err()
+        {
+            connected = false;
+        }
+    }
+}
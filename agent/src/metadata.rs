@@ -0,0 +1,84 @@
+//! Client for the metadata the host publishes through firecracker's MMDS (version 2).
+//!
+//! MMDS is served by firecracker on the network interface of the guest, see
+//! https://github.com/firecracker-microvm/firecracker/blob/main/docs/mmds/mmds-user-guide.md. It is only reachable
+//! with a route to it, which the `codepot-metadata` wrapper script sets up.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use serde_json::Value;
+
+const ADDRESS: Ipv4Addr = Ipv4Addr::new(169, 254, 169, 254);
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Every lookup gets a new session token, so it does not have to live long.
+const TOKEN_TTL_SECS: u32 = 60;
+
+/// Print the value at the given `/` separated path: strings as they are, arrays with one element per line and
+/// everything else as JSON.
+pub fn print(path: &str) -> io::Result<()> {
+    let token = request(
+        "PUT",
+        "/latest/api/token",
+        &format!("X-metadata-token-ttl-seconds: {TOKEN_TTL_SECS}"),
+    )?;
+    let value = request(
+        "GET",
+        &format!("/{}", path.trim_start_matches('/')),
+        &format!(
+            "X-metadata-token: {}\r\nAccept: application/json",
+            token.trim()
+        ),
+    )?;
+    let value: Value = serde_json::from_str(&value)?;
+
+    let mut stdout = io::stdout().lock();
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                writeln!(stdout, "{}", plain(value))?;
+            }
+        }
+        value => writeln!(stdout, "{}", plain(value))?,
+    }
+    Ok(())
+}
+
+fn plain(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+/// Send a request with the given extra headers and return the body of a successful response.
+fn request(method: &str, path: &str, headers: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect_timeout(&SocketAddr::from((ADDRESS, 80)), TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\n\
+         Host: {ADDRESS}\r\n\
+         Connection: close\r\n\
+         Content-Length: 0\r\n\
+         {headers}\r\n\
+         \r\n"
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid response"))?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(io::Error::other(format!(
+            "{method} {path} failed with status {status}: {}",
+            body.trim()
+        )));
+    }
+    Ok(body.to_owned())
+}
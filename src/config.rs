@@ -1,12 +1,24 @@
-use std::{fs::File, io::Write, path::Path};
+use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
 
-use color_eyre::Result;
+use color_eyre::{
+    eyre::{ensure, eyre, Context},
+    Result,
+};
 
 use ipnet::Ipv4Net;
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, warn};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::{
+    host::{self, Arch},
+    languages::Toolchains,
+    machine::config::{
+        CpuTemplate, FileEngineType, MachineConfig, RateLimiterConfig, RateLimits, RootFs,
+        TokenBucketConfig,
+    },
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InterfaceConfig {
     pub if_name: String,
     pub ip_address: Ipv4Net,
@@ -23,35 +35,358 @@ impl InterfaceConfig {
     }
 }
 
+fn default_idle_timeout_secs() -> u64 {
+    600
+}
+
+/// Limits that keep a single VM from starving the others, while allowing short bursts (e.g. for downloading crates).
+fn default_rate_limits() -> RateLimits {
+    const MIB: u64 = 1024 * 1024;
+    let bandwidth = |per_sec, burst| RateLimiterConfig {
+        bandwidth: Some(TokenBucketConfig {
+            size: per_sec,
+            one_time_burst: Some(burst),
+            refill_time: 1000,
+        }),
+        ops: None,
+    };
+    RateLimits {
+        drive: Some(RateLimiterConfig {
+            ops: Some(TokenBucketConfig {
+                size: 5000,
+                one_time_burst: Some(20000),
+                refill_time: 1000,
+            }),
+            ..bandwidth(100 * MIB, 500 * MIB)
+        }),
+        net_rx: Some(bandwidth(20 * MIB, 200 * MIB)),
+        net_tx: Some(bandwidth(10 * MIB, 50 * MIB)),
+        // Plenty for seeding random number generators, but not enough to drain the entropy of the host.
+        entropy: Some(bandwidth(64 * 1024, MIB)),
+    }
+}
+
+/// Resources and limits of a VM, chosen by name when starting a session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub vcpu_count: u8,
+    pub mem_size_mib: usize,
+    /// Expose hyperthreads to the guest, needs an even number of vCPUs.
+    #[serde(default)]
+    pub smt: bool,
+    /// Firecracker CPU template, so that the guest sees the same CPU features on every host of a fleet.
+    #[serde(default)]
+    pub cpu_template: Option<CpuTemplate>,
+    /// IO engine of the drives.
+    #[serde(default)]
+    pub io_engine: FileEngineType,
+    /// IO engines of single drives by drive ID (`rootfs` or `home`), overriding `io_engine`.
+    #[serde(default)]
+    pub drive_io_engines: BTreeMap<String, FileEngineType>,
+    /// Disk and network limits.
+    #[serde(default = "default_rate_limits")]
+    pub rate_limits: RateLimits,
+    /// Seconds without activity after which a VM is torn down.
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl Profile {
+    /// Maximum number of vCPUs firecracker supports.
+    const MAX_VCPU_COUNT: u8 = 32;
+    /// Firecracker needs the guest memory to be a multiple of the huge page size to back it by huge pages, so insist
+    /// on it right away.
+    const MEM_ALIGNMENT_MIB: usize = 2;
+    /// Memory a guest booted from an initrd needs besides the unpacked initrd.
+    const INITRD_HEADROOM_MIB: usize = 128;
+
+    /// The firecracker machine config for this profile.
+    pub fn machine_config(&self) -> MachineConfig {
+        MachineConfig {
+            vcpu_count: self.vcpu_count,
+            mem_size_mib: self.mem_size_mib,
+            smt: self.smt,
+            // Set by `MachineConfigurator::set_cpu_template`, as custom templates are configured separately.
+            cpu_template: None,
+            track_dirty_pages: false, // Only needed for diff snapshots
+        }
+    }
+
+    /// IO engine of the drive with the given ID.
+    pub fn io_engine(&self, drive_id: &str) -> FileEngineType {
+        self.drive_io_engines
+            .get(drive_id)
+            .copied()
+            .unwrap_or(self.io_engine)
+    }
+
+    /// Use blocking IO instead of io_uring for all drives, returning whether any drive used io_uring.
+    fn disable_io_uring(&mut self) -> bool {
+        let mut changed = false;
+        for engine in std::iter::once(&mut self.io_engine).chain(self.drive_io_engines.values_mut())
+        {
+            if *engine == FileEngineType::Async {
+                *engine = FileEngineType::Sync;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Check that firecracker accepts the profile and that the host can run a VM with it from `root_fs`.
+    pub fn validate(&self, root_fs: &RootFs) -> Result<()> {
+        ensure!(
+            (1..=Self::MAX_VCPU_COUNT).contains(&self.vcpu_count),
+            "vCPU count has to be between 1 and {}",
+            Self::MAX_VCPU_COUNT
+        );
+        ensure!(
+            !self.smt || host::ARCH == Arch::X86_64,
+            "SMT is only supported on x86_64"
+        );
+        ensure!(
+            !self.smt || self.vcpu_count == 1 || self.vcpu_count.is_multiple_of(2),
+            "vCPU count has to be 1 or even with SMT enabled"
+        );
+        ensure!(
+            self.mem_size_mib > 0 && self.mem_size_mib.is_multiple_of(Self::MEM_ALIGNMENT_MIB),
+            "Memory size has to be a positive multiple of {} MiB",
+            Self::MEM_ALIGNMENT_MIB
+        );
+
+        let host_cpus = std::thread::available_parallelism()?.get();
+        ensure!(
+            usize::from(self.vcpu_count) <= host_cpus,
+            "{} vCPUs requested, but the host only has {host_cpus} CPUs",
+            self.vcpu_count
+        );
+        let host_mem_mib = host::memory_mib()?;
+        ensure!(
+            self.mem_size_mib <= host_mem_mib,
+            "{} MiB of memory requested, but the host only has {host_mem_mib} MiB",
+            self.mem_size_mib
+        );
+
+        match &self.cpu_template {
+            Some(CpuTemplate::Static(template)) => {
+                let vendor = host::cpu_vendor()?;
+                ensure!(
+                    vendor.as_deref() == template.vendor(),
+                    "CPU template {template:?} is not made for the CPUs of the host ({})",
+                    vendor.as_deref().unwrap_or("unknown vendor")
+                );
+            }
+            Some(CpuTemplate::Custom { custom }) => {
+                // Firecracker runs inside of the directory of the VM.
+                ensure!(
+                    custom.is_absolute(),
+                    "Path of custom CPU template {} has to be absolute",
+                    custom.display()
+                );
+                let template = std::fs::read_to_string(custom).with_context(|| {
+                    format!("Could not read custom CPU template {}", custom.display())
+                })?;
+                serde_json::from_str::<serde_json::Value>(&template).with_context(|| {
+                    format!("Custom CPU template {} is not valid JSON", custom.display())
+                })?;
+            }
+            None => {}
+        }
+
+        if let RootFs::Initrd { initrd_path, .. } = root_fs {
+            // The kernel unpacks the initrd into memory, for which it needs the memory of the initrd once more.
+            let initrd_mib = std::fs::metadata(initrd_path)
+                .with_context(|| format!("Could not read initrd {}", initrd_path.display()))?
+                .len()
+                .div_ceil(1024 * 1024) as usize;
+            let needed_mib = 2 * initrd_mib + Self::INITRD_HEADROOM_MIB;
+            ensure!(
+                needed_mib <= self.mem_size_mib,
+                "Booting from the initrd of {initrd_mib} MiB needs at least {needed_mib} MiB of memory, but only {} \
+                 MiB are configured",
+                self.mem_size_mib
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_profile_name() -> String {
+    "small".to_owned()
+}
+
+/// Profiles for general use, building Rust projects and classes, where students might read for a while before they
+/// do something.
+fn default_profiles() -> BTreeMap<String, Profile> {
+    const MIB: u64 = 1024 * 1024;
+    let small = Profile {
+        vcpu_count: 2,
+        mem_size_mib: 512,
+        smt: false,
+        cpu_template: None,
+        io_engine: FileEngineType::Sync,
+        drive_io_engines: BTreeMap::new(),
+        rate_limits: default_rate_limits(),
+        idle_timeout_secs: default_idle_timeout_secs(),
+    };
+    let mut rust_build = Profile {
+        vcpu_count: 4,
+        mem_size_mib: 2048,
+        // Builds hammer the drive, which io_uring copes with better.
+        io_engine: FileEngineType::Async,
+        idle_timeout_secs: 30 * 60,
+        ..small.clone()
+    };
+    if let Some(drive) = &mut rust_build.rate_limits.drive {
+        drive.bandwidth = Some(TokenBucketConfig {
+            size: 200 * MIB,
+            one_time_burst: Some(1000 * MIB),
+            refill_time: 1000,
+        });
+    }
+    let classroom = Profile {
+        vcpu_count: 1,
+        mem_size_mib: 256,
+        idle_timeout_secs: 45 * 60,
+        ..small.clone()
+    };
+    BTreeMap::from([
+        (default_profile_name(), small),
+        ("rust-build".to_owned(), rust_build),
+        ("classroom".to_owned(), classroom),
+    ])
+}
+
+/// Configs written before aarch64 was supported are all for x86_64 hosts.
+fn default_arch() -> Arch {
+    Arch::X86_64
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     // pub guest_default_username: String,
     // pub guest_default_password: String,
+    /// Architecture the kernel and the images were built for.
+    #[serde(default = "default_arch")]
+    pub arch: Arch,
     pub max_parallel_vm_count: usize,
     pub net: Ipv4Net,
     pub host_ifname: String,
     /// Address of the bridge on the host
     pub host_address: Ipv4Net,
     pub interfaces: Vec<InterfaceConfig>,
+    /// Public keys authorized to log into the guest user of every VM over SSH.
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+    /// Machine profiles by name.
+    #[serde(default = "default_profiles")]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Profile of sessions that do not ask for a specific one.
+    #[serde(default = "default_profile_name")]
+    pub default_profile: String,
+    /// Toolchains installed into the images by the names of their languages, the defaults of the languages if not
+    /// recorded.
+    #[serde(default)]
+    pub toolchains: Toolchains,
+    /// Versions of the toolchains installed into the images by the names of their languages, left out if the images
+    /// were built before.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub language_versions: BTreeMap<String, String>,
 }
 
 impl Config {
+    /// Config for images built for the architecture of the host.
     pub fn new(
         max_parallel_vm_count: usize,
         net: Ipv4Net,
         host_ifname: String,
         host_address: Ipv4Net,
         interfaces: Vec<InterfaceConfig>,
+        idle_timeout_secs: Option<u64>,
+        ssh_keys: Vec<String>,
     ) -> Self {
+        let mut profiles = default_profiles();
+        if let Some(idle_timeout_secs) = idle_timeout_secs {
+            for profile in profiles.values_mut() {
+                profile.idle_timeout_secs = idle_timeout_secs;
+            }
+        }
         Self {
+            arch: host::ARCH,
             max_parallel_vm_count,
             net,
             host_ifname,
             host_address,
             interfaces,
+            ssh_keys,
+            profiles,
+            default_profile: default_profile_name(),
+            toolchains: Toolchains::new(),
+            language_versions: BTreeMap::new(),
         }
     }
 
+    /// Check that the images were built for the architecture of the host, firecracker cannot boot them otherwise.
+    pub fn check_arch(&self) -> Result<()> {
+        ensure!(
+            self.arch == host::ARCH,
+            "Images were built for {}, but the host is {}, please run `codepot deinit --all` and `codepot init` to rebuild them",
+            self.arch,
+            host::ARCH
+        );
+        Ok(())
+    }
+
+    /// Look up a profile, the default one if `name` is not given.
+    pub fn profile(&self, name: Option<&str>) -> Result<(&str, &Profile)> {
+        let name = name.unwrap_or(&self.default_profile);
+        self.profiles
+            .get_key_value(name)
+            .map(|(name, profile)| (name.as_str(), profile))
+            .ok_or_else(|| eyre!("No profile named {name} in the config"))
+    }
+
+    /// Firecracker cannot start VMs with drives using io_uring if the host does not support it, so use blocking IO
+    /// instead there.
+    pub fn fall_back_to_sync_io(&mut self) {
+        if host::supports_io_uring() {
+            return;
+        }
+        for (name, profile) in &mut self.profiles {
+            if profile.disable_io_uring() {
+                warn!("Host does not support io_uring, using sync IO for the drives of profile {name}");
+            }
+        }
+    }
+
+    /// Check the profile `selected`, the default one if not given, see `Profile::validate`. The other profiles are
+    /// only checked to drop the ones the host cannot run with a warning, so that e.g. a profile for big hosts does not
+    /// keep small ones from serving the rest.
+    pub fn retain_valid_profiles(
+        &mut self,
+        selected: Option<&str>,
+        root_fs: &RootFs,
+    ) -> Result<()> {
+        let (selected, profile) = self.profile(selected)?;
+        profile
+            .validate(root_fs)
+            .with_context(|| format!("Invalid profile {selected}"))?;
+        let selected = selected.to_owned();
+        self.profiles.retain(|name, profile| {
+            if *name == selected {
+                return true;
+            }
+            match profile.validate(root_fs) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!("Not serving profile {name}: {err}");
+                    false
+                }
+            }
+        });
+        Ok(())
+    }
+
     pub fn read(path: impl AsRef<Path>) -> Result<Self> {
         let contents = std::fs::read_to_string(path.as_ref())?;
         debug!("Read config {contents} from {}", path.as_ref().display());
@@ -62,7 +397,7 @@ impl Config {
         let contents = serde_json::to_string(self)?;
         let mut file = File::create_new(path.as_ref())?;
         debug!("Writing config {contents} to {}", path.as_ref().display());
-        file.write_all(&contents.as_bytes())?;
+        file.write_all(contents.as_bytes())?;
         Ok(())
     }
 }
@@ -0,0 +1,459 @@
+//! Diagnostics of the compilers, i.e. errors and warnings with the position in the sources they refer to and the fixes
+//! the compiler suggests, for editors to show them inline and offer quick fixes.
+//!
+//! The compilers are asked for machine-readable output where they offer it: JSON for rustc and SARIF for clang. Go and
+//! Zig only print text in the format of GCC, which is parsed. Either way the diagnostics are normalized into
+//! `Diagnostic`, with one-based lines and columns and paths relative to the working directory, see `relative_path`.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::execution::WORK_DIR;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+    Help,
+}
+
+impl Severity {
+    /// Parse the severities of GCC, rustc and SARIF.
+    pub fn parse(severity: &str) -> Option<Self> {
+        match severity {
+            "error" | "fatal error" | "failure-note" => Some(Self::Error),
+            "warning" => Some(Self::Warning),
+            "note" | "none" => Some(Self::Note),
+            "help" => Some(Self::Help),
+            _ if severity.starts_with("error") => Some(Self::Error),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Note => "note",
+            Self::Help => "help",
+        }
+    }
+}
+
+/// A range of a source, from `line`:`column` up to `end_line`:`end_column`, exclusive.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    /// Path relative to the working directory.
+    pub file: Option<String>,
+    /// One-based line.
+    pub line: Option<u32>,
+    /// One-based column.
+    pub column: Option<u32>,
+    pub end_line: Option<u32>,
+    pub end_column: Option<u32>,
+}
+
+/// Text that replaces a span.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replacement {
+    #[serde(flatten)]
+    pub span: Span,
+    pub text: String,
+}
+
+/// A fix of a diagnostic suggested by the compiler, made up of all of its replacements.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suggestion {
+    pub message: String,
+    pub replacements: Vec<Replacement>,
+}
+
+/// A diagnostic of a compiler.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// Where the diagnostic points to. The file is `None` for diagnostics about the whole build, e.g. linker errors.
+    #[serde(flatten)]
+    pub span: Span,
+    pub severity: Severity,
+    pub message: String,
+    /// Code of the diagnostic, e.g. `E0425` or `-Wunused-variable`.
+    pub code: Option<String>,
+    /// Notes and help without a position of their own.
+    pub notes: Vec<String>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl Diagnostic {
+    fn new(span: Span, severity: Severity, message: String) -> Self {
+        Self {
+            span,
+            severity,
+            message,
+            code: None,
+            notes: Vec::new(),
+            suggestions: Vec::new(),
+        }
+    }
+
+    /// Render the diagnostic in the format of GCC.
+    fn render(&self) -> String {
+        let position = match (&self.span.file, self.span.line, self.span.column) {
+            (Some(file), Some(line), Some(column)) => format!("{file}:{line}:{column}: "),
+            (Some(file), Some(line), None) => format!("{file}:{line}: "),
+            (Some(file), None, _) => format!("{file}: "),
+            (None, _, _) => String::new(),
+        };
+        let code = self
+            .code
+            .as_ref()
+            .map(|code| format!(" [{code}]"))
+            .unwrap_or_default();
+        let mut rendered = format!(
+            "{position}{}: {}{code}\n",
+            self.severity.name(),
+            self.message
+        );
+        for note in &self.notes {
+            rendered.push_str(&format!("  note: {note}\n"));
+        }
+        for suggestion in &self.suggestions {
+            rendered.push_str(&format!("  fix: {}\n", suggestion.message));
+        }
+        rendered
+    }
+}
+
+/// Diagnostics parsed from the output of a compiler.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub diagnostics: Vec<Diagnostic>,
+    /// Human readable output, which is rendered from the diagnostics if the compiler printed them in a
+    /// machine-readable format.
+    pub stderr: String,
+}
+
+/// Path of a source relative to the working directory, from a path or `file://` URI of a compiler.
+pub fn relative_path(file: &str) -> String {
+    let path = Path::new(file.strip_prefix("file://").unwrap_or(file));
+    let path = path.strip_prefix(WORK_DIR).unwrap_or(path);
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.display().to_string()
+}
+
+/// Parse diagnostics in the format of GCC, which clang, Go and Zig use as well, e.g. `main.c:3:5: error: message`.
+/// Lines without a severity are errors, as Go does not print one. Diagnostics without a position look like
+/// `error: message` or `ld: error: message`. Lines that are not diagnostics, e.g. the quoted source, are skipped.
+pub fn parse_gcc_style(stderr: &str) -> Diagnostics {
+    Diagnostics {
+        diagnostics: stderr.lines().filter_map(parse_gcc_style_line).collect(),
+        stderr: stderr.to_owned(),
+    }
+}
+
+fn parse_gcc_style_line(line: &str) -> Option<Diagnostic> {
+    let severity_and_message = |rest: &str| {
+        let (severity, message) = rest.trim_start().split_once(": ")?;
+        Some((Severity::parse(severity)?, message.to_owned()))
+    };
+    let (first, rest) = line.split_once(':')?;
+    // `error: message`
+    if let Some(severity) = Severity::parse(first) {
+        return Some(Diagnostic::new(
+            Span::default(),
+            severity,
+            rest.trim().to_owned(),
+        ));
+    }
+    let Some((line_number, mut rest)) = rest
+        .split_once(':')
+        .and_then(|(line_number, rest)| Some((line_number.parse().ok()?, rest)))
+    else {
+        // `ld: error: message`
+        let (severity, message) = severity_and_message(rest)?;
+        return Some(Diagnostic::new(Span::default(), severity, message));
+    };
+    let mut column = None;
+    if let Some((number, after)) = rest.split_once(':') {
+        if let Ok(number) = number.parse() {
+            column = Some(number);
+            rest = after;
+        }
+    }
+    let (severity, message) =
+        severity_and_message(rest).unwrap_or((Severity::Error, rest.trim().to_owned()));
+    let span = Span {
+        file: Some(relative_path(first)),
+        line: Some(line_number),
+        column,
+        ..Default::default()
+    };
+    Some(Diagnostic::new(span, severity, message))
+}
+
+/// Parse the SARIF logs clang prints, one per compiled file, and the text of other tools in between, e.g. of the
+/// linker. See https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html for the format.
+pub fn parse_sarif(stderr: &str) -> Diagnostics {
+    let mut parsed = Diagnostics::default();
+    for line in stderr.lines() {
+        let log = line
+            .trim_start()
+            .starts_with('{')
+            .then(|| serde_json::from_str::<Value>(line).ok())
+            .flatten();
+        let Some(log) = log else {
+            parsed.diagnostics.extend(parse_gcc_style_line(line));
+            parsed.stderr.push_str(line);
+            parsed.stderr.push('\n');
+            continue;
+        };
+        let results = log["runs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|run| run["results"].as_array().into_iter().flatten());
+        for result in results {
+            let Some(diagnostic) = sarif_result(result) else {
+                continue;
+            };
+            parsed.stderr.push_str(&diagnostic.render());
+            parsed.diagnostics.push(diagnostic);
+        }
+    }
+    parsed
+}
+
+fn sarif_result(result: &Value) -> Option<Diagnostic> {
+    let severity = Severity::parse(result["level"].as_str().unwrap_or("warning"))?;
+    let message = result["message"]["text"].as_str()?.to_owned();
+    let span = result["locations"][0]["physicalLocation"]
+        .as_object()
+        .map(|location| sarif_span(&location["artifactLocation"], &location["region"]))
+        .unwrap_or_default();
+    let suggestions = result["fixes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|fix| Suggestion {
+            message: fix["description"]["text"]
+                .as_str()
+                .unwrap_or("Apply fix")
+                .to_owned(),
+            replacements: fix["artifactChanges"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|change| {
+                    let artifact = &change["artifactLocation"];
+                    change["replacements"].as_array().into_iter().flatten().map(
+                        move |replacement| Replacement {
+                            span: sarif_span(artifact, &replacement["deletedRegion"]),
+                            text: replacement["insertedContent"]["text"]
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_owned(),
+                        },
+                    )
+                })
+                .collect(),
+        })
+        .collect();
+    Some(Diagnostic {
+        code: result["ruleId"].as_str().map(str::to_owned),
+        suggestions,
+        ..Diagnostic::new(span, severity, message)
+    })
+}
+
+fn sarif_span(artifact: &Value, region: &Value) -> Span {
+    let number = |value: &Value| value.as_u64().and_then(|number| u32::try_from(number).ok());
+    let line = number(&region["startLine"]);
+    Span {
+        file: artifact["uri"].as_str().map(relative_path),
+        line,
+        column: number(&region["startColumn"]),
+        // SARIF regions end on their start line if not given.
+        end_line: number(&region["endLine"]).or(line),
+        end_column: number(&region["endColumn"]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn span(file: &str, line: u32, column: Option<u32>) -> Span {
+        Span {
+            file: Some(file.to_owned()),
+            line: Some(line),
+            column,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_go_errors() {
+        let stderr = "\
+# command-line-arguments
+./main.go:4:2: declared and not used: x
+./main.go:5:14: undefined: y
+";
+        let parsed = parse_gcc_style(stderr);
+        assert_eq!(parsed.stderr, stderr);
+        let [unused, undefined] = parsed.diagnostics.as_slice() else {
+            panic!("{:?}", parsed.diagnostics);
+        };
+        assert_eq!(unused.span, span("main.go", 4, Some(2)));
+        assert_eq!(unused.severity, Severity::Error);
+        assert_eq!(unused.message, "declared and not used: x");
+        assert_eq!(undefined.span, span("main.go", 5, Some(14)));
+        assert_eq!(undefined.message, "undefined: y");
+    }
+
+    #[test]
+    fn parses_zig_errors() {
+        let stderr = "\
+main.zig:4:5: error: use of undeclared identifier 'x'
+    x += 1;
+    ^
+main.zig:2:1: note: called from here
+pub fn main() void {
+^~~~~~~~~~~~~~~~~~
+referenced by:
+    callMain: /usr/local/zig/lib/std/start.zig:524:17
+error: ld.lld: undefined symbol: foo
+ld.lld: error: undefined symbol: bar
+";
+        let parsed = parse_gcc_style(stderr);
+        let diagnostics: Vec<_> = parsed
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                (
+                    &diagnostic.span,
+                    diagnostic.severity,
+                    diagnostic.message.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                (
+                    &span("main.zig", 4, Some(5)),
+                    Severity::Error,
+                    "use of undeclared identifier 'x'"
+                ),
+                (
+                    &span("main.zig", 2, Some(1)),
+                    Severity::Note,
+                    "called from here"
+                ),
+                (
+                    &Span::default(),
+                    Severity::Error,
+                    "ld.lld: undefined symbol: foo"
+                ),
+                (&Span::default(), Severity::Error, "undefined symbol: bar"),
+            ]
+        );
+    }
+
+    /// `clang -fdiagnostics-format=sarif` of a C file with an unused variable and a missing semicolon, with the linker
+    /// error of another file and the summary of clang.
+    const CLANG_SARIF: &str = concat!(
+        r#"{"$schema":"https://docs.oasis-open.org/sarif/sarif/v2.1.0/cos02/schemas/sarif-schema-2.1.0.json","#,
+        r#""runs":[{"artifacts":[{"length":-1,"location":{"index":0,"uri":"file:///tmp/codepot/main.c"},"#,
+        r#""mimeType":"text/plain","roles":["resultFile"]}],"columnKind":"unicodeCodePoints","results":["#,
+        r#"{"level":"warning","locations":[{"physicalLocation":{"artifactLocation":{"index":0,"#,
+        r#""uri":"file:///tmp/codepot/main.c"},"region":{"endColumn":10,"startColumn":9,"startLine":2}}}],"#,
+        r#""message":{"text":"unused variable 'x'"},"ruleId":"-Wunused-variable","ruleIndex":0},"#,
+        r#"{"fixes":[{"artifactChanges":[{"artifactLocation":{"index":0,"uri":"file:///tmp/codepot/main.c"},"#,
+        r#""replacements":[{"deletedRegion":{"endColumn":14,"startColumn":14,"startLine":3},"#,
+        r#""insertedContent":{"text":";"}}]}],"description":{"text":"insert ';'"}}],"level":"error","#,
+        r#""locations":[{"physicalLocation":{"artifactLocation":{"index":0,"uri":"file:///tmp/codepot/main.c"},"#,
+        r#""region":{"endColumn":14,"startColumn":14,"startLine":3}}}],"#,
+        r#""message":{"text":"expected ';' after return statement"},"ruleIndex":1}],"#,
+        r#""tool":{"driver":{"fullName":"","informationUri":"https://clang.llvm.org/docs/UsersManual.html","#,
+        r#""language":"en-US","name":"clang","version":"17.0.6"}}}],"version":"2.1.0"}"#,
+        "\n",
+        "1 warning and 1 error generated.\n",
+        "/usr/bin/ld: /tmp/util-2d1c3b.o: in function `helper':\n",
+        "util.c:(.text+0x5): undefined reference to `missing'\n",
+        "clang: error: linker command failed with exit code 1 (use -v to see invocation)\n",
+    );
+
+    #[test]
+    fn parses_clang_sarif() {
+        let parsed = parse_sarif(CLANG_SARIF);
+        assert_eq!(
+            parsed.stderr,
+            "\
+main.c:2:9: warning: unused variable 'x' [-Wunused-variable]
+main.c:3:14: error: expected ';' after return statement
+  fix: insert ';'
+1 warning and 1 error generated.
+/usr/bin/ld: /tmp/util-2d1c3b.o: in function `helper':
+util.c:(.text+0x5): undefined reference to `missing'
+clang: error: linker command failed with exit code 1 (use -v to see invocation)
+"
+        );
+
+        let [unused, semicolon, linker] = parsed.diagnostics.as_slice() else {
+            panic!("{:?}", parsed.diagnostics);
+        };
+        assert_eq!(
+            unused.span,
+            Span {
+                end_line: Some(2),
+                end_column: Some(10),
+                ..span("main.c", 2, Some(9))
+            }
+        );
+        assert_eq!(unused.severity, Severity::Warning);
+        assert_eq!(unused.code.as_deref(), Some("-Wunused-variable"));
+        assert!(unused.suggestions.is_empty());
+
+        assert_eq!(semicolon.severity, Severity::Error);
+        assert_eq!(semicolon.code, None);
+        let [suggestion] = semicolon.suggestions.as_slice() else {
+            panic!("{:?}", semicolon.suggestions);
+        };
+        assert_eq!(suggestion.message, "insert ';'");
+        let [replacement] = suggestion.replacements.as_slice() else {
+            panic!("{:?}", suggestion.replacements);
+        };
+        assert_eq!(
+            replacement.span,
+            Span {
+                end_line: Some(3),
+                end_column: Some(14),
+                ..span("main.c", 3, Some(14))
+            }
+        );
+        assert_eq!(replacement.text, ";");
+
+        assert_eq!(linker.span, Span::default());
+        assert_eq!(linker.severity, Severity::Error);
+        assert_eq!(
+            linker.message,
+            "linker command failed with exit code 1 (use -v to see invocation)"
+        );
+    }
+
+    #[test]
+    fn reads_sarif_results_without_location_or_level() {
+        let diagnostic =
+            sarif_result(&json!({ "message": { "text": "argument unused" } })).unwrap();
+        assert_eq!(diagnostic.span, Span::default());
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.message, "argument unused");
+
+        assert!(sarif_result(&json!({ "level": "error" })).is_none());
+    }
+}
@@ -0,0 +1,479 @@
+//! Assembly and intermediate representations emitted by the compilers, in the style of Compiler Explorer: every line of
+//! the output is mapped to the line of the source it was generated from, and the noise (directives, debug info,
+//! metadata) is filtered out.
+//!
+//! The compilers are told to emit debug info, from which the mapping is recovered: the `.file` and `.loc` directives of
+//! LLVM assembly, the `!dbg` locations of LLVM IR and the positions Go prints in front of every instruction. Symbols
+//! are demangled inside of the guest with `c++filt`, which knows both C++ and Rust symbols.
+
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// Output of the compiler to return instead of running the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Emit {
+    Asm,
+    LlvmIr,
+    /// Rust's mid-level IR.
+    Mir,
+}
+
+/// Compiler backend, which decides the format of the emitted code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Clang, rustc and zig.
+    Llvm,
+    /// The Go compiler, which has its own assembly syntax and does not mangle symbols.
+    Go,
+}
+
+/// Syntax of x86_64 assembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AsmSyntax {
+    Att,
+    Intel,
+}
+
+/// How the compiler output is processed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmitOptions {
+    /// Syntax of the assembly, the default of the compiler if not given. Go always uses its own syntax.
+    pub syntax: Option<AsmSyntax>,
+    pub demangle: bool,
+    /// Leave out directives, debug info and metadata.
+    pub filter: bool,
+}
+
+impl Default for EmitOptions {
+    fn default() -> Self {
+        Self {
+            syntax: None,
+            demangle: true,
+            filter: true,
+        }
+    }
+}
+
+/// Line of the sources some output was generated from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLine {
+    /// Path relative to the sources if it is one of them, e.g. `main.rs`, otherwise as given by the compiler, e.g. for
+    /// code inlined from the standard library.
+    pub file: Option<String>,
+    pub line: u32,
+}
+
+/// A line of the compiler output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmittedLine {
+    pub text: String,
+    pub source: Option<SourceLine>,
+}
+
+/// Map the lines of the output to the sources in `work_dir`, filtering them if asked to.
+pub fn process(
+    emit: Emit,
+    backend: Backend,
+    output: &str,
+    work_dir: &str,
+    filter: bool,
+) -> Vec<EmittedLine> {
+    let relative = |file: &str| {
+        Path::new(file)
+            .strip_prefix(work_dir)
+            .map(|file| file.display().to_string())
+            .unwrap_or_else(|_| file.to_owned())
+    };
+    match emit {
+        Emit::Asm if backend == Backend::Go => go_asm(output, relative, filter),
+        Emit::Asm => llvm_asm(output, relative, filter),
+        Emit::LlvmIr => llvm_ir(output, relative, filter),
+        Emit::Mir => output
+            .lines()
+            // The header warns that MIR is not stable.
+            .filter(|line| !(filter && line.starts_with("//")))
+            .map(|line| EmittedLine {
+                text: line.to_owned(),
+                source: None,
+            })
+            .collect(),
+    }
+}
+
+/// Assembly as emitted by LLVM.
+fn llvm_asm(output: &str, relative: impl Fn(&str) -> String, filter: bool) -> Vec<EmittedLine> {
+    let mut files = HashMap::new();
+    let mut current = None;
+    let mut in_debug_section = false;
+    let mut lines = Vec::new();
+    for line in output.lines() {
+        let trimmed = line.trim();
+        let mut words = trimmed.split_whitespace();
+        let directive = words.next().unwrap_or_default();
+        match directive {
+            ".file" => {
+                // `.file <index> ["<directory>"] "<name>" [md5 <checksum>]`
+                if let Some(index) = words.next().and_then(|index| index.parse::<u32>().ok()) {
+                    let quoted: Vec<_> = trimmed.split('"').skip(1).step_by(2).collect();
+                    let path = match quoted.as_slice() {
+                        [name] => name.to_string(),
+                        [directory, name, ..] => {
+                            Path::new(directory).join(name).display().to_string()
+                        }
+                        [] => continue,
+                    };
+                    files.insert(index, relative(&path));
+                }
+            }
+            ".loc" => {
+                let index = words.next().and_then(|index| index.parse::<u32>().ok());
+                let line = words.next().and_then(|line| line.parse::<u32>().ok());
+                current = match (index, line) {
+                    // Line 0 marks code that belongs to no line, e.g. compiler generated code.
+                    (_, Some(0)) | (_, None) => None,
+                    (index, Some(line)) => Some(SourceLine {
+                        file: index.and_then(|index| files.get(&index).cloned()),
+                        line,
+                    }),
+                };
+            }
+            ".section" | ".text" | ".data" | ".bss" => {
+                in_debug_section = trimmed.contains(".debug_");
+            }
+            _ => {}
+        }
+
+        // Labels may be followed by a comment, e.g. `main:  # @main` or `.LBB0_2:  // %if.end`.
+        let code = ["#", "//"]
+            .iter()
+            .fold(trimmed, |code, comment| {
+                code.split(comment).next().unwrap_or_default()
+            })
+            .trim_end();
+        let is_label = !line.starts_with(char::is_whitespace) && code.ends_with(':');
+        if is_label && !trimmed.starts_with(".L") {
+            // A new function starts.
+            current = None;
+        }
+        if filter {
+            let is_directive = trimmed.starts_with('.') && !is_label;
+            let is_comment = ["#", "//", ";", "@"]
+                .iter()
+                .any(|prefix| trimmed.starts_with(prefix));
+            let is_internal_label = is_label
+                && [
+                    ".Ltmp",
+                    ".Lfunc_begin",
+                    ".Lfunc_end",
+                    ".Lcfi",
+                    ".Ldebug",
+                    ".Linfo",
+                    ".Lsec",
+                    ".Lline",
+                ]
+                .iter()
+                .any(|prefix| trimmed.starts_with(prefix));
+            if in_debug_section
+                || trimmed.is_empty()
+                || is_directive
+                || is_comment
+                || is_internal_label
+            {
+                continue;
+            }
+        }
+        lines.push(EmittedLine {
+            text: line.to_owned(),
+            source: if is_label { None } else { current.clone() },
+        });
+    }
+    lines
+}
+
+/// Assembly as printed by the Go compiler, where every instruction starts with its position, e.g.
+/// `0x0000 00000 (/tmp/codepot/main.go:5) TEXT main.main(SB), ABIInternal, $40-0`.
+fn go_asm(output: &str, relative: impl Fn(&str) -> String, filter: bool) -> Vec<EmittedLine> {
+    let position = |line: &str| {
+        let start = line.find('(')?;
+        let end = start + line[start..].find(')')?;
+        let (file, line) = line[start + 1..end].rsplit_once(':')?;
+        Some(SourceLine {
+            file: Some(relative(file)),
+            line: line.parse().ok()?,
+        })
+    };
+    output
+        .lines()
+        .filter_map(|line| {
+            let source = position(line);
+            // Functions start with a header like `main.main STEXT size=103 args=0x0 locals=0x40`. Everything else
+            // without a position is machine code, relocations, data and the name of the package.
+            let is_function = !line.starts_with(char::is_whitespace) && line.contains(" STEXT ");
+            if filter && source.is_none() && !is_function {
+                return None;
+            }
+            Some(EmittedLine {
+                text: line.to_owned(),
+                source,
+            })
+        })
+        .collect()
+}
+
+/// LLVM IR, whose instructions refer to their `!DILocation` with `!dbg !<id>`.
+fn llvm_ir(output: &str, relative: impl Fn(&str) -> String, filter: bool) -> Vec<EmittedLine> {
+    // Metadata nodes by their ID, e.g. `!12 = !DILocation(line: 3, column: 5, scope: !7)`.
+    let metadata: HashMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.strip_prefix('!')?.split_once(" = "))
+        .collect();
+    let field = |node: &'_ str, name: &str| -> Option<String> {
+        let start = node.find(&format!("{name}: "))? + name.len() + 2;
+        let value = &node[start..];
+        if let Some(quoted) = value.strip_prefix('"') {
+            return Some(quoted[..quoted.find('"')?].to_owned());
+        }
+        let end = value.find([',', ')']).unwrap_or(value.len());
+        Some(value[..end].to_owned())
+    };
+    let node = |reference: &str| metadata.get(reference.strip_prefix('!')?).copied();
+    // Follow the scopes up to the file they are in.
+    let file = |mut scope: String| {
+        for _ in 0..16 {
+            let node = node(&scope)?;
+            if node.contains("DIFile(") {
+                let name = field(node, "filename")?;
+                let directory = field(node, "directory").unwrap_or_default();
+                return Some(relative(
+                    &Path::new(&directory).join(name).display().to_string(),
+                ));
+            }
+            scope = field(node, "file").or_else(|| field(node, "scope"))?;
+        }
+        None
+    };
+    let location = |reference: &str| {
+        let node = node(reference)?;
+        let line = field(node, "line")?
+            .parse()
+            .ok()
+            .filter(|line| *line != 0)?;
+        Some(SourceLine {
+            file: field(node, "scope").and_then(file),
+            line,
+        })
+    };
+
+    output
+        .lines()
+        .filter_map(|line| {
+            let (text, source) = match line.split_once(", !dbg ") {
+                Some((text, reference)) => {
+                    let reference = reference.split([',', ' ']).next().unwrap_or_default();
+                    (if filter { text } else { line }, location(reference))
+                }
+                None => (line, None),
+            };
+            let trimmed = text.trim();
+            if filter
+                && (trimmed.is_empty()
+                    || trimmed.starts_with(['!', ';', '#'])
+                    || trimmed.contains("@llvm.dbg.")
+                    || ["attributes ", "source_filename", "target "]
+                        .iter()
+                        .any(|prefix| trimmed.starts_with(prefix)))
+            {
+                return None;
+            }
+            Some(EmittedLine {
+                text: text.to_owned(),
+                source,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text and line of the emitted lines, checking that all of them come from `main.*`.
+    fn lines(emitted: &[EmittedLine]) -> Vec<(&str, Option<u32>)> {
+        emitted
+            .iter()
+            .map(|line| {
+                if let Some(source) = &line.source {
+                    assert!(source.file.as_deref().unwrap().starts_with("main."));
+                }
+                (
+                    line.text.trim(),
+                    line.source.as_ref().map(|source| source.line),
+                )
+            })
+            .collect()
+    }
+
+    /// `clang -O2 -g -S` of a C file with `square` and `abs_value`, shortened.
+    const LLVM_ASM: &str = "\
+\t.text
+\t.file\t\"main.c\"
+\t.globl\tsquare                          # -- Begin function square
+\t.type\tsquare,@function
+square:                                 # @square
+.Lfunc_begin0:
+\t.file\t0 \"/tmp/codepot\" \"main.c\" md5 0x5e8a0a6c3c6b0f4b1e0f8b5c0e2f6d1a
+\t.loc\t0 1 0                           # main.c:1:0
+\t.cfi_startproc
+# %bb.0:
+\t#DEBUG_VALUE: square:x <- $edi
+\t.loc\t0 2 14 prologue_end             # main.c:2:14
+\tmovl\t%edi, %eax
+\timull\t%edi, %eax
+.Ltmp0:
+\t.loc\t0 2 5 is_stmt 0                 # main.c:2:5
+\tretq
+.Ltmp1:
+.Lfunc_end0:
+\t.size\tsquare, .Lfunc_end0-square
+\t.cfi_endproc
+                                        # -- End function
+\t.globl\tabs_value                       # -- Begin function abs_value
+\t.type\tabs_value,@function
+abs_value:                              # @abs_value
+.Lfunc_begin1:
+\t.loc\t0 5 0                           # main.c:5:0
+\t.cfi_startproc
+# %bb.0:
+\t.loc\t0 6 9 prologue_end              # main.c:6:9
+\ttestl\t%edi, %edi
+\tjns\t.LBB1_2
+# %bb.1:
+\t.loc\t0 7 16                          # main.c:7:16
+\tnegl\t%edi
+.LBB1_2:                                # %if.end
+\t.loc\t0 9 5                           # main.c:9:5
+\tmovl\t%edi, %eax
+\tretq
+.Lfunc_end1:
+\t.section\t.debug_abbrev,\"\",@progbits
+\t.byte\t1                               # Abbreviation Code
+\t.section\t.debug_info,\"\",@progbits
+.Lcu_begin0:
+\t.long\t.Ldebug_info_end0-.Ldebug_info_start0 # Length of Unit
+\t.ident\t\"clang version 17.0.6\"
+";
+
+    /// `go build -gcflags=-S` of a Go file with `square`, shortened.
+    const GO_ASM: &str = "\
+# command-line-arguments
+main.square STEXT nosplit size=4 args=0x8 locals=0x0 funcid=0x0 align=0x0
+\t0x0000 00000 (/tmp/codepot/main.go:3)\tTEXT\tmain.square(SB), NOSPLIT|NOFRAME|ABIInternal, $0-8
+\t0x0000 00000 (/tmp/codepot/main.go:4)\tIMULQ\tAX, AX
+\t0x0004 00004 (/tmp/codepot/main.go:4)\tRET
+\t0x0000 48 0f af c0 c3                                   H....
+go:cuinfo.producer.main SDWARFCUINFO dupok size=0
+\t0x0000 72 65 67 69 73 74 65 72 61 62 69 3d 00           regabi=.
+";
+
+    /// `clang -O2 -g -S -emit-llvm` of a C file with `square`, shortened.
+    const LLVM_IR: &str = "\
+; ModuleID = 'main.c'
+source_filename = \"main.c\"
+target triple = \"x86_64-pc-linux-gnu\"
+
+; Function Attrs: mustprogress nofree norecurse nosync nounwind willreturn memory(none) uwtable
+define dso_local i32 @square(i32 noundef %0) local_unnamed_addr #0 !dbg !10 {
+  tail call void @llvm.dbg.value(metadata i32 %0, metadata !16, metadata !DIExpression()), !dbg !17
+  %2 = mul nsw i32 %0, %0, !dbg !18
+  ret i32 %2, !dbg !19
+}
+
+attributes #0 = { mustprogress nofree norecurse nosync nounwind willreturn memory(none) uwtable }
+
+!llvm.dbg.cu = !{!0}
+!0 = distinct !DICompileUnit(language: DW_LANG_C11, file: !1, producer: \"clang version 17.0.6\", isOptimized: true)
+!1 = !DIFile(filename: \"main.c\", directory: \"/tmp/codepot\", checksumkind: CSK_MD5)
+!10 = distinct !DISubprogram(name: \"square\", scope: !1, file: !1, line: 1, scopeLine: 1, unit: !0)
+!16 = !DILocalVariable(name: \"x\", arg: 1, scope: !10, file: !1, line: 1)
+!17 = !DILocation(line: 0, scope: !10)
+!18 = !DILocation(line: 2, column: 14, scope: !10)
+!19 = !DILocation(line: 2, column: 5, scope: !10)
+";
+
+    #[test]
+    fn maps_llvm_asm_to_sources() {
+        let emitted = process(Emit::Asm, Backend::Llvm, LLVM_ASM, "/tmp/codepot", true);
+        assert_eq!(
+            lines(&emitted),
+            [
+                ("square:                                 # @square", None),
+                ("movl\t%edi, %eax", Some(2)),
+                ("imull\t%edi, %eax", Some(2)),
+                ("retq", Some(2)),
+                ("abs_value:                              # @abs_value", None),
+                ("testl\t%edi, %edi", Some(6)),
+                ("jns\t.LBB1_2", Some(6)),
+                ("negl\t%edi", Some(7)),
+                (".LBB1_2:                                # %if.end", None),
+                ("movl\t%edi, %eax", Some(9)),
+                ("retq", Some(9)),
+            ]
+        );
+
+        let unfiltered = process(Emit::Asm, Backend::Llvm, LLVM_ASM, "/tmp/codepot", false);
+        assert_eq!(unfiltered.len(), LLVM_ASM.lines().count());
+    }
+
+    #[test]
+    fn maps_go_asm_to_sources() {
+        let emitted = process(Emit::Asm, Backend::Go, GO_ASM, "/tmp/codepot", true);
+        assert_eq!(
+            lines(&emitted),
+            [
+                (
+                    "main.square STEXT nosplit size=4 args=0x8 locals=0x0 funcid=0x0 align=0x0",
+                    None
+                ),
+                (
+                    "0x0000 00000 (/tmp/codepot/main.go:3)\tTEXT\tmain.square(SB), \
+                     NOSPLIT|NOFRAME|ABIInternal, $0-8",
+                    Some(3)
+                ),
+                (
+                    "0x0000 00000 (/tmp/codepot/main.go:4)\tIMULQ\tAX, AX",
+                    Some(4)
+                ),
+                ("0x0004 00004 (/tmp/codepot/main.go:4)\tRET", Some(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_llvm_ir_to_sources() {
+        let emitted = process(Emit::LlvmIr, Backend::Llvm, LLVM_IR, "/tmp/codepot", true);
+        assert_eq!(
+            lines(&emitted),
+            [
+                (
+                    "define dso_local i32 @square(i32 noundef %0) local_unnamed_addr #0 !dbg !10 {",
+                    None
+                ),
+                ("%2 = mul nsw i32 %0, %0", Some(2)),
+                ("ret i32 %2", Some(2)),
+                ("}", None),
+            ]
+        );
+
+        // Unfiltered lines keep their debug locations.
+        let unfiltered = process(Emit::LlvmIr, Backend::Llvm, LLVM_IR, "/tmp/codepot", false);
+        assert_eq!(unfiltered.len(), LLVM_IR.lines().count());
+        assert!(unfiltered
+            .iter()
+            .any(|line| line.text == "  ret i32 %2, !dbg !19"
+                && line.source.as_ref().map(|source| source.line) == Some(2)));
+    }
+}
@@ -0,0 +1,775 @@
+//! Compile and run code inside of a VM, the core of the HTTP API of `codepot run`.
+//!
+//! Every execution gets a fresh VM from the pool, which is destroyed afterwards, so that nothing one execution does
+//! can affect the next one. The sources are written into a directory of the guest user through the agent, then the
+//! compiler and the program run as that user. Time limits are enforced inside of the guest with `timeout`, the host
+//! only gives up on a step if the guest does not report back in time.
+
+use std::{
+    path::{Component, Path},
+    time::{Duration, Instant},
+};
+
+use codepot_agent::ExecRequest;
+use color_eyre::{
+    eyre::{ensure, eyre},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::debug;
+
+use crate::{
+    diagnostics::Diagnostic,
+    emit::{self, AsmSyntax, Backend, Emit, EmitOptions, EmittedLine},
+    host::{self, Arch},
+    init::{GUEST_GID, GUEST_UID},
+    languages::{self, Language, Toolchains},
+    machine::agent::{AgentClient, ExecEvent, ExitStatus},
+    pool::Pool,
+};
+
+/// Directory of the guest the sources are written to and the programs run in.
+pub const WORK_DIR: &str = "/tmp/codepot";
+/// Name of the binary built by the compile step.
+pub const BINARY_NAME: &str = "main";
+/// Name of the file the compiler writes its output to if it is asked for.
+pub const EMIT_FILE_NAME: &str = "emitted";
+/// Name of the file the processed compiler output is read from.
+const EMITTED_FILE_NAME: &str = "emitted.out";
+/// Compiler output beyond this is cut off.
+const MAX_EMITTED_BYTES: usize = 8 * 1024 * 1024;
+/// How much longer than the time limit of a step the host waits for the guest to report back.
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// Signal `timeout` kills commands with once their time is up. Busybox execs the command itself, so it shows up as the
+/// signal that killed the command.
+const SIGKILL: i32 = 9;
+
+/// A source file, by its path relative to the working directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub name: String,
+    pub contents: String,
+}
+
+/// Limits of an execution, capped by the limits of the server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub compile_timeout_secs: u64,
+    pub run_timeout_secs: u64,
+    /// Output of a step beyond this is cut off, for stdout and stderr each.
+    pub output_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            compile_timeout_secs: 30,
+            run_timeout_secs: 10,
+            output_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Upper bound of the limits clients can ask for.
+    pub const MAX: Self = Self {
+        compile_timeout_secs: 120,
+        run_timeout_secs: 60,
+        output_bytes: 1024 * 1024,
+    };
+
+    fn capped(&self) -> Self {
+        Self {
+            compile_timeout_secs: self
+                .compile_timeout_secs
+                .min(Self::MAX.compile_timeout_secs),
+            run_timeout_secs: self.run_timeout_secs.min(Self::MAX.run_timeout_secs),
+            output_bytes: self.output_bytes.min(Self::MAX.output_bytes),
+        }
+    }
+}
+
+/// Code to compile and run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecutionRequest {
+    /// Name of the language, see `languages`.
+    pub language: String,
+    pub files: Vec<SourceFile>,
+    #[serde(default)]
+    pub stdin: String,
+    /// Arguments of the program.
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Machine profile of the VM, the one of the server if not given.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Options specific to the language, e.g. the `toolchain` and `edition` of Rust, which the language checks.
+    #[serde(default)]
+    pub options: Value,
+    /// Return the output of the compiler instead of running the program, if the language can emit it.
+    #[serde(default)]
+    pub emit: Option<Emit>,
+    #[serde(default)]
+    pub emit_options: EmitOptions,
+}
+
+impl ExecutionRequest {
+    /// Maximum number of source files.
+    const MAX_FILES: usize = 64;
+
+    pub fn language(&self) -> Result<&'static dyn Language> {
+        languages::get(&self.language).ok_or_else(|| eyre!("Unknown language {}", self.language))
+    }
+
+    /// Check that the language is known, the files stay inside of the working directory and that there is something to
+    /// compile. The options of the language are checked against the installed `toolchains`.
+    pub fn validate(&self, toolchains: &Toolchains) -> Result<()> {
+        let language = self.language()?;
+        ensure!(
+            !self.files.is_empty() && self.files.len() <= Self::MAX_FILES,
+            "Between 1 and {} files are needed",
+            Self::MAX_FILES
+        );
+        for file in &self.files {
+            let path = Path::new(&file.name);
+            ensure!(
+                path.components()
+                    .all(|component| matches!(component, Component::Normal(_))),
+                "Invalid file name {:?}, has to be a relative path without `..`",
+                file.name
+            );
+        }
+        ensure!(
+            !self.sources().is_empty(),
+            "No source files with one of the extensions {:?}",
+            language.extensions()
+        );
+        if let Some(emit) = self.emit {
+            self.validate_emit(language, emit)?;
+        }
+        let toolchains = toolchains.get(language.name()).unwrap_or(&Value::Null);
+        language.validate(self, toolchains)
+    }
+
+    fn validate_emit(&self, language: &dyn Language, emit: Emit) -> Result<()> {
+        ensure!(
+            language.emits().contains(&emit),
+            "{} cannot emit {emit:?}",
+            language.display_name()
+        );
+        if let Some(syntax) = self.emit_options.syntax {
+            ensure!(
+                emit == Emit::Asm,
+                "An assembly syntax is only supported for assembly"
+            );
+            ensure!(
+                language.asm_syntaxes().contains(&syntax),
+                "{} does not support {syntax:?} syntax",
+                language.display_name()
+            );
+            ensure!(
+                syntax != AsmSyntax::Intel || host::ARCH == Arch::X86_64,
+                "Intel syntax is only supported on x86_64"
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether Intel syntax was asked for.
+    pub fn intel_syntax(&self) -> bool {
+        self.emit_options.syntax == Some(AsmSyntax::Intel)
+    }
+
+    /// Command cutting off the compiler output and demangling its symbols into `EMITTED_FILE_NAME`.
+    fn emitted_command(&self, language: &dyn Language) -> Vec<String> {
+        let demangle = self.emit_options.demangle && language.backend() == Backend::Llvm;
+        let mut script = vec![format!("head -c {MAX_EMITTED_BYTES} {EMIT_FILE_NAME}")];
+        if demangle {
+            script.push("c++filt".to_owned());
+        }
+        let script = format!("{} > {EMITTED_FILE_NAME}", script.join(" | "));
+        vec!["sh".to_owned(), "-c".to_owned(), script]
+    }
+
+    /// The source named `name`, or the first one if there is none. For compilers that start from a single file.
+    pub fn root(&self, name: &str) -> String {
+        let sources = self.sources();
+        sources
+            .iter()
+            .find(|source| **source == name)
+            .or(sources.first())
+            .map(|source| source.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Names of the files with one of the extensions of the language.
+    pub fn sources(&self) -> Vec<&str> {
+        let extensions = self
+            .language()
+            .map(|language| language.extensions())
+            .unwrap_or_default();
+        self.files
+            .iter()
+            .map(|file| file.name.as_str())
+            .filter(|name| {
+                Path::new(name)
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .is_some_and(|extension| extensions.contains(&extension))
+            })
+            .collect()
+    }
+}
+
+/// Outcome of compiling or running the code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepResult {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` if the step did not report back, e.g. because its output was cut off or it ran past its time limit.
+    pub exit_status: Option<ExitStatus>,
+    /// The step was killed because it exceeded its time limit.
+    pub timed_out: bool,
+    /// The output exceeded the limit and was cut off.
+    pub truncated: bool,
+    pub duration_ms: u64,
+}
+
+/// Where the time of an execution went.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Timings {
+    /// Waiting for a VM.
+    pub acquire_ms: u64,
+    /// Writing the sources.
+    pub setup_ms: u64,
+    pub total_ms: u64,
+}
+
+/// Outcome of an execution.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecutionResult {
+    pub compile: StepResult,
+    /// Errors and warnings of the compiler. Its output holds their human readable rendering.
+    pub diagnostics: Vec<Diagnostic>,
+    /// `None` if the code did not compile or is not run.
+    pub run: Option<StepResult>,
+    /// Output of the compiler, if it was asked for and the code compiled.
+    pub emitted: Option<Vec<EmittedLine>>,
+    pub timings: Timings,
+}
+
+/// Compile and run code in a fresh VM with the named profile from `pool`.
+pub fn execute(pool: &Pool, profile: &str, request: &ExecutionRequest) -> Result<ExecutionResult> {
+    let start = Instant::now();
+    let language = request.language()?;
+    let limits = request.limits.capped();
+    let machine = pool.acquire(profile)?;
+    let acquired = Instant::now();
+    debug!("Executing {} code in VM {}", request.language, machine.id());
+
+    let agent = machine.agent();
+    let user = Some((GUEST_UID, GUEST_GID));
+    let mut dirs = vec![WORK_DIR.to_owned()];
+    for file in &request.files {
+        if let Some(parent) = Path::new(&file.name).parent() {
+            dirs.push(Path::new(WORK_DIR).join(parent).display().to_string());
+        }
+    }
+    let mkdir = agent.run(
+        &ExecRequest {
+            program: "mkdir".to_owned(),
+            args: std::iter::once("-p".to_owned()).chain(dirs).collect(),
+            user,
+            ..Default::default()
+        },
+        &[],
+    )?;
+    ensure!(
+        mkdir.status.success(),
+        "Could not create working directory: {}",
+        String::from_utf8_lossy(&mkdir.stderr).trim()
+    );
+    for file in &request.files {
+        agent.write_file(
+            Path::new(WORK_DIR).join(&file.name),
+            file.contents.as_bytes(),
+            0o644,
+            user,
+        )?;
+    }
+    let setup_done = Instant::now();
+
+    let command = match request.emit {
+        Some(emit) => language.emit_command(request, emit),
+        None => language.compile_command(request),
+    };
+    // Machine-readable diagnostics are a lot larger than their rendering, which is what the limit applies to.
+    let mut compile = run_step(
+        &agent,
+        command,
+        Vec::new(),
+        &[],
+        limits.compile_timeout_secs,
+        Limits::MAX.output_bytes,
+    )?;
+    if compile.truncated {
+        // The compiler was killed in the middle of a line, which would be passed on as text if it was cut out of
+        // machine-readable diagnostics.
+        drop_partial_line(&mut compile.stderr);
+    }
+    let diagnostics = language.diagnostics(&compile.stderr);
+    compile.stderr = diagnostics.stderr;
+    for output in [&mut compile.stdout, &mut compile.stderr] {
+        if output.len() > limits.output_bytes {
+            let mut end = limits.output_bytes;
+            while !output.is_char_boundary(end) {
+                end -= 1;
+            }
+            output.truncate(end);
+            compile.truncated = true;
+        }
+    }
+    let emitted = match request.emit {
+        Some(emit) if compile.success => {
+            let processed = run_step(
+                &agent,
+                request.emitted_command(language),
+                Vec::new(),
+                &[],
+                limits.compile_timeout_secs,
+                limits.output_bytes,
+            )?;
+            ensure!(
+                processed.success,
+                "Could not process compiler output: {}",
+                processed.stderr.trim()
+            );
+            let emitted = agent.read_file(Path::new(WORK_DIR).join(EMITTED_FILE_NAME))?;
+            Some(emit::process(
+                emit,
+                language.backend(),
+                &String::from_utf8_lossy(&emitted),
+                WORK_DIR,
+                request.emit_options.filter,
+            ))
+        }
+        _ => None,
+    };
+    let run = if compile.success && request.emit.is_none() && language.runs(request) {
+        Some(run_step(
+            &agent,
+            language.run_command(request),
+            language.run_env(request),
+            request.stdin.as_bytes(),
+            limits.run_timeout_secs,
+            limits.output_bytes,
+        )?)
+    } else {
+        None
+    };
+    // Tear down the VM before answering, so that the slot is free again.
+    drop(machine);
+
+    Ok(ExecutionResult {
+        compile,
+        diagnostics: diagnostics.diagnostics,
+        run,
+        emitted,
+        timings: Timings {
+            acquire_ms: millis(acquired - start),
+            setup_ms: millis(setup_done - acquired),
+            total_ms: millis(start.elapsed()),
+        },
+    })
+}
+
+/// Run a command as the guest user inside of the working directory with the toolchains in its `PATH` and `env` added to
+/// its environment, killing it after `timeout_secs`.
+fn run_step(
+    agent: &AgentClient,
+    command: Vec<String>,
+    mut env: Vec<(String, String)>,
+    stdin: &[u8],
+    timeout_secs: u64,
+    output_bytes: usize,
+) -> Result<StepResult> {
+    let start = Instant::now();
+    let timeout = Duration::from_secs(timeout_secs);
+    // The compilers keep their caches in the home directory.
+    env.push(("HOME".to_owned(), WORK_DIR.to_owned()));
+    let mut execution = agent.exec(&ExecRequest {
+        program: "sh".to_owned(),
+        args: step_args(command, timeout_secs),
+        env,
+        cwd: Some(WORK_DIR.into()),
+        user: Some((GUEST_UID, GUEST_GID)),
+    })?;
+    // The program does not have to read all of its input, so failing to feed it is not an error.
+    let _feeder = execution.feed_stdin(stdin.to_owned())?;
+
+    // The program may keep running past the deadline, e.g. if a child process of it holds on to its output. It is
+    // killed together with the VM.
+    let deadline = start + timeout + GRACE_PERIOD;
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut truncated = false;
+    let mut past_deadline = false;
+    let exit_status = loop {
+        let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+            past_deadline = true;
+            break None;
+        };
+        execution.set_timeout(Some(remaining))?;
+        let event = match execution.next_event() {
+            Ok(event) => event,
+            Err(_) if Instant::now() >= deadline => {
+                past_deadline = true;
+                break None;
+            }
+            Err(err) => return Err(err),
+        };
+        let (output, data) = match event {
+            ExecEvent::Stdout(data) => (&mut stdout, data),
+            ExecEvent::Stderr(data) => (&mut stderr, data),
+            ExecEvent::Exited(status) => break Some(status),
+        };
+        output.extend_from_slice(&data);
+        if output.len() > output_bytes {
+            // The command is killed together with the VM.
+            output.truncate(output_bytes);
+            truncated = true;
+            break None;
+        }
+    };
+
+    let duration = start.elapsed();
+    let timed_out = past_deadline
+        || exit_status.is_some_and(|status| status.signal == Some(SIGKILL) && duration >= timeout);
+    Ok(StepResult {
+        success: exit_status.is_some_and(|status| status.success()),
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        exit_status,
+        timed_out,
+        truncated,
+        duration_ms: millis(duration),
+    })
+}
+
+/// Arguments of `sh` running `command` with the environment of the toolchains, which is only set up for login shells,
+/// and killing it after `timeout_secs`.
+fn step_args(command: Vec<String>, timeout_secs: u64) -> Vec<String> {
+    [
+        "-c",
+        ". /etc/profile && exec \"$@\"",
+        "sh",
+        "timeout",
+        "-s",
+        "KILL",
+    ]
+    .map(str::to_owned)
+    .into_iter()
+    .chain([timeout_secs.to_string()])
+    .chain(command)
+    .collect()
+}
+
+/// Cut off the last line of the output if it does not end with a newline.
+fn drop_partial_line(output: &mut String) {
+    let end = output.rfind('\n').map_or(0, |newline| newline + 1);
+    output.truncate(end);
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(language: &str, files: &[&str], extra: Value) -> ExecutionRequest {
+        let mut request = json!({
+            "language": language,
+            "files": files
+                .iter()
+                .map(|name| json!({ "name": name, "contents": "" }))
+                .collect::<Vec<_>>(),
+        });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn toolchains() -> Toolchains {
+        languages::toolchains(&["rust.extra-toolchains=nightly".to_owned()]).unwrap()
+    }
+
+    fn command(command: &[&str]) -> Vec<String> {
+        command.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn validates_requests() {
+        request(
+            "c",
+            &["main.c", "util/helper.c", "util/helper.h"],
+            json!({}),
+        )
+        .validate(&toolchains())
+        .unwrap();
+        request("rust", &["main.rs"], json!({ "emit": "mir" }))
+            .validate(&toolchains())
+            .unwrap();
+        request(
+            "rust",
+            &["main.rs"],
+            json!({ "options": { "toolchain": "nightly", "edition": "2024" } }),
+        )
+        .validate(&toolchains())
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let invalid = [
+            request("cobol", &["main.cob"], json!({})),
+            request("c", &[], json!({})),
+            request("c", &["../main.c"], json!({})),
+            request("c", &["/tmp/main.c"], json!({})),
+            request("c", &["main.rs"], json!({})),
+            request("go", &["main.go"], json!({ "emit": "llvm-ir" })),
+            request("c", &["main.c"], json!({ "emit": "mir" })),
+            request(
+                "zig",
+                &["main.zig"],
+                json!({ "emit": "asm", "emit_options": { "syntax": "intel" } }),
+            ),
+            request(
+                "c",
+                &["main.c"],
+                json!({ "emit": "llvm-ir", "emit_options": { "syntax": "att" } }),
+            ),
+            request(
+                "rust",
+                &["main.rs"],
+                json!({ "options": { "edition": "2019" } }),
+            ),
+            request(
+                "rust",
+                &["main.rs"],
+                json!({ "options": { "toolchain": "beta" } }),
+            ),
+            request(
+                "rust",
+                &["main.rs"],
+                json!({ "options": { "release": "yes" } }),
+            ),
+            request("c", &["main.c"], json!({ "options": { "std": "c99" } })),
+        ];
+        for request in invalid {
+            assert!(request.validate(&toolchains()).is_err(), "{request:?}");
+        }
+        let files: Vec<_> = (0..=ExecutionRequest::MAX_FILES)
+            .map(|i| format!("{i}.c"))
+            .collect();
+        let files: Vec<_> = files.iter().map(String::as_str).collect();
+        assert!(request("c", &files, json!({}))
+            .validate(&toolchains())
+            .is_err());
+    }
+
+    #[test]
+    fn caps_limits() {
+        let limits = Limits {
+            compile_timeout_secs: 1000,
+            run_timeout_secs: 1,
+            output_bytes: usize::MAX,
+        }
+        .capped();
+        assert_eq!(
+            limits.compile_timeout_secs,
+            Limits::MAX.compile_timeout_secs
+        );
+        assert_eq!(limits.run_timeout_secs, 1);
+        assert_eq!(limits.output_bytes, Limits::MAX.output_bytes);
+    }
+
+    #[test]
+    fn drops_partial_lines() {
+        let mut output = "{\"message\":\"unused\"}\n{\"message\":\"cannot fi".to_owned();
+        drop_partial_line(&mut output);
+        assert_eq!(output, "{\"message\":\"unused\"}\n");
+        drop_partial_line(&mut output);
+        assert_eq!(output, "{\"message\":\"unused\"}\n");
+
+        let mut output = "{\"runs\":[".to_owned();
+        drop_partial_line(&mut output);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn builds_compile_commands() {
+        let c = request("c", &["main.c", "util.c", "util.h"], json!({}));
+        assert_eq!(
+            c.language().unwrap().compile_command(&c),
+            command(&[
+                "clang",
+                "-std=c17",
+                "-Wall",
+                "-fdiagnostics-format=sarif",
+                "-Wno-sarif-format-unstable",
+                "-O2",
+                "-o",
+                "main",
+                "main.c",
+                "util.c",
+                "-lm",
+            ])
+        );
+
+        let rust = request(
+            "rust",
+            &["lib.rs", "main.rs"],
+            json!({ "options": { "toolchain": "nightly", "release": false } }),
+        );
+        assert_eq!(
+            rust.language().unwrap().compile_command(&rust),
+            command(&[
+                "rustc",
+                "+nightly",
+                "--error-format=json",
+                "--edition",
+                "2021",
+                "-o",
+                "main",
+                "main.rs",
+            ])
+        );
+
+        let zig = request("zig", &["lib.zig"], json!({}));
+        assert_eq!(
+            zig.language().unwrap().compile_command(&zig),
+            command(&[
+                "zig",
+                "build-exe",
+                "-O",
+                "ReleaseSafe",
+                "-femit-bin=main",
+                "lib.zig",
+            ])
+        );
+    }
+
+    #[test]
+    fn builds_emit_commands() {
+        let cpp = request(
+            "cpp",
+            &["util.cpp", "main.cpp"],
+            json!({ "emit": "llvm-ir" }),
+        );
+        assert_eq!(
+            cpp.language().unwrap().emit_command(&cpp, Emit::LlvmIr),
+            command(&[
+                "clang++",
+                "-std=c++20",
+                "-Wall",
+                "-fdiagnostics-format=sarif",
+                "-Wno-sarif-format-unstable",
+                "-O2",
+                "-g",
+                "-S",
+                "-emit-llvm",
+                "-o",
+                "emitted",
+                "main.cpp",
+            ])
+        );
+
+        let rust = request(
+            "rust",
+            &["lib.rs"],
+            json!({ "options": { "crate_type": "lib" } }),
+        );
+        assert_eq!(
+            rust.language().unwrap().emit_command(&rust, Emit::Mir),
+            command(&[
+                "rustc",
+                "--error-format=json",
+                "--edition",
+                "2021",
+                "-O",
+                "--crate-type",
+                "lib",
+                "--emit=mir=emitted",
+                "-C",
+                "debuginfo=1",
+                "-C",
+                "codegen-units=1",
+                "lib.rs",
+            ])
+        );
+    }
+
+    #[test]
+    fn builds_run_commands() {
+        let go = request("go", &["main.go"], json!({ "args": ["a b", "c"] }));
+        let language = go.language().unwrap();
+        assert!(language.runs(&go));
+        assert_eq!(language.run_command(&go), command(&["./main", "a b", "c"]));
+
+        let rust = request(
+            "rust",
+            &["lib.rs"],
+            json!({ "options": { "crate_type": "lib", "backtrace": true } }),
+        );
+        let language = rust.language().unwrap();
+        assert!(!language.runs(&rust));
+        assert_eq!(
+            language.run_env(&rust),
+            vec![("RUST_BACKTRACE".to_owned(), "1".to_owned())]
+        );
+    }
+
+    #[test]
+    fn builds_step_and_emitted_commands() {
+        assert_eq!(
+            step_args(command(&["./main", "arg"]), 10),
+            command(&[
+                "-c",
+                ". /etc/profile && exec \"$@\"",
+                "sh",
+                "timeout",
+                "-s",
+                "KILL",
+                "10",
+                "./main",
+                "arg",
+            ])
+        );
+
+        let c = request("c", &["main.c"], json!({ "emit": "asm" }));
+        assert_eq!(
+            c.emitted_command(c.language().unwrap()),
+            command(&[
+                "sh",
+                "-c",
+                "head -c 8388608 emitted | c++filt > emitted.out",
+            ])
+        );
+        let go = request("go", &["main.go"], json!({ "emit": "asm" }));
+        assert_eq!(
+            go.emitted_command(go.language().unwrap()),
+            command(&["sh", "-c", "head -c 8388608 emitted > emitted.out"])
+        );
+    }
+}
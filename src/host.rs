@@ -0,0 +1,128 @@
+//! Probe what the host offers to VMs.
+
+use std::fmt;
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// CPU architectures firecracker runs on. The guests always have the architecture of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    /// Name of the architecture as used by Rust targets and the firecracker CI artifacts.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64",
+            Self::Aarch64 => "aarch64",
+        }
+    }
+
+    /// Name of the architecture as used by OCI images, e.g. for `buildah from --arch`.
+    pub fn oci_name(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "amd64",
+            Self::Aarch64 => "arm64",
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Architecture of the host, which codepot is built for.
+#[cfg(target_arch = "x86_64")]
+pub const ARCH: Arch = Arch::X86_64;
+/// Architecture of the host, which codepot is built for.
+#[cfg(target_arch = "aarch64")]
+pub const ARCH: Arch = Arch::Aarch64;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("Firecracker only runs on x86_64 and aarch64 hosts");
+
+/// Total memory of the host.
+pub fn memory_mib() -> Result<usize> {
+    meminfo_mib("MemTotal")
+}
+
+/// Memory available for new VMs without swapping.
+pub fn available_memory_mib() -> Result<usize> {
+    meminfo_mib("MemAvailable")
+}
+
+/// Read a field of `/proc/meminfo`.
+fn meminfo_mib(field: &str) -> Result<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    let kib = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|total| {
+            total
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<usize>()
+                .ok()
+        })
+        .ok_or_else(|| eyre!("Could not find {field} in /proc/meminfo"))?;
+    Ok(kib / 1024)
+}
+
+/// Vendor of the CPUs of the host as reported by CPUID, e.g. `GenuineIntel` or `AuthenticAMD`. `None` on hosts that do
+/// not report one, like aarch64.
+pub fn cpu_vendor() -> Result<Option<String>> {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo")?;
+    Ok(cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "vendor_id")
+        .map(|(_, vendor)| vendor.trim().to_owned()))
+}
+
+/// Whether firecracker can use io_uring for drives. Firecracker needs Linux 5.10 or newer for it, and io_uring may be
+/// disabled (`kernel.io_uring_disabled`) or blocked by seccomp, e.g. in containers, so setting up a ring is tried as
+/// well. Firecracker runs as the same user as codepot, so it is allowed to use io_uring if codepot is.
+pub fn supports_io_uring() -> bool {
+    let Ok(release) = std::fs::read_to_string("/proc/sys/kernel/osrelease") else {
+        return false;
+    };
+    let mut version = release
+        .trim()
+        .split(['.', '-'])
+        .map(|part| part.parse::<u32>().unwrap_or_default());
+    let version = (
+        version.next().unwrap_or_default(),
+        version.next().unwrap_or_default(),
+    );
+    let usable = io_uring_usable();
+    debug!(
+        "Host runs Linux {}.{}, io_uring usable: {usable}",
+        version.0, version.1
+    );
+    version >= (5, 10) && usable
+}
+
+/// Set up and tear down an io_uring with a single entry, see io_uring_setup(2).
+fn io_uring_usable() -> bool {
+    // `struct io_uring_params`, which is filled in by the kernel. It only has to be zeroed to set up a ring.
+    let mut params = [0u32; 30];
+    // SAFETY: The kernel writes at most `size_of::<io_uring_params>()` (120) bytes to `params`.
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, 1u32, params.as_mut_ptr()) };
+    if fd < 0 {
+        debug!(
+            "Could not set up io_uring: {}",
+            std::io::Error::last_os_error()
+        );
+        return false;
+    }
+    // SAFETY: The file descriptor was just returned by the kernel and is not used anywhere else.
+    unsafe { libc::close(fd as libc::c_int) };
+    true
+}
@@ -2,43 +2,67 @@
 
 use std::{
     cell::OnceCell,
+    collections::BTreeMap,
     ffi::{OsStr, OsString},
     fs::File,
     io::{self, BufWriter, Read, Write},
     ops::DerefMut,
     path::Path,
     process::Command,
-    sync::LazyLock,
 };
 
 use color_eyre::eyre::{bail, ensure, Context, Result};
 use rand::distributions::{Alphanumeric, DistString};
 use reqwest::Url;
 use scopeguard::guard;
+use serde_json::Value;
 use tempfile::{NamedTempFile, TempDir};
 use tracing::{debug, error, info, warn};
 
-use crate::util::run_sudo;
+use crate::{
+    host::{self, Arch},
+    languages::{self, Toolchains},
+    util::run_sudo,
+};
 
-const LATEST_KERNEL_IMAGE: &'static str =
-    "spec.ccfc.min/firecracker-ci/v1.9/x86_64/vmlinux-5.10.219-no-acpi";
-static KERNEL_IMAGE_DOWNLOAD_URL: LazyLock<Url> = LazyLock::new(|| {
+/// Download URL of the kernel built by the firecracker CI for the given architecture. ACPI is only supported on x86_64
+/// and slows down booting, so the kernel without it is used there.
+fn kernel_image_download_url(arch: Arch) -> Url {
+    let image = match arch {
+        Arch::X86_64 => "vmlinux-5.10.219-no-acpi",
+        Arch::Aarch64 => "vmlinux-5.10.219",
+    };
     let mut url = Url::parse("https://s3.amazonaws.com/").unwrap();
-    url.set_path(LATEST_KERNEL_IMAGE);
+    url.set_path(&format!("spec.ccfc.min/firecracker-ci/v1.9/{arch}/{image}"));
     url
-});
+}
 
-const GET_CMDLINE_KEY_SCRIPT: &'static str = include_str!("../../vm_utils/get_cmdline_key");
-const IFUPDOWN_EXECUTOR_SCRIPT: &'static str = include_str!("../../vm_utils/cmdline_static");
-const INTERFACES_CONFIG: &'static str = include_str!("../../vm_utils/interfaces");
-const MOTD: &'static str = include_str!("../../vm_utils/motd");
+const METADATA_SCRIPT: &str = include_str!("../../vm_utils/codepot-metadata");
+const IFUPDOWN_EXECUTOR_SCRIPT: &str = include_str!("../../vm_utils/mmds_static");
+const INTERFACES_CONFIG: &str = include_str!("../../vm_utils/interfaces");
+const MOTD: &str = include_str!("../../vm_utils/motd");
+const INIT_SCRIPT: &str = include_str!("../../vm_utils/init");
+const RESTORE_SERVICE: &str = include_str!("../../vm_utils/codepot-restore");
+const READY_SCRIPT: &str = include_str!("../../vm_utils/ready.start");
+const AUTHORIZED_KEYS_SCRIPT: &str = include_str!("../../vm_utils/authorized-keys.start");
+const MOTD_SCRIPT: &str = include_str!("../../vm_utils/motd.start");
+const CONSOLE_SERVICE: &str = include_str!("../../vm_utils/codepot-console");
+const CONSOLE_COMMANDS_SCRIPT: &str = include_str!("../../vm_utils/console_commands");
+const AGENT_SERVICE: &str = include_str!("../../vm_utils/codepot-agent");
+
+// Hardcoded at the moment
+pub const GUEST_UID: u32 = 1000;
+pub const GUEST_GID: u32 = 1000;
+/// Label of the filesystem holding the home directory of the guest user when booting from an initrd.
+const HOME_LABEL: &str = "codepot-home";
 
 /// Build up the file image by using `buildah` to build up an alpine container with the necessary tools installed.
 ///
 /// Note that the drop implementation is blocking, so building an image should not be done from an async context.
 #[derive(Debug)]
-struct EphemeralContainer {
+pub struct EphemeralContainer {
     container_id: String,
+    arch: Arch,
     username: String,
     password: String,
     uid: u32,
@@ -46,10 +70,21 @@ struct EphemeralContainer {
 }
 
 impl EphemeralContainer {
-    const BUILDAH_PATH: &'static str = "buildah";
-    const RUSTUP_VERSION: &'static str = "1.27.1";
-    const RUSTUP_SHA256: &'static str =
-        "1455d1df3825c5f24ba06d9dd1c7052908272a2cae9aa749ea49d67acbe22b47";
+    const BUILDAH_PATH: &str = "buildah";
+    /// The toolchains are installed outside of the home directory, which is a separate drive when booting from an
+    /// initrd, but owned by the guest user so that it can update them and add components.
+    fn toolchain_paths() -> Vec<&'static str> {
+        languages::all()
+            .iter()
+            .flat_map(|language| language.install_paths())
+            .copied()
+            .collect()
+    }
+
+    /// Architecture the container is built for.
+    pub fn arch(&self) -> Arch {
+        self.arch
+    }
 
     fn username(&self) -> &str {
         &self.username
@@ -58,15 +93,15 @@ impl EphemeralContainer {
         &self.password
     }
 
-    /// Start building the container
-    fn new(username: String, password: String) -> Result<Self> {
+    /// Start building the container from the base image for `arch`.
+    fn new(arch: Arch, username: String, password: String) -> Result<Self> {
         // Hardcoded at the moment
         const BASE_IMAGE: &str = "alpine:3.20";
-        const UID: u32 = 1000;
-        const GID: u32 = 1000;
 
         let output = Command::new(Self::BUILDAH_PATH)
             .arg("from")
+            .arg("--arch")
+            .arg(arch.oci_name())
             .arg(BASE_IMAGE)
             .output()
             .context("Could not create ephemeral container")?;
@@ -89,15 +124,21 @@ impl EphemeralContainer {
 
         Ok(Self {
             container_id,
+            arch,
             username,
             password,
-            uid: UID,
-            gid: GID,
+            uid: GUEST_UID,
+            gid: GUEST_GID,
         })
     }
 
     /// Run a single command in the working container.
-    fn run(&self, cmd: impl AsRef<OsStr>) -> Result<()> {
+    pub fn run(&self, cmd: impl AsRef<OsStr>) -> Result<()> {
+        self.output(cmd).map(drop)
+    }
+
+    /// Run a single command in the working container and return its output.
+    fn output(&self, cmd: impl AsRef<OsStr>) -> Result<String> {
         let output = Command::new(Self::BUILDAH_PATH)
             .arg("run")
             .arg(&self.container_id)
@@ -117,7 +158,7 @@ impl EphemeralContainer {
             bail!("Could not create ephemeral container: {}", stderr.trim());
         }
 
-        Ok(())
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
 
     /// Copy a file into the container.
@@ -150,7 +191,7 @@ impl EphemeralContainer {
         permissions: &str,
     ) -> Result<()> {
         let mut temp = NamedTempFile::new()?;
-        temp.write_all(&contents.as_bytes())?;
+        temp.write_all(contents.as_bytes())?;
         temp.flush()?;
         self.copy(temp.path(), &path)
             .context("Could not add file contents")?;
@@ -164,22 +205,118 @@ impl EphemeralContainer {
         Ok(())
     }
 
-    fn install_rust(&self) {
-        todo!();
+    /// Install the guest agent and start it at boot. The binary has to be statically linked, as it is built on the host
+    /// but runs on alpine.
+    fn install_agent(&self, agent_path: &Path) -> Result<()> {
+        debug!("Installing agent from {}", agent_path.display());
+        self.copy(agent_path, "/usr/local/bin/codepot-agent")
+            .context("Could not add agent")?;
+        self.run("chmod 755 /usr/local/bin/codepot-agent")?;
+        self.add_file_contents("/etc/init.d/codepot-agent", AGENT_SERVICE, "755")
+            .context("Could not add agent service")?;
+        self.run("rc-update add codepot-agent boot")
+            .context("Could not setup agent service")?;
+        Ok(())
+    }
+
+    /// Download a file into the container, failing if its SHA-256 does not match `sha256`.
+    pub fn download_verified(&self, url: &str, sha256: &str, to_container: &str) -> Result<()> {
+        debug!("Downloading {url} to {to_container}");
+        self.run(format!(
+            "wget -q -O {to_container} {url} && echo '{sha256}  {to_container}' | sha256sum -c -s"
+        ))
+        .with_context(|| format!("Could not download {url}, or its checksum does not match"))
+    }
+
+    /// Install the toolchains of all languages and put them into the `PATH` of login shells.
+    fn install_toolchains(&self, toolchains: &Toolchains) -> Result<()> {
+        for language in languages::all() {
+            let toolchains = toolchains.get(language.name()).unwrap_or(&Value::Null);
+            language
+                .install(self, toolchains)
+                .with_context(|| format!("Could not install {}", language.display_name()))?;
+            if let Some(profile) = language.profile() {
+                self.add_file_contents(
+                    format!("/etc/profile.d/codepot-{}.sh", language.name()),
+                    &profile,
+                    "644",
+                )
+                .with_context(|| {
+                    format!("Could not add environment of {}", language.display_name())
+                })?;
+            }
+        }
+        let paths = Self::toolchain_paths();
+        if !paths.is_empty() {
+            self.run(format!(
+                "chown -R {}:{} {}",
+                self.uid,
+                self.gid,
+                paths.join(" ")
+            ))
+            .context("Could not hand the toolchains to the guest user")?;
+        }
+        Ok(())
+    }
+
+    /// Ask the toolchains for their versions, by the names of their languages.
+    fn probe_versions(&self) -> Result<BTreeMap<String, String>> {
+        languages::all()
+            .iter()
+            .map(|language| {
+                let output = self
+                    .output(format!(". /etc/profile && {}", language.version_command()))
+                    .with_context(|| {
+                        format!("Could not get version of {}", language.display_name())
+                    })?;
+                let version = output.lines().next().unwrap_or_default().trim().to_owned();
+                Ok((language.name().to_owned(), version))
+            })
+            .collect()
+    }
+
+    /// Welcome message listing the languages with their versions. The idle timeout is filled in by the guest, see
+    /// `vm_utils/motd.start`.
+    fn motd(versions: &BTreeMap<String, String>) -> String {
+        let languages: String = languages::all()
+            .iter()
+            .map(|language| {
+                let version = versions
+                    .get(language.name())
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                format!("  {:<6} {version}\n", language.display_name())
+            })
+            .collect();
+        MOTD.replace("{languages}", &languages)
     }
 
     /// Setup the container by installing necessary packages and tools
-    fn setup(&self) -> Result<()> {
-        const PACKAGES: [&'static str; 5] = ["openrc", "sudo", "util-linux", "dropbear", "clang"];
+    fn setup(
+        &self,
+        agent_path: &Path,
+        toolchains: &Toolchains,
+    ) -> Result<BTreeMap<String, String>> {
+        // `binutils` brings `c++filt`, which demangles the symbols of emitted assembly.
+        const PACKAGES: [&str; 5] = ["openrc", "sudo", "util-linux", "dropbear", "binutils"];
 
         // TODO: Dropbear, https://gruchalski.com/posts/2021-02-13-launching-alpine-linux-on-firecracker-like-a-boss/
 
         // Install necessary packages
         debug!("Installing packages");
         self.run("apk update")?;
+        let mut packages = PACKAGES.to_vec();
+        for package in languages::all()
+            .iter()
+            .flat_map(|language| language.packages())
+        {
+            if !packages.contains(package) {
+                packages.push(package);
+            }
+        }
         self.run(format!(
             "apk add{}",
-            PACKAGES.iter().fold(String::new(), |mut acc, s| {
+            packages.iter().fold(String::new(), |mut acc, s| {
                 acc.push(' ');
                 acc.push_str(s);
                 acc
@@ -203,21 +340,13 @@ impl EphemeralContainer {
             self.username
         ))?;
 
-        // Setup auto-login for the serial console
-        debug!("Setting up auto-login");
-        self.run(
-            "ln -s agetty /etc/init.d/agetty.ttyS0 \
-                 && echo ttyS0 > /etc/securetty",
-        )
-        .context("Could not setup auto-login")?;
-
         // Setup necessary system jobs.
         debug!("Setting up system jobs");
         self.run(
-            "rc-update add agetty.ttyS0 default \
-                 && rc-update add devfs boot \
+            "rc-update add devfs boot \
                  && rc-update add procfs boot \
                  && rc-update add sysfs boot \
+                 && rc-update add localmount boot \
                  && rc-update add local default \
                  && rc-update add dropbear",
         )
@@ -232,37 +361,73 @@ impl EphemeralContainer {
         .context("Could not setup RC")?;
 
         debug!("Copying files...");
+        self.add_file_contents("/usr/local/bin/codepot-metadata", METADATA_SCRIPT, "755")
+            .context("Could not add metadata script")?;
         self.add_file_contents(
-            "/usr/local/bin/get_cmdline_key",
-            GET_CMDLINE_KEY_SCRIPT,
-            "755",
-        )
-        .context("Could not add cmdline get script")?;
-        self.add_file_contents(
-            "/usr/libexec/ifupdown-ng/cmdline_static",
+            "/usr/libexec/ifupdown-ng/mmds_static",
             IFUPDOWN_EXECUTOR_SCRIPT,
             "755",
         )
         .context("Could not add ifupdown executor script")?;
         self.add_file_contents("/etc/network/interfaces", INTERFACES_CONFIG, "644")
             .context("Could not add interfaces config")?;
-        self.add_file_contents("/etc/motd", MOTD, "644")
-            .context("Could not add motd")?;
+        self.add_file_contents("/init", INIT_SCRIPT, "755")
+            .context("Could not add initrd init script")?;
+        self.add_file_contents("/etc/init.d/codepot-restore", RESTORE_SERVICE, "755")
+            .context("Could not add restore service")?;
+        self.run("rc-update add codepot-restore boot")
+            .context("Could not setup restore service")?;
+        // `local` runs the scripts in alphabetical order, so the VM is only reported ready afterwards.
+        self.add_file_contents(
+            "/etc/local.d/codepot-authorized-keys.start",
+            AUTHORIZED_KEYS_SCRIPT,
+            "755",
+        )
+        .context("Could not add authorized keys script")?;
+        self.add_file_contents("/etc/local.d/codepot-motd.start", MOTD_SCRIPT, "755")
+            .context("Could not add motd script")?;
+        self.add_file_contents("/etc/local.d/codepot-ready.start", READY_SCRIPT, "755")
+            .context("Could not add ready script")?;
+        // The serial console carries commands of the host, so it cannot be used for logins.
+        self.add_file_contents(
+            "/usr/local/bin/codepot-console",
+            CONSOLE_COMMANDS_SCRIPT,
+            "755",
+        )
+        .context("Could not add console command script")?;
+        self.add_file_contents("/etc/init.d/codepot-console", CONSOLE_SERVICE, "755")
+            .context("Could not add console service")?;
+        self.run("rc-update add codepot-console default")
+            .context("Could not setup console service")?;
         self.run("echo 'DROPBEAR_OPTS=\"-w -j\"' > /etc/conf.d/dropbear")?; // '-s' to disable password logins
 
-        Ok(())
+        self.install_agent(agent_path)?;
+        self.install_toolchains(toolchains)?;
+
+        let versions = self.probe_versions()?;
+        self.add_file_contents("/etc/motd.template", &Self::motd(&versions), "644")
+            .context("Could not add motd")?;
+
+        Ok(versions)
     }
 
-    /// Build the ephemeral container.
-    fn build(username: String, password: String) -> Result<Self> {
-        info!("Building ephemeral container");
-        let this = Self::new(username, password)?;
-        this.setup()?;
-        Ok(this)
+    /// Build the ephemeral container, returning it with the versions of the toolchains by the names of their
+    /// languages.
+    fn build(
+        arch: Arch,
+        username: String,
+        password: String,
+        agent_path: &Path,
+        toolchains: &Toolchains,
+    ) -> Result<(Self, BTreeMap<String, String>)> {
+        info!("Building ephemeral container for {arch}");
+        let this = Self::new(arch, username, password)?;
+        let versions = this.setup(agent_path, toolchains)?;
+        Ok((this, versions))
     }
 
     /// Build an image of the given size (in bytes) from the container and put it at the specified path.
-    fn to_image(self, image_path: impl AsRef<Path>, image_size: u64) -> Result<()> {
+    fn into_image(self, image_path: impl AsRef<Path>, image_size: u64) -> Result<()> {
         info!("Creating image");
         let defused = OnceCell::new();
 
@@ -337,6 +502,12 @@ impl EphemeralContainer {
         cp_arg.push(&mount_dir);
         cp_arg.push(format!("/usr/bin/sudo && chown {}:{} ", self.uid, self.gid));
         cp_arg.push(mount_dir.join(format!("home/{}", self.username)));
+        // Copying made root the owner of the toolchains as well.
+        cp_arg.push(format!(" && chown -R {}:{}", self.uid, self.gid));
+        for path in Self::toolchain_paths() {
+            cp_arg.push(" ");
+            cp_arg.push(mount_dir.join(path.trim_start_matches('/')));
+        }
         cp_arg.push(" && umount ");
         cp_arg.push(&mount_dir);
 
@@ -353,6 +524,100 @@ impl EphemeralContainer {
 
         Ok(())
     }
+
+    /// Build an initramfs (an uncompressed `newc` cpio archive) from the container and put it at the specified path.
+    fn to_initrd(&self, initrd_path: impl AsRef<Path>) -> Result<()> {
+        info!("Creating initrd");
+        let defused = OnceCell::new();
+
+        // The home directory is only a separate drive when booting from an initrd, which keeps the rest of the
+        // filesystem in memory. The entry is removed again afterwards as the container is used for the rootfs image.
+        let fstab_entry = format!(
+            "LABEL={HOME_LABEL} /home/{} ext4 defaults,nofail 0 0",
+            self.username
+        );
+        self.run(format!("echo '{fstab_entry}' >> /etc/fstab"))
+            .context("Could not add home directory mount")?;
+        let _fstab_entry = guard((), |_| {
+            if let Err(err) = self.run(format!("sed -i '/^LABEL={HOME_LABEL} /d' /etc/fstab")) {
+                error!("Could not remove home directory mount: {err}");
+            }
+        });
+
+        let initrd = File::create_new(&initrd_path).context("Could not create initrd file")?;
+        let initrd = guard(initrd, |initrd| {
+            drop(initrd);
+            if defused.get().is_none() {
+                debug!("Removing initrd because creation was not successful");
+                let _ = std::fs::remove_file(&initrd_path);
+            }
+        });
+
+        // Inside of the user namespace the files are owned by the users of the container, which is what ends up in
+        // the archive.
+        let cpio_output = Command::new(Self::BUILDAH_PATH)
+            .arg("unshare")
+            .arg("--mount")
+            .arg(format!("MNT_PATH={}", self.container_id))
+            .arg("sh")
+            .arg("-c")
+            .arg(r#"cd "$MNT_PATH" && find . | cpio -o -H newc --quiet"#)
+            .stdout(initrd.try_clone()?)
+            .output()?;
+        if !cpio_output.status.success() {
+            let stderr = String::from_utf8_lossy(&cpio_output.stderr);
+            bail!(
+                "Could not archive ephemeral container {}: {}",
+                self.container_id,
+                stderr.trim()
+            );
+        }
+
+        info!("Created initrd at {}", initrd_path.as_ref().display());
+
+        defused.get_or_init(|| ());
+
+        Ok(())
+    }
+}
+
+/// Create an empty image of the given size (in bytes) for the home directory of the guest user. The image is sparse, so
+/// it only takes up the space that is actually used.
+fn create_home_image(image_path: impl AsRef<Path>, image_size: u64) -> Result<()> {
+    info!("Creating home directory image");
+    let defused = OnceCell::new();
+
+    let image = File::create_new(&image_path).context("Could not create image file")?;
+    let image = guard(image, |image| {
+        drop(image);
+        if defused.get().is_none() {
+            debug!("Removing image because creation was not successful");
+            let _ = std::fs::remove_file(&image_path);
+        }
+    });
+    image.set_len(image_size)?;
+
+    let mkfs_output = Command::new("mkfs.ext4")
+        .arg("-q")
+        .arg("-L")
+        .arg(HOME_LABEL)
+        .arg("-E")
+        .arg(format!("root_owner={GUEST_UID}:{GUEST_GID}"))
+        .arg(image_path.as_ref())
+        .output()?;
+    if !mkfs_output.status.success() {
+        let stderr = String::from_utf8_lossy(&mkfs_output.stderr);
+        bail!("Could not create home filesystem: {}", stderr.trim());
+    }
+
+    info!(
+        "Created home directory image at {} with size {image_size}",
+        image_path.as_ref().display()
+    );
+
+    defused.get_or_init(|| ());
+
+    Ok(())
 }
 
 impl Drop for EphemeralContainer {
@@ -380,28 +645,110 @@ impl Drop for EphemeralContainer {
     }
 }
 
-/// Create and download necessary kernel and rootfs images.
+/// Check that the agent at `agent_path` is an executable for `arch`, which the guest could not run otherwise.
+fn check_agent_arch(arch: Arch, agent_path: &Path) -> Result<()> {
+    // `e_machine` of the ELF header, see elf(5).
+    const EM_X86_64: u16 = 62;
+    const EM_AARCH64: u16 = 183;
+
+    let mut header = [0; 20];
+    File::open(agent_path)
+        .and_then(|mut agent| agent.read_exact(&mut header))
+        .with_context(|| format!("Could not read guest agent at {}", agent_path.display()))?;
+    ensure!(
+        header.starts_with(b"\x7fELF"),
+        "Guest agent at {} is not an ELF executable",
+        agent_path.display()
+    );
+    let machine = u16::from_le_bytes([header[18], header[19]]);
+    let expected = match arch {
+        Arch::X86_64 => EM_X86_64,
+        Arch::Aarch64 => EM_AARCH64,
+    };
+    ensure!(
+        machine == expected,
+        "Guest agent at {} is not built for {arch}, build it with `--target {arch}-unknown-linux-musl`",
+        agent_path.display()
+    );
+    Ok(())
+}
+
+/// Paths and sizes of the images needed to boot from an initrd.
+#[derive(Debug, Clone, Copy)]
+pub struct InitrdImages<'a> {
+    pub initrd_image_path: &'a Path,
+    pub home_image_path: &'a Path,
+    /// Size of the home directory image, in bytes.
+    pub home_size: u64,
+}
+
+/// Create and download necessary kernel and rootfs images for the architecture of the host, and optionally the images
+/// to boot from an initrd. The guest agent at `agent_path` and the `toolchains` of all languages are installed into
+/// the images. Returns the versions of the toolchains by the names of their languages if the images were built.
+#[allow(clippy::too_many_arguments)]
 pub fn init_images(
     kernel_image_path: &Path,
     rootfs_image_path: &Path,
     rootfs_size: u64,
+    initrd: Option<InitrdImages>,
+    agent_path: &Path,
+    toolchains: &Toolchains,
     username: String,
     password: String,
-) -> Result<()> {
-    if rootfs_image_path.try_exists()? {
+) -> Result<Option<BTreeMap<String, String>>> {
+    let arch = host::ARCH;
+    let build_rootfs = !rootfs_image_path.try_exists()?;
+    if !build_rootfs {
         warn!(
             "RootFS image already exists at {}, not building it",
             rootfs_image_path.display()
         );
-    } else {
-        let container = EphemeralContainer::build(username, password)?;
+    }
+    let mut build_initrd = false;
+    if let Some(initrd) = initrd {
+        build_initrd = !initrd.initrd_image_path.try_exists()?;
+        if !build_initrd {
+            warn!(
+                "Initrd already exists at {}, not building it",
+                initrd.initrd_image_path.display()
+            );
+        }
+    }
+
+    let mut versions = None;
+    if build_rootfs || build_initrd {
+        ensure!(
+            agent_path.try_exists()?,
+            "Guest agent not found at {}, build it with `cargo build --release --target {arch}-unknown-linux-musl -p codepot-agent` and pass it with `--agent`",
+            agent_path.display()
+        );
+        check_agent_arch(arch, agent_path)?;
+        let (container, built_versions) =
+            EphemeralContainer::build(arch, username, password, agent_path, toolchains)?;
+        versions = Some(built_versions);
 
         println!(
             "Default user is {}, password is {}",
             container.username(),
             container.password()
         );
-        container.to_image(&rootfs_image_path, rootfs_size)?;
+        if let Some(initrd) = initrd.filter(|_| build_initrd) {
+            container.to_initrd(initrd.initrd_image_path)?;
+        }
+        if build_rootfs {
+            container.into_image(rootfs_image_path, rootfs_size)?;
+        }
+    }
+
+    if let Some(initrd) = initrd {
+        if initrd.home_image_path.try_exists()? {
+            warn!(
+                "Home directory image already exists at {}, not creating it",
+                initrd.home_image_path.display()
+            );
+        } else {
+            create_home_image(initrd.home_image_path, initrd.home_size)?;
+        }
     }
 
     if kernel_image_path.try_exists()? {
@@ -410,16 +757,16 @@ pub fn init_images(
             kernel_image_path.display()
         );
     } else {
+        let url = kernel_image_download_url(arch);
         info!(
-            "Downloading image from {} and putting it into {}",
-            *KERNEL_IMAGE_DOWNLOAD_URL,
+            "Downloading image from {url} and putting it into {}",
             kernel_image_path.display()
         );
-        let image_contents = reqwest::blocking::get(KERNEL_IMAGE_DOWNLOAD_URL.clone())
-            .context("Could not download kernel image")?;
+        let image_contents =
+            reqwest::blocking::get(url).context("Could not download kernel image")?;
 
         let mut file = BufWriter::new(File::create(kernel_image_path)?);
         std::io::copy(&mut image_contents.bytes()?.as_ref(), &mut file)?;
     }
-    Ok(())
+    Ok(versions)
 }
@@ -1,5 +1,5 @@
 mod build_image;
 mod networking;
 
-pub use build_image::init_images;
-pub use networking::init_networking;
+pub use build_image::{init_images, EphemeralContainer, InitrdImages, GUEST_GID, GUEST_UID};
+pub use networking::{deinit_networking, init_networking};
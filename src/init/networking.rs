@@ -1,4 +1,7 @@
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeSet, HashSet},
+    net::Ipv4Addr,
+};
 
 use color_eyre::{
     eyre::{ensure, Context, OptionExt},
@@ -8,24 +11,37 @@ use ipnet::Ipv4Net;
 use rand::distributions::{Alphanumeric, DistString};
 use tracing::{debug, info};
 
-use crate::{config::InterfaceConfig, util::run_sudo};
+use crate::{
+    config::{Config, InterfaceConfig},
+    util::run_sudo,
+};
 
-const BRIDGE_NAME: &'static str = "codepot0";
+const BRIDGE_NAME: &str = "codepot0";
+const IF_NAME_PREFIX: &str = "vethcdpt";
+/// Comment attached to the NAT rule, so that it can be found again without knowing the host interface.
+const NAT_RULE_COMMENT: &str = "codepot";
 
 fn random_if_name() -> String {
     format!(
-        "vethcdpt{}",
+        "{IF_NAME_PREFIX}{}",
         Alphanumeric.sample_string(&mut rand::thread_rng(), 6)
     )
 }
 
-// TODO: We need to figure out how to do networking for multiple vms:
-// - MAC addresses and IPs need to be setup
-// - Multiple tuns need to be setup
+/// Derive the guest MAC address from its IP address, following the firecracker convention of a locally administered
+/// `06:00` prefix followed by the four octets of the IP. As the IPs of the VMs are unique, so are their MACs.
+fn mac_address(ip_address: Ipv4Addr) -> String {
+    let [a, b, c, d] = ip_address.octets();
+    format!("06:00:{a:02X}:{b:02X}:{c:02X}:{d:02X}")
+}
+
+fn remove_tap_interface(if_name: &str) -> Result<()> {
+    run_sudo(format!("ip link del {if_name} 2> /dev/null || true"))
+}
 
 fn setup_tap_interface(if_name: &str) -> Result<()> {
     // Remove interface...
-    run_sudo(format!("ip link del {if_name} 2> /dev/null || true"))?;
+    remove_tap_interface(if_name)?;
 
     // and create it again to be idempotent.
     run_sudo(format!("ip tuntap add {if_name} mode tap"))?;
@@ -35,36 +51,49 @@ fn setup_tap_interface(if_name: &str) -> Result<()> {
     Ok(())
 }
 
-/// Configure host interface and ip table rules to do NAT.
-fn setup_host_interface(host_if_name: &str, host_address: Ipv4Net) -> Result<()> {
-    // Remove bridge
+/// Remove the bridge and the ip table rules added by `setup_host_interface`. The NAT rule is found by its comment, the
+/// name of the host interface is only needed to remove untagged rules added by earlier versions.
+fn remove_host_interface(host_if_name: Option<&str>) -> Result<()> {
     run_sudo(format!("ip link del {BRIDGE_NAME} 2> /dev/null || true"))?;
 
-    // Add bridge again to be idempotent.
-    run_sudo(format!("ip link add name {BRIDGE_NAME} type bridge"))?;
-    run_sudo(format!("ip addr add {host_address} dev {BRIDGE_NAME}"))?;
-    run_sudo(format!("ip link set dev {BRIDGE_NAME} up"))?;
-
-    // Remove exsting rules...
     run_sudo("iptables -D FORWARD -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT || true")?;
+    // `iptables -S` prints the rules as the arguments that added them, so turning `-A` into `-D` deletes them.
     run_sudo(format!(
-        "iptables -t nat -D POSTROUTING -o {host_if_name} -j MASQUERADE || true"
+        "iptables -t nat -S POSTROUTING | grep -e '-m comment --comment {NAT_RULE_COMMENT} ' | sed 's/^-A /-D /' | while read -r rule; do iptables -t nat $rule; done"
     ))?;
+    if let Some(host_if_name) = host_if_name {
+        run_sudo(format!(
+            "iptables -t nat -D POSTROUTING -o {host_if_name} -j MASQUERADE || true"
+        ))?;
+    }
     run_sudo(format!(
         "iptables -D FORWARD -i {BRIDGE_NAME} -j ACCEPT || true"
     ))?;
 
-    // and apply them again to be idempotent.
+    Ok(())
+}
+
+/// Configure host interface and ip table rules to do NAT.
+fn setup_host_interface(host_if_name: &str, host_address: Ipv4Net) -> Result<()> {
+    // Remove bridge and existing rules...
+    remove_host_interface(Some(host_if_name))?;
+
+    // and add them again to be idempotent.
+    run_sudo(format!("ip link add name {BRIDGE_NAME} type bridge"))?;
+    run_sudo(format!("ip addr add {host_address} dev {BRIDGE_NAME}"))?;
+    run_sudo(format!("ip link set dev {BRIDGE_NAME} up"))?;
+
     run_sudo("iptables -A FORWARD -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT")?;
     run_sudo(format!(
-        "iptables -t nat -A POSTROUTING -o {host_if_name} -j MASQUERADE"
+        "iptables -t nat -A POSTROUTING -o {host_if_name} -m comment --comment {NAT_RULE_COMMENT} -j MASQUERADE"
     ))?;
     run_sudo(format!("iptables -A FORWARD -i {BRIDGE_NAME} -j ACCEPT"))?;
 
     Ok(())
 }
 
-/// Initialize networking, returning the list of created interfaces and associated static IP addresses.
+/// Initialize networking, returning the list of created interfaces (one per VM slot) and associated static IP
+/// addresses.
 pub fn init_networking(
     max_parallel_vm_count: usize,
     host_if_name: &str,
@@ -72,7 +101,7 @@ pub fn init_networking(
 ) -> Result<(Vec<InterfaceConfig>, Ipv4Net)> {
     info!("Setting up networking");
     ensure!(
-        max_parallel_vm_count + 1 <= net.hosts().count(),
+        max_parallel_vm_count < net.hosts().count(),
         "More VMs than hostmask allows"
     );
 
@@ -84,29 +113,29 @@ pub fn init_networking(
         net.prefix_len(),
     )
     .unwrap();
-    let mac_addresses = std::iter::once("06:00:AC:10:00:02".to_owned()); // TODO
-
-    if max_parallel_vm_count > 1 {
-        todo!();
-    }
 
     // Make sure that we have `max_parallel_vm_count` unique interface names
     let ifs: Vec<_> = loop {
-        let s: HashSet<_> = std::iter::repeat_with(|| random_if_name())
+        let s: HashSet<_> = std::iter::repeat_with(random_if_name)
             .take(max_parallel_vm_count)
             .collect();
         if s.len() == max_parallel_vm_count {
             break s
                 .into_iter()
-                .zip(ip_addresses.map(|s| Ipv4Net::new(s, net.prefix_len()).unwrap()))
-                .zip(mac_addresses)
-                .map(|((n, a), b)| InterfaceConfig::new(n, a, b))
+                .zip(ip_addresses)
+                .map(|(n, a)| {
+                    InterfaceConfig::new(
+                        n,
+                        Ipv4Net::new(a, net.prefix_len()).unwrap(),
+                        mac_address(a),
+                    )
+                })
                 .collect();
         }
     };
 
     // enable forwarding
-    run_sudo(format!("echo 1 > /proc/sys/net/ipv4/ip_forward"))?;
+    run_sudo("echo 1 > /proc/sys/net/ipv4/ip_forward")?;
 
     debug!("Setting up host interface {host_if_name}");
     setup_host_interface(host_if_name, host_address).context("could not setup host interface")?;
@@ -118,6 +147,60 @@ pub fn init_networking(
     Ok((ifs, host_address))
 }
 
-pub fn deinit_networking() -> Result<()> {
-    todo!()
+/// Remove all interfaces and ip table rules created by `init_networking`. This is idempotent and also cleans up after a
+/// partial setup: taps that are not part of the config (e.g. because `init_networking` failed half way) are found by
+/// their name prefix, and the NAT rule by its comment.
+///
+/// Note that IP forwarding is left enabled, as it might have been enabled before codepot was set up.
+pub fn deinit_networking(config: Option<&Config>) -> Result<()> {
+    info!("Tearing down networking");
+
+    let mut if_names: BTreeSet<_> = config
+        .into_iter()
+        .flat_map(|c| &c.interfaces)
+        .map(|i| i.if_name.clone())
+        .collect();
+    for entry in std::fs::read_dir("/sys/class/net").context("Could not list interfaces")? {
+        let if_name = entry?.file_name().to_string_lossy().into_owned();
+        if if_name.starts_with(IF_NAME_PREFIX) {
+            if_names.insert(if_name);
+        }
+    }
+
+    for if_name in &if_names {
+        debug!("Removing tap interface {if_name}");
+        remove_tap_interface(if_name).context("could not remove tap interface")?;
+    }
+
+    let host_if_name = config.map(|c| c.host_ifname.as_str());
+    debug!("Removing bridge {BRIDGE_NAME} and ip table rules");
+    remove_host_interface(host_if_name).context("could not remove host interface")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_unique_local_unicast_macs() {
+        let net: Ipv4Net = "10.128.0.1/16".parse().unwrap();
+        // The first address belongs to the host, the others to the slots.
+        let macs: Vec<_> = net.hosts().skip(1).map(mac_address).collect();
+        for mac in &macs {
+            let octets: Vec<_> = mac
+                .split(':')
+                .map(|octet| u8::from_str_radix(octet, 16).unwrap())
+                .collect();
+            assert_eq!(octets.len(), 6, "{mac}");
+            assert_eq!(octets[0] & 0b10, 0b10, "{mac} is not locally administered");
+            assert_eq!(octets[0] & 0b01, 0, "{mac} is not unicast");
+        }
+        assert_eq!(macs.iter().collect::<HashSet<_>>().len(), macs.len());
+        assert_eq!(
+            mac_address(Ipv4Addr::new(10, 128, 64, 2)),
+            "06:00:0A:80:40:02"
+        );
+    }
 }
@@ -0,0 +1,104 @@
+//! C and C++, compiled with clang from the Alpine packages.
+
+use super::{args, Language};
+use crate::{
+    diagnostics::{self, Diagnostics},
+    emit::Emit,
+    execution::{ExecutionRequest, BINARY_NAME, EMIT_FILE_NAME},
+};
+
+pub const C: Clang = Clang {
+    name: "c",
+    display_name: "C",
+    compiler: "clang",
+    standard: "-std=c17",
+    extensions: &["c"],
+    root: "main.c",
+    libraries: &["-lm"],
+};
+
+pub const CPP: Clang = Clang {
+    name: "cpp",
+    display_name: "C++",
+    compiler: "clang++",
+    standard: "-std=c++20",
+    extensions: &["cpp", "cc", "cxx"],
+    root: "main.cpp",
+    libraries: &[],
+};
+
+/// A language compiled by clang, which compiles all sources into the binary. Diagnostics are printed as SARIF.
+#[derive(Debug)]
+pub struct Clang {
+    name: &'static str,
+    display_name: &'static str,
+    compiler: &'static str,
+    standard: &'static str,
+    extensions: &'static [&'static str],
+    /// Source that is compiled when emitting, as clang emits one file per source.
+    root: &'static str,
+    /// Linked in addition to the standard library.
+    libraries: &'static [&'static str],
+}
+
+impl Clang {
+    fn clang_command(&self) -> Vec<String> {
+        args(&[
+            self.compiler,
+            self.standard,
+            "-Wall",
+            "-fdiagnostics-format=sarif",
+            // Clang warns that the format may still change.
+            "-Wno-sarif-format-unstable",
+        ])
+    }
+}
+
+impl Language for Clang {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn display_name(&self) -> &'static str {
+        self.display_name
+    }
+
+    fn packages(&self) -> &'static [&'static str] {
+        // `build-base` brings the linker and the headers of the C library.
+        &["clang", "build-base"]
+    }
+
+    fn version_command(&self) -> &'static str {
+        "clang --version"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        self.extensions
+    }
+
+    fn compile_command(&self, request: &ExecutionRequest) -> Vec<String> {
+        let mut command = self.clang_command();
+        command.extend(args(&["-O2", "-o", BINARY_NAME]));
+        command.extend(request.sources().iter().map(|source| source.to_string()));
+        command.extend(args(self.libraries));
+        command
+    }
+
+    fn emit_command(&self, request: &ExecutionRequest, emit: Emit) -> Vec<String> {
+        let mut command = self.clang_command();
+        command.extend(args(&["-O2", "-g", "-S"]));
+        if emit == Emit::LlvmIr {
+            command.push("-emit-llvm".to_owned());
+        }
+        if request.intel_syntax() {
+            command.push("-masm=intel".to_owned());
+        }
+        command.extend(args(&["-o", EMIT_FILE_NAME]));
+        command.push(request.root(self.root));
+        command
+    }
+
+    fn diagnostics(&self, stderr: &str) -> Diagnostics {
+        diagnostics::parse_sarif(stderr)
+    }
+}
@@ -0,0 +1,103 @@
+//! Go, from the pinned release of https://go.dev.
+
+use color_eyre::{eyre::Context, Result};
+use serde_json::Value;
+use tracing::debug;
+
+use super::{args, Language};
+use crate::{
+    emit::{AsmSyntax, Backend, Emit},
+    execution::{ExecutionRequest, BINARY_NAME, EMIT_FILE_NAME},
+    host::Arch,
+    init::EphemeralContainer,
+};
+
+const GO_VERSION: &str = "1.23.2";
+const GO_ROOT: &str = "/usr/local/go";
+
+/// SHA-256 of the Go release tarball for the architecture, from https://go.dev/dl/.
+fn go_sha256(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "542d3c1705f1c6a1c5a80d5dc62e2e45171af291e755d591c5e6531ef63b454e",
+        Arch::Aarch64 => "f626cdd92fc21a88b31c1251f419c17782933a42903db87a174ce74eeecc66a9",
+    }
+}
+
+#[derive(Debug)]
+pub struct Go;
+
+impl Language for Go {
+    fn name(&self) -> &'static str {
+        "go"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Go"
+    }
+
+    fn packages(&self) -> &'static [&'static str] {
+        // cgo needs a C compiler and the headers of the C library.
+        &["build-base"]
+    }
+
+    /// Install the pinned Go release.
+    fn install(&self, container: &EphemeralContainer, _toolchains: &Value) -> Result<()> {
+        debug!("Installing Go {GO_VERSION}");
+        let arch = container.arch();
+        let url = format!(
+            "https://go.dev/dl/go{GO_VERSION}.linux-{}.tar.gz",
+            arch.oci_name()
+        );
+        container.download_verified(&url, go_sha256(arch), "/tmp/go.tar.gz")?;
+        // The tarball holds the `go` directory.
+        container
+            .run("tar -xzf /tmp/go.tar.gz -C /usr/local && rm /tmp/go.tar.gz")
+            .context("Could not install Go")?;
+        Ok(())
+    }
+
+    fn install_paths(&self) -> &'static [&'static str] {
+        &[GO_ROOT]
+    }
+
+    fn profile(&self) -> Option<String> {
+        Some(format!("export PATH=\"{GO_ROOT}/bin:$PATH\"\n"))
+    }
+
+    fn version_command(&self) -> &'static str {
+        "go version"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["go"]
+    }
+
+    fn emits(&self) -> &'static [Emit] {
+        &[Emit::Asm]
+    }
+
+    fn asm_syntaxes(&self) -> &'static [AsmSyntax] {
+        &[]
+    }
+
+    fn backend(&self) -> Backend {
+        Backend::Go
+    }
+
+    fn compile_command(&self, request: &ExecutionRequest) -> Vec<String> {
+        let mut command = args(&["go", "build", "-o", BINARY_NAME]);
+        command.extend(request.sources().iter().map(|source| source.to_string()));
+        command
+    }
+
+    /// Go prints its assembly instead of writing it to a file.
+    fn emit_command(&self, request: &ExecutionRequest, _emit: Emit) -> Vec<String> {
+        let script = format!(
+            "go build -gcflags=-S -o /dev/null \"$@\" 2> {EMIT_FILE_NAME} \
+             || {{ cat {EMIT_FILE_NAME} >&2; exit 1; }}"
+        );
+        let mut command = args(&["sh", "-c", &script, "sh"]);
+        command.extend(request.sources().iter().map(|source| source.to_string()));
+        command
+    }
+}
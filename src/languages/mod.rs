@@ -0,0 +1,161 @@
+//! The languages code can be executed in. A language owns everything that is specific to it: how its toolchain is
+//! installed into the images, which files it compiles, the commands that compile and run the code, how to ask the
+//! toolchain for its version and how to read the diagnostics of its compiler.
+//!
+//! Adding a language means implementing `Language` and adding it to `LANGUAGES`, the image builder and the server only
+//! go through the registry.
+
+mod clang;
+mod go;
+pub mod rust;
+mod zig;
+
+use std::collections::BTreeMap;
+
+use color_eyre::{
+    eyre::{ensure, eyre},
+    Result,
+};
+use serde_json::Value;
+
+use crate::{
+    diagnostics::{self, Diagnostics},
+    emit::{AsmSyntax, Backend, Emit},
+    execution::{ExecutionRequest, BINARY_NAME},
+    init::EphemeralContainer,
+};
+
+/// All languages, in the order they are listed to users.
+static LANGUAGES: [&dyn Language; 5] = [&clang::C, &clang::CPP, &rust::Rust, &go::Go, &zig::Zig];
+
+/// Toolchains installed into the images by the names of their languages, see `Language::toolchains`.
+pub type Toolchains = BTreeMap<String, Value>;
+
+pub trait Language: Sync {
+    /// Name in execution requests, e.g. `cpp`.
+    fn name(&self) -> &'static str;
+    /// Name shown to users, e.g. `C++`.
+    fn display_name(&self) -> &'static str;
+
+    /// Alpine packages the toolchain needs.
+    fn packages(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Toolchains to install from the options of `codepot init` for the language, e.g. `toolchain=nightly`, in the
+    /// form `install` and `validate` read them. They are recorded in the config, `Value::Null` stands for the defaults.
+    fn toolchains(&self, options: &BTreeMap<String, String>) -> Result<Value> {
+        ensure!(
+            options.is_empty(),
+            "{} has no install options",
+            self.display_name()
+        );
+        Ok(Value::Null)
+    }
+    /// Install the parts of the toolchain that do not come as packages.
+    fn install(&self, _container: &EphemeralContainer, _toolchains: &Value) -> Result<()> {
+        Ok(())
+    }
+    /// Directories outside of the packages the toolchain is installed to, which are handed to the guest user.
+    fn install_paths(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Script setting up the environment of the toolchain for login shells, e.g. its `PATH`.
+    fn profile(&self) -> Option<String> {
+        None
+    }
+    /// Shell command printing the version of the toolchain on its first line.
+    fn version_command(&self) -> &'static str;
+
+    /// File extensions of the sources passed to the compiler.
+    fn extensions(&self) -> &'static [&'static str];
+    /// Outputs the compiler can emit instead of a program.
+    fn emits(&self) -> &'static [Emit] {
+        &[Emit::Asm, Emit::LlvmIr]
+    }
+    /// Assembly syntaxes that can be asked for explicitly.
+    fn asm_syntaxes(&self) -> &'static [AsmSyntax] {
+        &[AsmSyntax::Att, AsmSyntax::Intel]
+    }
+    /// Compiler backend, which decides the format of the emitted code.
+    fn backend(&self) -> Backend {
+        Backend::Llvm
+    }
+
+    /// Check the `options` of the request, which are specific to the language, against the installed toolchains.
+    fn validate(&self, request: &ExecutionRequest, _toolchains: &Value) -> Result<()> {
+        ensure!(
+            request.options.is_null(),
+            "{} has no options",
+            self.display_name()
+        );
+        Ok(())
+    }
+    /// Command building `BINARY_NAME` from the sources in the working directory.
+    fn compile_command(&self, request: &ExecutionRequest) -> Vec<String>;
+    /// Command writing the output of the compiler to `EMIT_FILE_NAME`, with debug info that maps it to the sources.
+    fn emit_command(&self, request: &ExecutionRequest, emit: Emit) -> Vec<String>;
+    /// Whether the compiled code is run, e.g. libraries are not.
+    fn runs(&self, _request: &ExecutionRequest) -> bool {
+        true
+    }
+    /// Command running the compiled code.
+    fn run_command(&self, request: &ExecutionRequest) -> Vec<String> {
+        std::iter::once(format!("./{BINARY_NAME}"))
+            .chain(request.args.iter().cloned())
+            .collect()
+    }
+    /// Environment of the compiled code.
+    fn run_env(&self, _request: &ExecutionRequest) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Diagnostics in the error output of a compile command, by default in the format of GCC.
+    fn diagnostics(&self, stderr: &str) -> Diagnostics {
+        diagnostics::parse_gcc_style(stderr)
+    }
+}
+
+/// All languages, in the order they are listed to users.
+pub fn all() -> &'static [&'static dyn Language] {
+    &LANGUAGES
+}
+
+/// Look up a language by its name.
+pub fn get(name: &str) -> Option<&'static dyn Language> {
+    LANGUAGES
+        .iter()
+        .copied()
+        .find(|language| language.name() == name)
+}
+
+/// Toolchains of all languages from the options of `codepot init`, each given as `<language>.<option>=<value>`.
+pub fn toolchains(options: &[String]) -> Result<Toolchains> {
+    let mut by_language: BTreeMap<&str, BTreeMap<String, String>> = BTreeMap::new();
+    for option in options {
+        let (language, key, value) = option
+            .split_once('.')
+            .and_then(|(language, option)| {
+                let (key, value) = option.split_once('=')?;
+                Some((language, key, value))
+            })
+            .ok_or_else(|| {
+                eyre!("Invalid install option {option:?}, expected `<language>.<option>=<value>`")
+            })?;
+        ensure!(get(language).is_some(), "Unknown language {language}");
+        by_language
+            .entry(language)
+            .or_default()
+            .insert(key.to_owned(), value.to_owned());
+    }
+    LANGUAGES
+        .iter()
+        .map(|language| {
+            let options = by_language.remove(language.name()).unwrap_or_default();
+            Ok((language.name().to_owned(), language.toolchains(&options)?))
+        })
+        .collect()
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
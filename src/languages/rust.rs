@@ -0,0 +1,607 @@
+//! Rust, installed through rustup with the toolchains given to `codepot init`.
+
+use std::collections::BTreeMap;
+
+use color_eyre::{
+    eyre::{bail, ensure, Context},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::debug;
+
+use super::{args, Language};
+use crate::{
+    diagnostics::{
+        relative_path, Diagnostic, Diagnostics, Replacement, Severity, Span, Suggestion,
+    },
+    emit::Emit,
+    execution::{ExecutionRequest, BINARY_NAME, EMIT_FILE_NAME},
+    host::Arch,
+    init::EphemeralContainer,
+};
+
+const RUSTUP_VERSION: &str = "1.27.1";
+/// Where rustup keeps the toolchains.
+const RUSTUP_HOME: &str = "/usr/local/rustup";
+/// Where cargo keeps its binaries and the registry.
+const CARGO_HOME: &str = "/usr/local/cargo";
+
+/// SHA-256 of `rustup-init` for the musl target of the architecture, from
+/// `https://static.rust-lang.org/rustup/archive/<version>/<target>/rustup-init.sha256`.
+fn rustup_sha256(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "1455d1df3825c5f24ba06d9dd1c7052908272a2cae9aa749ea49d67acbe22b47",
+        Arch::Aarch64 => "7087ada906cd27a00c8e0323401a46804a03a742bd07811da6dead016617cc64",
+    }
+}
+
+/// Rust toolchains installed into the images through rustup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RustToolchains {
+    /// Default toolchain, e.g. `stable` or `1.81.0`.
+    pub default: String,
+    /// Further toolchains, e.g. `nightly`, which are picked with `rustc +<toolchain>`.
+    pub extra: Vec<String>,
+    /// Components added to every toolchain, e.g. `rustfmt`.
+    pub components: Vec<String>,
+}
+
+impl Default for RustToolchains {
+    fn default() -> Self {
+        Self {
+            default: "stable".to_owned(),
+            extra: Vec::new(),
+            components: vec!["rustfmt".to_owned(), "clippy".to_owned()],
+        }
+    }
+}
+
+impl RustToolchains {
+    /// The toolchains recorded by `Language::toolchains`, the defaults for `Value::Null`.
+    pub fn installed(toolchains: &Value) -> Result<Self> {
+        Ok(serde_json::from_value::<Option<Self>>(toolchains.clone())
+            .context("Invalid Rust toolchains")?
+            .unwrap_or_default())
+    }
+
+    /// Check that the names of the toolchains and components are plain names, as they end up in shell commands.
+    pub fn validate(&self) -> Result<()> {
+        for name in self.names().iter().chain(&self.components) {
+            ensure!(
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')),
+                "Invalid Rust toolchain or component {name:?}, only letters, digits, `.`, `_` and `-` are allowed"
+            );
+        }
+        Ok(())
+    }
+
+    /// Names of all toolchains, the default one first.
+    pub fn names(&self) -> Vec<String> {
+        std::iter::once(&self.default)
+            .chain(&self.extra)
+            .cloned()
+            .collect()
+    }
+}
+
+/// What a Rust crate is built as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrateType {
+    #[default]
+    Bin,
+    Lib,
+}
+
+/// Options of Rust executions, modeled after the ones of the Rust playground.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RustOptions {
+    /// Rustup toolchain, the default toolchain of the images if not given.
+    pub toolchain: Option<String>,
+    pub edition: String,
+    /// Build with optimizations.
+    pub release: bool,
+    pub crate_type: CrateType,
+    /// Build and run the tests instead of the program.
+    pub tests: bool,
+    /// Print a backtrace when the program panics.
+    pub backtrace: bool,
+}
+
+impl Default for RustOptions {
+    fn default() -> Self {
+        Self {
+            toolchain: None,
+            edition: "2021".to_owned(),
+            release: true,
+            crate_type: CrateType::Bin,
+            tests: false,
+            backtrace: false,
+        }
+    }
+}
+
+impl RustOptions {
+    const EDITIONS: [&str; 4] = ["2015", "2018", "2021", "2024"];
+
+    fn parse(request: &ExecutionRequest) -> Result<Self> {
+        Ok(
+            serde_json::from_value::<Option<Self>>(request.options.clone())
+                .context("Invalid Rust options")?
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Options of a request that passed `Rust::validate`.
+    fn of(request: &ExecutionRequest) -> Self {
+        Self::parse(request).unwrap_or_default()
+    }
+}
+
+/// Split a comma-separated list given on the command line.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Rust, which only compiles the root of the crate, `main.rs` or `lib.rs` if present. Diagnostics are printed as JSON.
+#[derive(Debug)]
+pub struct Rust;
+
+impl Rust {
+    fn rustc_command(&self, request: &ExecutionRequest) -> Vec<String> {
+        let options = RustOptions::of(request);
+        let mut command = vec!["rustc".to_owned()];
+        if let Some(toolchain) = &options.toolchain {
+            // Picked by the rustup proxy.
+            command.push(format!("+{toolchain}"));
+        }
+        command.push("--error-format=json".to_owned());
+        command.extend(["--edition".to_owned(), options.edition]);
+        if options.release {
+            command.push("-O".to_owned());
+        }
+        if options.tests {
+            command.push("--test".to_owned());
+        } else if options.crate_type == CrateType::Lib {
+            command.extend(args(&["--crate-type", "lib"]));
+        }
+        command
+    }
+
+    fn root(&self, request: &ExecutionRequest) -> String {
+        match RustOptions::of(request).crate_type {
+            CrateType::Bin => request.root("main.rs"),
+            CrateType::Lib => request.root("lib.rs"),
+        }
+    }
+}
+
+impl Language for Rust {
+    fn name(&self) -> &'static str {
+        "rust"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Rust"
+    }
+
+    fn packages(&self) -> &'static [&'static str] {
+        // rustc links with the system linker against the C library.
+        &["build-base"]
+    }
+
+    /// Takes the default `toolchain`, comma-separated `extra-toolchains` and comma-separated `components` added to
+    /// all of them.
+    fn toolchains(&self, options: &BTreeMap<String, String>) -> Result<Value> {
+        let mut toolchains = RustToolchains::default();
+        for (key, value) in options {
+            match key.as_str() {
+                "toolchain" => toolchains.default = value.clone(),
+                "extra-toolchains" => toolchains.extra = split_list(value),
+                "components" => toolchains.components = split_list(value),
+                _ => bail!("Unknown Rust install option {key}"),
+            }
+        }
+        toolchains.validate()?;
+        Ok(serde_json::to_value(toolchains)?)
+    }
+
+    /// Install rustup with the given toolchains, all with the given components.
+    fn install(&self, container: &EphemeralContainer, toolchains: &Value) -> Result<()> {
+        let rust = RustToolchains::installed(toolchains)?;
+        rust.validate()?;
+        debug!("Installing Rust {}", rust.default);
+        let arch = container.arch();
+        let target = format!("{arch}-unknown-linux-musl");
+        let url = format!(
+            "https://static.rust-lang.org/rustup/archive/{RUSTUP_VERSION}/{target}/rustup-init"
+        );
+        container.download_verified(&url, rustup_sha256(arch), "/tmp/rustup-init")?;
+        let mut install = format!(
+            "chmod 755 /tmp/rustup-init \
+                 && RUSTUP_HOME={RUSTUP_HOME} CARGO_HOME={CARGO_HOME} /tmp/rustup-init -y --no-modify-path \
+                    --profile minimal --default-host {target} --default-toolchain {}",
+            rust.default
+        );
+        let components: String = rust
+            .components
+            .iter()
+            .map(|component| format!(" --component {component}"))
+            .collect();
+        install.push_str(&components);
+        install.push_str(" && rm /tmp/rustup-init");
+        container.run(install).context("Could not install Rust")?;
+
+        for toolchain in &rust.extra {
+            debug!("Installing Rust {toolchain}");
+            container
+                .run(format!(
+                    "RUSTUP_HOME={RUSTUP_HOME} CARGO_HOME={CARGO_HOME} {CARGO_HOME}/bin/rustup toolchain install \
+                     {toolchain} --profile minimal{components}"
+                ))
+                .with_context(|| format!("Could not install Rust {toolchain}"))?;
+        }
+        Ok(())
+    }
+
+    fn install_paths(&self) -> &'static [&'static str] {
+        &[RUSTUP_HOME, CARGO_HOME]
+    }
+
+    fn profile(&self) -> Option<String> {
+        Some(format!(
+            "export RUSTUP_HOME={RUSTUP_HOME}\nexport CARGO_HOME={CARGO_HOME}\nexport PATH=\"{CARGO_HOME}/bin:$PATH\"\n"
+        ))
+    }
+
+    fn version_command(&self) -> &'static str {
+        "rustc --version"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["rs"]
+    }
+
+    fn emits(&self) -> &'static [Emit] {
+        &[Emit::Asm, Emit::LlvmIr, Emit::Mir]
+    }
+
+    fn validate(&self, request: &ExecutionRequest, toolchains: &Value) -> Result<()> {
+        let options = RustOptions::parse(request)?;
+        ensure!(
+            RustOptions::EDITIONS.contains(&options.edition.as_str()),
+            "Unknown Rust edition {}",
+            options.edition
+        );
+        if let Some(toolchain) = &options.toolchain {
+            ensure!(
+                RustToolchains::installed(toolchains)?
+                    .names()
+                    .contains(toolchain),
+                "Rust toolchain {toolchain} is not installed"
+            );
+        }
+        Ok(())
+    }
+
+    fn compile_command(&self, request: &ExecutionRequest) -> Vec<String> {
+        let mut command = self.rustc_command(request);
+        command.extend(args(&["-o", BINARY_NAME]));
+        command.push(self.root(request));
+        command
+    }
+
+    fn emit_command(&self, request: &ExecutionRequest, emit: Emit) -> Vec<String> {
+        let mut command = self.rustc_command(request);
+        let kind = match emit {
+            Emit::Asm => "asm",
+            Emit::LlvmIr => "llvm-ir",
+            Emit::Mir => "mir",
+        };
+        command.push(format!("--emit={kind}={EMIT_FILE_NAME}"));
+        // Line tables for the mapping, and a single codegen unit so that there is a single output file.
+        command.extend(args(&["-C", "debuginfo=1", "-C", "codegen-units=1"]));
+        if request.intel_syntax() {
+            command.extend(args(&["-C", "llvm-args=-x86-asm-syntax=intel"]));
+        }
+        command.push(self.root(request));
+        command
+    }
+
+    fn runs(&self, request: &ExecutionRequest) -> bool {
+        let options = RustOptions::of(request);
+        options.crate_type == CrateType::Bin || options.tests
+    }
+
+    fn run_env(&self, request: &ExecutionRequest) -> Vec<(String, String)> {
+        if RustOptions::of(request).backtrace {
+            vec![("RUST_BACKTRACE".to_owned(), "1".to_owned())]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Diagnostics printed by rustc as JSON, one per line, which carry their human readable rendering as well. The
+    /// summaries at the end, e.g. `aborting due to 1 previous error`, are only kept in the rendering.
+    fn diagnostics(&self, stderr: &str) -> Diagnostics {
+        let mut parsed = Diagnostics::default();
+        for line in stderr.lines() {
+            let Ok(diagnostic) = serde_json::from_str::<RustcDiagnostic>(line) else {
+                // Not everything is a diagnostic, e.g. the output of a panicking compiler.
+                parsed.stderr.push_str(line);
+                parsed.stderr.push('\n');
+                continue;
+            };
+            parsed
+                .stderr
+                .push_str(diagnostic.rendered.as_deref().unwrap_or_default());
+            let is_summary = diagnostic.spans.is_empty()
+                && (diagnostic.message.starts_with("aborting due to")
+                    || diagnostic.message.ends_with("emitted")
+                    || diagnostic.message.starts_with("For more information"));
+            if let (Some(severity), false) = (Severity::parse(&diagnostic.level), is_summary) {
+                parsed.diagnostics.push(diagnostic.normalize(severity));
+            }
+        }
+        parsed
+    }
+}
+
+/// Diagnostic in the JSON format of rustc, see
+/// https://doc.rust-lang.org/rustc/json.html#diagnostics.
+#[derive(Debug, Deserialize)]
+struct RustcDiagnostic {
+    message: String,
+    code: Option<RustcCode>,
+    level: String,
+    spans: Vec<RustcSpan>,
+    children: Vec<RustcDiagnostic>,
+    /// Not set for children.
+    rendered: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcCode {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcSpan {
+    file_name: String,
+    line_start: u32,
+    line_end: u32,
+    column_start: u32,
+    column_end: u32,
+    is_primary: bool,
+    label: Option<String>,
+    suggested_replacement: Option<String>,
+}
+
+impl RustcSpan {
+    fn span(&self) -> Span {
+        Span {
+            file: Some(relative_path(&self.file_name)),
+            line: Some(self.line_start),
+            column: Some(self.column_start),
+            end_line: Some(self.line_end),
+            end_column: Some(self.column_end),
+        }
+    }
+}
+
+impl RustcDiagnostic {
+    /// Children with replacements become suggestions, the others notes.
+    fn normalize(self, severity: Severity) -> Diagnostic {
+        let primary = self
+            .spans
+            .iter()
+            .find(|span| span.is_primary)
+            .or(self.spans.first());
+        let mut notes: Vec<String> = primary
+            .and_then(|span| span.label.clone())
+            .into_iter()
+            .collect();
+        let mut suggestions = Vec::new();
+        for child in self.children {
+            let replacements: Vec<_> = child
+                .spans
+                .iter()
+                .filter_map(|span| {
+                    Some(Replacement {
+                        span: span.span(),
+                        text: span.suggested_replacement.clone()?,
+                    })
+                })
+                .collect();
+            if replacements.is_empty() {
+                notes.push(format!("{}: {}", child.level, child.message));
+            } else {
+                suggestions.push(Suggestion {
+                    message: child.message,
+                    replacements,
+                });
+            }
+        }
+        Diagnostic {
+            span: primary.map(RustcSpan::span).unwrap_or_default(),
+            severity,
+            message: self.message,
+            code: self.code.map(|code| code.code),
+            notes,
+            suggestions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rust_toolchains(default: &str, components: &[&str]) -> RustToolchains {
+        RustToolchains {
+            default: default.to_owned(),
+            extra: vec!["nightly-2024-10-01".to_owned()],
+            components: components.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_toolchain_names() {
+        rust_toolchains("1.81.0", &["rustfmt", "rust-src"])
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_shell_in_toolchain_names() {
+        assert!(rust_toolchains("stable; rm -rf /", &[]).validate().is_err());
+        assert!(rust_toolchains("stable", &["rustfmt $(id)"])
+            .validate()
+            .is_err());
+        assert!(rust_toolchains("", &[]).validate().is_err());
+    }
+
+    /// `rustc --error-format=json` of a `main.rs` with an unused variable and a typo, with its summaries.
+    const RUSTC_JSON: &str = concat!(
+        r#"{"$message_type":"diagnostic","message":"unused variable: `z`","#,
+        r#""code":{"code":"unused_variables","explanation":null},"level":"warning","spans":[{"file_name":"main.rs","#,
+        r#""byte_start":20,"byte_end":21,"line_start":2,"line_end":2,"column_start":9,"column_end":10,"#,
+        r#""is_primary":true,"text":[{"text":"    let z = 1;","highlight_start":9,"highlight_end":10}],"label":null,"#,
+        r#""suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":["#,
+        r#"{"message":"`#[warn(unused_variables)]` on by default","code":null,"level":"note","spans":[],"#,
+        r#""children":[],"rendered":null},{"message":"if this is intentional, prefix it with an underscore","#,
+        r#""code":null,"level":"help","spans":[{"file_name":"main.rs","byte_start":20,"byte_end":21,"line_start":2,"#,
+        r#""line_end":2,"column_start":9,"column_end":10,"is_primary":true,"text":[{"text":"    let z = 1;","#,
+        r#""highlight_start":9,"highlight_end":10}],"label":null,"suggested_replacement":"_z","#,
+        r#""suggestion_applicability":"MachineApplicable","expansion":null}],"children":[],"rendered":null}],"#,
+        r#""rendered":"warning: unused variable: `z`\n --> main.rs:2:9\n  |\n2 |     let z = 1;\n  |         ^ help: "#,
+        r#"if this is intentional, prefix it with an underscore: `_z`\n  |\n  = note: `#[warn(unused_variables)]` "#,
+        r#"on by default\n\n"}"#,
+        "\n",
+        r#"{"$message_type":"diagnostic","message":"cannot find value `y` in this scope","#,
+        r#""code":{"code":"E0425","explanation":"An unresolved name was used.\n"},"level":"error","spans":["#,
+        r#"{"file_name":"main.rs","byte_start":51,"byte_end":52,"line_start":3,"line_end":3,"column_start":20,"#,
+        r#""column_end":21,"is_primary":true,"text":[{"text":"    println!(\"{}\", y);","highlight_start":20,"#,
+        r#""highlight_end":21}],"label":"help: a local variable with a similar name exists: `z`","#,
+        r#""suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"#,
+        r#""rendered":"error[E0425]: cannot find value `y` in this scope\n --> main.rs:3:20\n  |\n3 |     "#,
+        r#"println!(\"{}\", y);\n  |                    ^ help: a local variable with a similar name exists: "#,
+        r#"`z`\n\n"}"#,
+        "\n",
+        r#"{"$message_type":"diagnostic","message":"aborting due to 1 previous error; 1 warning emitted","#,
+        r#""code":null,"level":"error","spans":[],"children":[],"#,
+        r#""rendered":"error: aborting due to 1 previous error; 1 warning emitted\n\n"}"#,
+        "\n",
+        r#"{"$message_type":"diagnostic","#,
+        r#""message":"For more information about this error, try `rustc --explain E0425`.","code":null,"#,
+        r#""level":"failure-note","spans":[],"children":[],"#,
+        r#""rendered":"For more information about this error, try `rustc --explain E0425`.\n"}"#,
+        "\n",
+    );
+
+    #[test]
+    fn normalizes_rustc_diagnostics() {
+        let parsed = Rust.diagnostics(RUSTC_JSON);
+        assert_eq!(
+            parsed.stderr,
+            "\
+warning: unused variable: `z`
+ --> main.rs:2:9
+  |
+2 |     let z = 1;
+  |         ^ help: if this is intentional, prefix it with an underscore: `_z`
+  |
+  = note: `#[warn(unused_variables)]` on by default
+
+error[E0425]: cannot find value `y` in this scope
+ --> main.rs:3:20
+  |
+3 |     println!(\"{}\", y);
+  |                    ^ help: a local variable with a similar name exists: `z`
+
+error: aborting due to 1 previous error; 1 warning emitted
+
+For more information about this error, try `rustc --explain E0425`.
+"
+        );
+
+        // The summaries are not diagnostics of their own.
+        let [unused, unresolved] = parsed.diagnostics.as_slice() else {
+            panic!("{:?}", parsed.diagnostics);
+        };
+        let span = |line, column| Span {
+            file: Some("main.rs".to_owned()),
+            line: Some(line),
+            column: Some(column),
+            end_line: Some(line),
+            end_column: Some(column + 1),
+        };
+        assert_eq!(unused.span, span(2, 9));
+        assert_eq!(unused.severity, Severity::Warning);
+        assert_eq!(unused.code.as_deref(), Some("unused_variables"));
+        // Children without replacements become notes, the others suggestions.
+        assert_eq!(
+            unused.notes,
+            ["note: `#[warn(unused_variables)]` on by default"]
+        );
+        let [suggestion] = unused.suggestions.as_slice() else {
+            panic!("{:?}", unused.suggestions);
+        };
+        assert_eq!(
+            suggestion.message,
+            "if this is intentional, prefix it with an underscore"
+        );
+        let [replacement] = suggestion.replacements.as_slice() else {
+            panic!("{:?}", suggestion.replacements);
+        };
+        assert_eq!(replacement.span, span(2, 9));
+        assert_eq!(replacement.text, "_z");
+
+        assert_eq!(unresolved.span, span(3, 20));
+        assert_eq!(unresolved.severity, Severity::Error);
+        assert_eq!(unresolved.code.as_deref(), Some("E0425"));
+        // The label of the primary span is kept as a note.
+        assert_eq!(
+            unresolved.notes,
+            ["help: a local variable with a similar name exists: `z`"]
+        );
+        assert!(unresolved.suggestions.is_empty());
+    }
+
+    #[test]
+    fn keeps_output_of_a_panicking_compiler() {
+        let parsed = Rust
+            .diagnostics("thread 'rustc' panicked at compiler/rustc_middle/src/ty/mod.rs:1:1\n");
+        assert!(parsed.diagnostics.is_empty());
+        assert_eq!(
+            parsed.stderr,
+            "thread 'rustc' panicked at compiler/rustc_middle/src/ty/mod.rs:1:1\n"
+        );
+    }
+
+    #[test]
+    fn parses_install_options() {
+        let options = BTreeMap::from([
+            ("toolchain".to_owned(), "1.81.0".to_owned()),
+            ("extra-toolchains".to_owned(), "beta, nightly".to_owned()),
+        ]);
+        let toolchains = RustToolchains::installed(&Rust.toolchains(&options).unwrap()).unwrap();
+        assert_eq!(toolchains.names(), ["1.81.0", "beta", "nightly"]);
+        assert_eq!(toolchains.components, ["rustfmt", "clippy"]);
+
+        let unknown = BTreeMap::from([("channel".to_owned(), "beta".to_owned())]);
+        assert!(Rust.toolchains(&unknown).is_err());
+        let invalid = BTreeMap::from([("components".to_owned(), "rustfmt;id".to_owned())]);
+        assert!(Rust.toolchains(&invalid).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! Zig, from the pinned release of https://ziglang.org.
+
+use color_eyre::{eyre::Context, Result};
+use serde_json::Value;
+use tracing::debug;
+
+use super::{args, Language};
+use crate::{
+    emit::{AsmSyntax, Emit},
+    execution::{ExecutionRequest, BINARY_NAME, EMIT_FILE_NAME},
+    host::Arch,
+    init::EphemeralContainer,
+};
+
+const ZIG_VERSION: &str = "0.13.0";
+const ZIG_ROOT: &str = "/usr/local/zig";
+
+/// SHA-256 of the Zig release tarball for the architecture, from https://ziglang.org/download/index.json.
+fn zig_sha256(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X86_64 => "d45312e61ebcc48032b77bc4cf7fd6915c11fa16e4aad116b66c9468211230ea",
+        Arch::Aarch64 => "041ac42323837eb5624068acd8b00cd5777dac4cf91179e8dad7a7e90dd0c556",
+    }
+}
+
+/// Zig, which only compiles the root of the module tree, `main.zig` if present.
+#[derive(Debug)]
+pub struct Zig;
+
+impl Language for Zig {
+    fn name(&self) -> &'static str {
+        "zig"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Zig"
+    }
+
+    /// Install the pinned Zig release.
+    fn install(&self, container: &EphemeralContainer, _toolchains: &Value) -> Result<()> {
+        debug!("Installing Zig {ZIG_VERSION}");
+        let arch = container.arch();
+        let name = format!("zig-linux-{arch}-{ZIG_VERSION}");
+        let url = format!("https://ziglang.org/download/{ZIG_VERSION}/{name}.tar.xz");
+        container.download_verified(&url, zig_sha256(arch), "/tmp/zig.tar.xz")?;
+        container
+            .run(format!(
+                "tar -xJf /tmp/zig.tar.xz -C /usr/local && mv /usr/local/{name} {ZIG_ROOT} && rm /tmp/zig.tar.xz"
+            ))
+            .context("Could not install Zig")?;
+        Ok(())
+    }
+
+    fn install_paths(&self) -> &'static [&'static str] {
+        &[ZIG_ROOT]
+    }
+
+    fn profile(&self) -> Option<String> {
+        Some(format!("export PATH=\"{ZIG_ROOT}:$PATH\"\n"))
+    }
+
+    fn version_command(&self) -> &'static str {
+        "zig version"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["zig"]
+    }
+
+    fn asm_syntaxes(&self) -> &'static [AsmSyntax] {
+        &[AsmSyntax::Att]
+    }
+
+    fn compile_command(&self, request: &ExecutionRequest) -> Vec<String> {
+        let mut command = args(&["zig", "build-exe", "-O", "ReleaseSafe"]);
+        command.push(format!("-femit-bin={BINARY_NAME}"));
+        command.push(request.root("main.zig"));
+        command
+    }
+
+    fn emit_command(&self, request: &ExecutionRequest, emit: Emit) -> Vec<String> {
+        let mut command = args(&["zig", "build-obj", "-O", "ReleaseSafe", "-fno-emit-bin"]);
+        command.push(match emit {
+            Emit::LlvmIr => format!("-femit-llvm-ir={EMIT_FILE_NAME}"),
+            _ => format!("-femit-asm={EMIT_FILE_NAME}"),
+        });
+        command.push(request.root("main.zig"));
+        command
+    }
+}
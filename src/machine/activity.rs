@@ -0,0 +1,124 @@
+//! Detect whether a VM is in use, so that VMs nobody uses anymore can be torn down.
+//!
+//! A VM counts as active while its network traffic (e.g. an SSH session) or the CPU time of its firecracker process
+//! increase noticeably, or when it was used through the agent. The counters are compared between two samples, so they
+//! should be sampled every few seconds.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{Context, OptionExt},
+    Result,
+};
+
+/// Counters of a VM that increase while it is in use.
+#[derive(Debug, Clone, Copy)]
+struct Counters {
+    net_bytes: u64,
+    cpu_ticks: u64,
+    events: u64,
+}
+
+/// Counts events that show that a VM is in use, like requests to the agent.
+#[derive(Debug, Default)]
+pub struct ActivityEvents(AtomicU64);
+
+impl ActivityEvents {
+    pub fn record(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Activity {
+    last_active: Instant,
+    last_sample: Option<(Instant, Counters)>,
+}
+
+impl Activity {
+    /// Traffic between two samples below this is background noise like ARP.
+    const NET_BYTES_THRESHOLD: u64 = 1024;
+    /// Fraction of a CPU used by an idle guest (timers, kernel threads) that does not count as activity.
+    const CPU_THRESHOLD: f64 = 0.05;
+    /// `USER_HZ`, the unit of the CPU times in `/proc`, which is fixed to 100 on Linux.
+    const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+
+    pub fn new() -> Self {
+        Self {
+            last_active: Instant::now(),
+            last_sample: None,
+        }
+    }
+
+    /// Start counting the idle time from now, e.g. when a VM that waited in the pool is handed out.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Sample the counters of the firecracker process `pid` using the tap `if_name` and return for how long the VM
+    /// has been idle.
+    pub fn update(&mut self, pid: u32, if_name: &str, events: &ActivityEvents) -> Result<Duration> {
+        let now = Instant::now();
+        let counters = Counters {
+            net_bytes: net_bytes(if_name)?,
+            cpu_ticks: cpu_ticks(pid)?,
+            events: events.count(),
+        };
+
+        if let Some((sampled_at, last)) = self.last_sample.replace((now, counters)) {
+            let cpu_secs = counters.cpu_ticks.saturating_sub(last.cpu_ticks) as f64
+                / Self::CLOCK_TICKS_PER_SEC;
+            let cpu_usage = cpu_secs / now.duration_since(sampled_at).as_secs_f64();
+            if counters.net_bytes.saturating_sub(last.net_bytes) > Self::NET_BYTES_THRESHOLD
+                || cpu_usage > Self::CPU_THRESHOLD
+                || counters.events > last.events
+            {
+                self.last_active = now;
+            }
+        }
+
+        Ok(now.duration_since(self.last_active))
+    }
+}
+
+/// Bytes received and sent on the given interface.
+fn net_bytes(if_name: &str) -> Result<u64> {
+    let mut bytes = 0;
+    for counter in ["rx_bytes", "tx_bytes"] {
+        let path = format!("/sys/class/net/{if_name}/statistics/{counter}");
+        let value =
+            std::fs::read_to_string(&path).with_context(|| format!("Could not read {path}"))?;
+        bytes += value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("Invalid value in {path}: {value}"))?;
+    }
+    Ok(bytes)
+}
+
+/// CPU time (user and system) used by all threads of the given process, in clock ticks.
+fn cpu_ticks(pid: u32) -> Result<u64> {
+    let path = format!("/proc/{pid}/stat");
+    let stat = std::fs::read_to_string(&path).with_context(|| format!("Could not read {path}"))?;
+    // The command name may contain spaces, so start after it. `utime` and `stime` are fields 14 and 15, the state
+    // following the command name is field 3.
+    let (_, fields) = stat
+        .rsplit_once(')')
+        .ok_or_eyre(format!("Invalid contents of {path}"))?;
+    let mut fields = fields.split_whitespace().skip(11);
+    let mut ticks = 0;
+    for _ in 0..2 {
+        ticks += fields
+            .next()
+            .and_then(|f| f.parse::<u64>().ok())
+            .ok_or_eyre(format!("Invalid contents of {path}"))?;
+    }
+    Ok(ticks)
+}
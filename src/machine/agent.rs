@@ -0,0 +1,292 @@
+//! Client for the agent running inside of every VM, see the `codepot-agent` crate for the protocol.
+//!
+//! The agent is reached over the vsock device of the VM. Firecracker exposes its host side as a Unix socket, on which
+//! connections to a port of the guest are opened with a `CONNECT <port>` handshake, see
+//! https://github.com/firecracker-microvm/firecracker/blob/main/docs/vsock.md.
+
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use codepot_agent::{read_frame, write_frame, ExecRequest, Request, Response, PORT};
+use color_eyre::{
+    eyre::{bail, eyre, Context, OptionExt},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::activity::ActivityEvents;
+
+/// A client talking to the agent of a VM. Like the `ApiClient`, every operation opens a new connection.
+#[derive(Debug, Clone)]
+pub struct AgentClient {
+    socket_path: PathBuf,
+    events: Arc<ActivityEvents>,
+}
+
+/// How a command run by the agent exited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitStatus {
+    /// Exit code, if the command was not killed by a signal.
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Collected output of a command run by the agent.
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub status: ExitStatus,
+    // Only the commands codepot runs itself use `run`, and those report through their exit status and stderr.
+    #[allow(dead_code)]
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Reported by a running command.
+#[derive(Debug, Clone)]
+pub enum ExecEvent {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exited(ExitStatus),
+}
+
+/// A command running inside of the guest.
+#[derive(Debug)]
+pub struct Execution {
+    stream: UnixStream,
+    exited: bool,
+}
+
+impl AgentClient {
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    /// How long the agent may take to answer a request or to report the next event of a command started by `run`.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(socket_path: impl AsRef<Path>, events: Arc<ActivityEvents>) -> Self {
+        Self {
+            socket_path: socket_path.as_ref().to_owned(),
+            events,
+        }
+    }
+
+    /// Wait until the agent answers.
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            match self.ping() {
+                Ok(()) => return Ok(()),
+                Err(err) if start.elapsed() >= timeout => {
+                    return Err(err)
+                        .with_context(|| format!("Agent did not become ready within {timeout:?}"))
+                }
+                Err(_) => sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    /// Check that the agent is alive.
+    pub fn ping(&self) -> Result<()> {
+        match self.request(&Request::Ping, &[])? {
+            (Response::Pong, _) => Ok(()),
+            (response, _) => Err(unexpected(response)),
+        }
+    }
+
+    /// Start a command, whose input and output are streamed through the returned `Execution`.
+    pub fn exec(&self, request: &ExecRequest) -> Result<Execution> {
+        debug!("Running {} {:?} in guest", request.program, request.args);
+        let mut stream = self.connect()?;
+        // Commands may run for a long time, limits are up to the caller, see `Execution::set_timeout`.
+        stream.set_read_timeout(None)?;
+        write_frame(&mut stream, &Request::Exec(request.clone()), &[])?;
+        self.events.record();
+        Ok(Execution {
+            stream,
+            exited: false,
+        })
+    }
+
+    /// Run a command to completion, feeding it `stdin` and collecting its output. Meant for the quick commands codepot
+    /// runs itself, so it gives up if the command does not report back within `REQUEST_TIMEOUT`.
+    pub fn run(&self, request: &ExecRequest, stdin: &[u8]) -> Result<Output> {
+        let mut execution = self.exec(request)?;
+        execution.set_timeout(Some(Self::REQUEST_TIMEOUT))?;
+        let feeder = execution.feed_stdin(stdin.to_owned())?;
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = loop {
+            match execution.next_event()? {
+                ExecEvent::Stdout(data) => stdout.extend_from_slice(&data),
+                ExecEvent::Stderr(data) => stderr.extend_from_slice(&data),
+                ExecEvent::Exited(status) => break status,
+            }
+        };
+        // The command does not have to read all of its input, so failing to feed it is not an error.
+        if let Ok(Err(err)) = feeder.join() {
+            debug!("Could not feed all input to {}: {err}", request.program);
+        }
+
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Write a file inside of the guest, owned by the given user and group or by root.
+    pub fn write_file(
+        &self,
+        path: impl AsRef<Path>,
+        contents: &[u8],
+        mode: u32,
+        owner: Option<(u32, u32)>,
+    ) -> Result<()> {
+        let path = path.as_ref().to_owned();
+        let request = Request::WriteFile {
+            path: path.clone(),
+            mode,
+            owner,
+        };
+        match self
+            .request(&request, contents)
+            .with_context(|| format!("Could not write {} in guest", path.display()))?
+        {
+            (Response::Done, _) => Ok(()),
+            (response, _) => Err(unexpected(response)),
+        }
+    }
+
+    /// Read a file from the guest.
+    pub fn read_file(&self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let path = path.as_ref().to_owned();
+        match self
+            .request(&Request::ReadFile { path: path.clone() }, &[])
+            .with_context(|| format!("Could not read {} from guest", path.display()))?
+        {
+            (Response::File, contents) => Ok(contents),
+            (response, _) => Err(unexpected(response)),
+        }
+    }
+
+    /// Reseed the random number generator of the guest with `seed`.
+    pub fn reseed(&self, seed: &[u8]) -> Result<()> {
+        match self
+            .request(&Request::Reseed, seed)
+            .context("Could not reseed guest")?
+        {
+            (Response::Done, _) => Ok(()),
+            (response, _) => Err(unexpected(response)),
+        }
+    }
+
+    /// Send a request that is answered with a single response, turning error responses into errors. Everything but
+    /// health checks and reseeding counts as activity of the VM.
+    fn request(&self, request: &Request, data: &[u8]) -> Result<(Response, Vec<u8>)> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(Self::REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(Self::REQUEST_TIMEOUT))?;
+        write_frame(&mut stream, request, data)?;
+        if !matches!(request, Request::Ping | Request::Reseed) {
+            self.events.record();
+        }
+        match read_frame(&mut stream)?.ok_or_eyre("Agent closed the connection")? {
+            (Response::Error { message }, _) => bail!("Agent request failed: {message}"),
+            response => Ok(response),
+        }
+    }
+
+    /// Open a connection to the agent, which times out reads after `CONNECT_TIMEOUT` until the caller sets its own
+    /// timeout.
+    fn connect(&self) -> Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.socket_path).with_context(|| {
+            format!(
+                "Could not connect to vsock socket {}",
+                self.socket_path.display()
+            )
+        })?;
+        stream.set_read_timeout(Some(Self::CONNECT_TIMEOUT))?;
+        stream.write_all(format!("CONNECT {PORT}\n").as_bytes())?;
+
+        // Read the answer byte by byte, so that nothing after it is consumed.
+        let mut answer = Vec::new();
+        let mut byte = [0];
+        while byte[0] != b'\n' {
+            stream
+                .read_exact(&mut byte)
+                .context("Agent is not listening")?;
+            answer.push(byte[0]);
+        }
+        let answer = String::from_utf8_lossy(&answer);
+        if !answer.starts_with("OK ") {
+            bail!("Could not connect to agent: {}", answer.trim());
+        }
+
+        Ok(stream)
+    }
+}
+
+impl Execution {
+    /// Send all of `data` to the command and close its stdin afterwards from another thread, as the command might
+    /// produce output before consuming all of its input.
+    pub fn feed_stdin(&self, data: Vec<u8>) -> Result<JoinHandle<Result<()>>> {
+        let mut input = self.stream.try_clone()?;
+        Ok(std::thread::spawn(move || {
+            write_stdin(&mut input, &data)?;
+            write_frame(&mut input, &Request::CloseStdin, &[])?;
+            Ok(())
+        }))
+    }
+
+    /// Give up waiting for the next event after `timeout`, by default there is no limit.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Block until the command produced output or exited. Must not be called again after it exited.
+    pub fn next_event(&mut self) -> Result<ExecEvent> {
+        if self.exited {
+            bail!("Command already exited");
+        }
+        let (response, data) =
+            read_frame(&mut self.stream)?.ok_or_eyre("Agent closed the connection")?;
+        match response {
+            Response::Stdout => Ok(ExecEvent::Stdout(data)),
+            Response::Stderr => Ok(ExecEvent::Stderr(data)),
+            Response::Exited { code, signal } => {
+                self.exited = true;
+                let _ = self.stream.shutdown(Shutdown::Both);
+                Ok(ExecEvent::Exited(ExitStatus { code, signal }))
+            }
+            Response::Error { message } => bail!("Could not run command: {message}"),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// Send input for a running command in frames of limited size.
+fn write_stdin(stream: &mut UnixStream, data: &[u8]) -> Result<()> {
+    const CHUNK_SIZE: usize = 1024 * 1024;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        write_frame(stream, &Request::Stdin, chunk)?;
+    }
+    Ok(())
+}
+
+fn unexpected(response: Response) -> color_eyre::Report {
+    eyre!("Unexpected response from agent: {response:?}")
+}
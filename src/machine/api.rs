@@ -0,0 +1,469 @@
+//! Client for the firecracker HTTP API that is served on the control socket of every VM.
+//!
+//! See https://github.com/firecracker-microvm/firecracker/blob/main/src/firecracker/swagger/firecracker.yaml for the
+//! API specification.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Result,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::debug;
+
+use super::config::{
+    BalloonStats, BalloonUpdateConfig, BlockDeviceConfig, BlockDeviceUpdateConfig,
+    BootSourceConfig, CreateSnapshotParams, LoadSnapshotParams, LoggerConfig, MachineConfig,
+    MetricsConfig, NetworkInterfaceConfig, NetworkInterfaceUpdateConfig, VmState, VmUpdateConfig,
+};
+
+/// Actions that can be sent to a VM through `PUT /actions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ActionType {
+    /// Flush the metrics, only valid after the metrics have been configured.
+    FlushMetrics,
+    /// Start the microVM, only valid before it has been started.
+    InstanceStart,
+    /// Send CTRL+ALT+DEL to the guest, which makes a well behaved guest shut down.
+    SendCtrlAltDel,
+}
+
+#[derive(Debug, Serialize)]
+struct InstanceActionInfo {
+    action_type: ActionType,
+}
+
+/// Error body returned by firecracker on failed requests.
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    fault_message: String,
+}
+
+/// A client talking to firecracker over its API socket. Every request opens a new connection, so the client is
+/// cheap to create and can be shared freely.
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    socket_path: PathBuf,
+}
+
+impl ApiClient {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(socket_path: impl AsRef<Path>) -> Self {
+        Self {
+            socket_path: socket_path.as_ref().to_owned(),
+        }
+    }
+
+    /// Wait until firecracker accepts connections on the API socket.
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            match UnixStream::connect(&self.socket_path) {
+                Ok(_) => return Ok(()),
+                Err(err) if start.elapsed() >= timeout => {
+                    return Err(err).with_context(|| {
+                        format!(
+                            "Firecracker API socket {} did not become ready within {timeout:?}",
+                            self.socket_path.display()
+                        )
+                    })
+                }
+                Err(_) => sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    /// Update the backing file or the rate limiter of a drive of a running VM.
+    pub fn patch_drive(&self, config: &BlockDeviceUpdateConfig) -> Result<()> {
+        self.patch(&format!("/drives/{}", config.drive_id), config)
+    }
+
+    /// Update the rate limiters of a network interface of a running VM.
+    pub fn patch_network_interface(&self, config: &NetworkInterfaceUpdateConfig) -> Result<()> {
+        self.patch(&format!("/network-interfaces/{}", config.iface_id), config)
+    }
+
+    /// Change the target size of the balloon of a running VM.
+    pub fn patch_balloon(&self, config: &BalloonUpdateConfig) -> Result<()> {
+        self.patch("/balloon", config)
+    }
+
+    /// Latest memory statistics reported by the balloon device.
+    pub fn balloon_statistics(&self) -> Result<BalloonStats> {
+        self.get("/balloon/statistics")
+    }
+
+    /// Configure the log of a VM that was not started yet.
+    pub fn put_logger(&self, config: &LoggerConfig) -> Result<()> {
+        self.put("/logger", config)
+    }
+
+    /// Configure the metrics of a VM that was not started yet.
+    pub fn put_metrics(&self, config: &MetricsConfig) -> Result<()> {
+        self.put("/metrics", config)
+    }
+
+    /// Replace the contents of the MMDS.
+    pub fn put_mmds(&self, contents: &impl Serialize) -> Result<()> {
+        self.put("/mmds", contents)
+    }
+
+    /// Pause or resume the VM.
+    pub fn set_state(&self, state: VmState) -> Result<()> {
+        self.patch("/vm", &VmUpdateConfig { state })
+    }
+
+    /// Create a snapshot of a paused VM.
+    pub fn create_snapshot(&self, params: &CreateSnapshotParams) -> Result<()> {
+        self.put("/snapshot/create", params)
+    }
+
+    /// Load a snapshot into a VM that was neither configured nor started.
+    pub fn load_snapshot(&self, params: &LoadSnapshotParams) -> Result<()> {
+        self.put("/snapshot/load", params)
+    }
+
+    /// Trigger an action on the VM.
+    pub fn action(&self, action_type: ActionType) -> Result<()> {
+        self.put("/actions", &InstanceActionInfo { action_type })
+    }
+
+    pub fn put(&self, path: &str, body: &impl Serialize) -> Result<()> {
+        self.request("PUT", path, Some(&serde_json::to_string(body)?))?;
+        Ok(())
+    }
+
+    pub fn patch(&self, path: &str, body: &impl Serialize) -> Result<()> {
+        self.request("PATCH", path, Some(&serde_json::to_string(body)?))?;
+        Ok(())
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let body = self.request("GET", path, None)?;
+        serde_json::from_str(&body)
+            .with_context(|| format!("Could not parse response of GET {path}: {body}"))
+    }
+
+    /// Send a single request and return the body of the response if it was successful.
+    fn request(&self, method: &str, path: &str, body: Option<&str>) -> Result<String> {
+        debug!(
+            "Firecracker API request {method} {path} on {}: {}",
+            self.socket_path.display(),
+            body.unwrap_or_default()
+        );
+        let stream = UnixStream::connect(&self.socket_path).with_context(|| {
+            format!(
+                "Could not connect to firecracker API socket {}",
+                self.socket_path.display()
+            )
+        })?;
+        stream.set_read_timeout(Some(Self::TIMEOUT))?;
+        stream.set_write_timeout(Some(Self::TIMEOUT))?;
+
+        let (status, response) = send_request(stream, method, path, body)
+            .with_context(|| format!("Firecracker API request {method} {path} failed"))?;
+
+        if !(200..300).contains(&status) {
+            let message = serde_json::from_str::<ApiError>(&response)
+                .map(|e| e.fault_message)
+                .unwrap_or(response);
+            bail!("Firecracker API request {method} {path} failed with status {status}: {message}");
+        }
+        Ok(response)
+    }
+}
+
+/// Endpoints for configuring a VM before it is started. `Machine` hands that configuration to firecracker in one go
+/// through `MachineConfigurator::store`, so they are only needed when driving firecracker by hand.
+#[allow(dead_code)]
+impl ApiClient {
+    /// Configure vcpus and memory of a VM that was not started yet.
+    pub fn put_machine_config(&self, config: &MachineConfig) -> Result<()> {
+        self.put("/machine-config", config)
+    }
+
+    /// Configure kernel and boot args of a VM that was not started yet.
+    pub fn put_boot_source(&self, config: &BootSourceConfig) -> Result<()> {
+        self.put("/boot-source", config)
+    }
+
+    /// Add or replace a drive of a VM that was not started yet.
+    pub fn put_drive(&self, config: &BlockDeviceConfig) -> Result<()> {
+        self.put(&format!("/drives/{}", config.drive_id), config)
+    }
+
+    /// Add or replace a network interface of a VM that was not started yet.
+    pub fn put_network_interface(&self, config: &NetworkInterfaceConfig) -> Result<()> {
+        self.put(&format!("/network-interfaces/{}", config.iface_id), config)
+    }
+
+    /// Boot a VM that was configured through the API.
+    pub fn start_instance(&self) -> Result<()> {
+        self.action(ActionType::InstanceStart)
+    }
+}
+
+/// Write a HTTP/1.1 request to the stream and read back the status code and body of the response.
+fn send_request(
+    mut stream: UnixStream,
+    method: &str,
+    path: &str,
+    body: Option<&str>,
+) -> Result<(u16, String)> {
+    let body = body.unwrap_or_default();
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Accept: application/json\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| eyre!("Invalid status line: {}", status_line.trim()))?;
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            bail!("Connection closed before the response was complete");
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid content length: {value}"))?;
+            }
+        }
+    }
+
+    let mut response = vec![0; content_length];
+    reader.read_exact(&mut response)?;
+    Ok((status, String::from_utf8(response)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+
+    use super::*;
+
+    /// Serve a single connection with the given response and return the request that was received.
+    fn serve_once(
+        listener: UnixListener,
+        status: &'static str,
+        body: &'static str,
+    ) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    content_length = value.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+            request.push_str(&String::from_utf8(request_body).unwrap());
+
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nServer: Firecracker API\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            request
+        })
+    }
+
+    fn fake_firecracker(
+        status: &'static str,
+        body: &'static str,
+    ) -> (tempfile::TempDir, ApiClient, thread::JoinHandle<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("api.socket");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = serve_once(listener, status, body);
+        (dir, ApiClient::new(socket_path), server)
+    }
+
+    #[test]
+    fn sends_requests_with_json_body() {
+        let (_dir, api, server) = fake_firecracker("204 No Content", "");
+        api.set_state(VmState::Paused).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("PATCH /vm HTTP/1.1\r\n"), "{request}");
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert!(
+            request.ends_with("\r\n\r\n{\"state\":\"Paused\"}"),
+            "{request}"
+        );
+    }
+
+    #[test]
+    fn parses_response_body() {
+        let (_dir, api, server) = fake_firecracker(
+            "200 OK",
+            r#"{"target_pages":0,"actual_pages":0,"target_mib":0,"actual_mib":0,"available_memory":1048576}"#,
+        );
+        let stats = api.balloon_statistics().unwrap();
+        assert_eq!(stats.available_memory, Some(1048576));
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /balloon/statistics HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn reports_fault_message() {
+        let (_dir, api, server) = fake_firecracker(
+            "400 Bad Request",
+            r#"{"fault_message":"The requested operation is not supported after starting the microVM."}"#,
+        );
+        let err = api.action(ActionType::SendCtrlAltDel).unwrap_err();
+        server.join().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Firecracker API request PUT /actions failed with status 400: \
+             The requested operation is not supported after starting the microVM."
+        );
+    }
+
+    #[test]
+    fn configures_vm_before_start() {
+        let (_dir, api, server) = fake_firecracker("204 No Content", "");
+        api.put_machine_config(&MachineConfig {
+            vcpu_count: 2,
+            mem_size_mib: 512,
+            smt: false,
+            cpu_template: None,
+            track_dirty_pages: false,
+        })
+        .unwrap();
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("PUT /machine-config HTTP/1.1\r\n"),
+            "{request}"
+        );
+        assert!(
+            request.ends_with(
+                r#"{"vcpu_count":2,"mem_size_mib":512,"smt":false,"cpu_template":null,"track_dirty_pages":false}"#
+            ),
+            "{request}"
+        );
+
+        let (_dir, api, server) = fake_firecracker("204 No Content", "");
+        api.put_boot_source(&BootSourceConfig {
+            kernel_image_path: PathBuf::from("vmlinux"),
+            boot_args: "console=ttyS0".to_owned().into(),
+            initrd_path: None,
+        })
+        .unwrap();
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("PUT /boot-source HTTP/1.1\r\n"),
+            "{request}"
+        );
+        assert!(
+            request.ends_with(
+                r#"{"kernel_image_path":"vmlinux","boot_args":"console=ttyS0","initrd_path":null}"#
+            ),
+            "{request}"
+        );
+
+        let (_dir, api, server) = fake_firecracker("204 No Content", "");
+        api.put_drive(&BlockDeviceConfig {
+            drive_id: "home".to_owned(),
+            partuuid: None,
+            is_root_device: false,
+            is_read_only: Some(false),
+            path_on_host: Some(PathBuf::from("home.img")),
+            rate_limiter: None,
+            file_engine_type: None,
+            socket: None,
+        })
+        .unwrap();
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("PUT /drives/home HTTP/1.1\r\n"),
+            "{request}"
+        );
+        assert!(
+            request.contains(r#""path_on_host":"home.img""#),
+            "{request}"
+        );
+
+        let (_dir, api, server) = fake_firecracker("204 No Content", "");
+        api.put_network_interface(&NetworkInterfaceConfig {
+            iface_id: "eth0".to_owned(),
+            host_dev_name: "codepot0".to_owned(),
+            guest_mac: Some("06:00:00:00:00:01".to_owned()),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+        })
+        .unwrap();
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("PUT /network-interfaces/eth0 HTTP/1.1\r\n"),
+            "{request}"
+        );
+        assert!(
+            request.contains(r#""host_dev_name":"codepot0""#),
+            "{request}"
+        );
+
+        let (_dir, api, server) = fake_firecracker("204 No Content", "");
+        api.start_instance().unwrap();
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("PUT /actions HTTP/1.1\r\n"),
+            "{request}"
+        );
+        assert!(
+            request.ends_with(r#"{"action_type":"InstanceStart"}"#),
+            "{request}"
+        );
+    }
+
+    #[test]
+    fn waits_until_socket_is_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let api = ApiClient::new(dir.path().join("api.socket"));
+        assert!(api.wait_until_ready(Duration::from_millis(50)).is_err());
+        let _listener = UnixListener::bind(dir.path().join("api.socket")).unwrap();
+        api.wait_until_ready(Duration::from_millis(50)).unwrap();
+    }
+}
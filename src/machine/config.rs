@@ -1,16 +1,18 @@
 use std::{
-    fs::File,
     io::Write,
     net::Ipv4Addr,
     path::{Path, PathBuf},
 };
 
-use color_eyre::Result;
-use ipnet::Ipv4Net;
-use serde::Serialize;
-use tempfile::tempfile;
+use color_eyre::{eyre::Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
 use tracing::debug;
 
+use crate::{host::Arch, util::clone_file};
+
+/// The kernel command line. It is readable by every process in the guest, so configuration is passed through the
+/// `Metadata` instead.
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
 pub struct BootArgs(String);
 
@@ -20,48 +22,16 @@ impl From<String> for BootArgs {
     }
 }
 
-impl BootArgs {
-    pub const SSH_KEY_KEY: &'static str = "ssh_key";
-    pub const STATIC_IP_KEY: &'static str = "static_ip";
-    pub const GATEWAY_IP_KEY: &'static str = "gateway_ip";
-
-    pub fn new() -> Self {
-        Self::default()
-    }
-
-    /// Add another argument to the boot args.
-    pub fn arg(&mut self, key: &str, value: &str) -> &mut Self {
-        const PATTERN: [char; 2] = [' ', '='];
-        self.0.push(' ');
-        if key.contains(PATTERN) {
-            self.0.push('"');
-        }
-        self.0.push_str(key);
-        if key.contains(PATTERN) {
-            self.0.push('"');
-        }
-        self.0.push('=');
-        if value.contains(PATTERN) {
-            self.0.push('"');
-        }
-        self.0.push_str(value);
-        if value.contains(PATTERN) {
-            self.0.push('"');
-        }
-        self
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
-struct BootSourceConfig {
-    kernel_image_path: PathBuf,
-    boot_args: BootArgs,
-    initrd_path: Option<PathBuf>,
+pub struct BootSourceConfig {
+    pub kernel_image_path: PathBuf,
+    pub boot_args: BootArgs,
+    pub initrd_path: Option<PathBuf>,
 }
 
 /// The engine file type, either Sync or Async (through io_uring).
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
-enum FileEngineType {
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileEngineType {
     /// Use an Async engine, based on io_uring.
     Async,
     /// Use a Sync engine, based on blocking system calls.
@@ -72,143 +42,624 @@ enum FileEngineType {
 /// Use this structure to set up the Block Device before booting the kernel. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/drive.rs#L29C1-L65C2.
 #[derive(Debug, PartialEq, Eq, Serialize)]
 #[serde(deny_unknown_fields)]
-struct BlockDeviceConfig {
+pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
-    drive_id: String,
+    pub drive_id: String,
     /// Part-UUID. Represents the unique id of the boot partition of this device. It is
     /// optional and it will be used only if the `is_root_device` field is true.
-    partuuid: Option<String>,
+    pub partuuid: Option<String>,
     /// If set to true, it makes the current device the root block device.
     /// Setting this flag to true will mount the block device in the
     /// guest under /dev/vda unless the partuuid is present.
-    is_root_device: bool,
+    pub is_root_device: bool,
     // VirtioBlock specific fields
     /// If set to true, the drive is opened in read-only mode. Otherwise, the
     /// drive is opened as read-write.
-    is_read_only: Option<bool>,
+    pub is_read_only: Option<bool>,
     /// Path of the drive.
-    path_on_host: Option<PathBuf>,
-    // /// Rate Limiter for I/O operations.
-    // rate_limiter: Option<RateLimiterConfig>,
+    pub path_on_host: Option<PathBuf>,
+    /// Rate Limiter for I/O operations.
+    pub rate_limiter: Option<RateLimiterConfig>,
     /// The type of IO engine used by the device.
     #[serde(rename = "io_engine")]
-    file_engine_type: Option<FileEngineType>,
+    pub file_engine_type: Option<FileEngineType>,
 
     // VhostUserBlock specific fields
     /// Path to the vhost-user socket.
-    socket: Option<String>,
+    pub socket: Option<String>,
+}
+
+/// Templates shipped with firecracker that normalize the CPU features exposed to the guest. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/cpu_config/templates.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StaticCpuTemplate {
+    /// C3 template for Intel CPUs.
+    C3,
+    /// T2 template for Intel CPUs.
+    T2,
+    /// T2S template for Intel CPUs.
+    T2S,
+    /// T2CL template for Intel Cascade Lake and newer CPUs.
+    T2CL,
+    /// T2A template for AMD CPUs.
+    T2A,
+    /// V1N1 template for ARM Neoverse V1 CPUs.
+    V1N1,
+}
+
+impl StaticCpuTemplate {
+    /// CPU vendor the template is made for, as reported by CPUID. ARM CPUs do not report one.
+    pub fn vendor(&self) -> Option<&'static str> {
+        match self {
+            Self::C3 | Self::T2 | Self::T2S | Self::T2CL => Some("GenuineIntel"),
+            Self::T2A => Some("AuthenticAMD"),
+            Self::V1N1 => None,
+        }
+    }
+}
+
+/// A CPU template, either one shipped with firecracker or a custom one, see
+/// https://github.com/firecracker-microvm/firecracker/blob/main/docs/cpu_templates/cpu-templates.md.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CpuTemplate {
+    Static(StaticCpuTemplate),
+    /// Absolute path of a JSON file with a custom template.
+    Custom {
+        custom: PathBuf,
+    },
 }
 
 /// Configuration of the microvm. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/machine_config.rs#L175.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
-struct MachineConfig {
+pub struct MachineConfig {
     /// Number of vcpu to start.
-    vcpu_count: u8,
+    pub vcpu_count: u8,
     /// The memory size in MiB.
-    mem_size_mib: usize,
+    pub mem_size_mib: usize,
     /// Enables or disabled SMT.
-    smt: bool,
+    pub smt: bool,
+    /// A CPU template that it is used to filter the CPU features exposed to the guest.
+    pub cpu_template: Option<StaticCpuTemplate>,
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
-    track_dirty_pages: bool,
+    pub track_dirty_pages: bool,
 }
 
 /// This struct represents the strongly typed equivalent of the json body from net iface
 /// related requests. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/net.rs#L19.
 #[derive(Debug, PartialEq, Eq, Serialize)]
 #[serde(deny_unknown_fields)]
-struct NetworkInterfaceConfig {
+pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
-    iface_id: String,
+    pub iface_id: String,
     /// Host level path for the guest network interface.
-    host_dev_name: String,
+    pub host_dev_name: String,
     /// Guest MAC address.
-    guest_mac: Option<String>,
-    // /// Rate Limiter for received packages.
-    // rx_rate_limiter: Option<RateLimiterConfig>,
-    // /// Rate Limiter for transmitted packages.
-    // tx_rate_limiter: Option<RateLimiterConfig>,
+    pub guest_mac: Option<String>,
+    /// Rate Limiter for received packages.
+    pub rx_rate_limiter: Option<RateLimiterConfig>,
+    /// Rate Limiter for transmitted packages.
+    pub tx_rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// A token bucket with size `size` that is refilled every `refill_time` milliseconds. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/mod.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenBucketConfig {
+    /// The size for the token bucket.
+    pub size: u64,
+    /// The initial size of a token bucket.
+    pub one_time_burst: Option<u64>,
+    /// The amount of milliseconds it takes for the bucket to be refilled.
+    pub refill_time: u64,
+}
+
+/// A rate limiter with a bandwidth (bytes) and an operations token bucket. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/mod.rs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimiterConfig {
+    /// Data used to initialize the RateLimiter::bandwidth bucket.
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// Data used to initialize the RateLimiter::ops bucket.
+    pub ops: Option<TokenBucketConfig>,
+}
+
+impl RateLimiterConfig {
+    /// An update that replaces a rate limiter by `limiter`. Buckets missing in an update are left unchanged, while
+    /// buckets of size 0 are disabled.
+    pub fn replacing(limiter: Option<Self>) -> Self {
+        const DISABLED: TokenBucketConfig = TokenBucketConfig {
+            size: 0,
+            one_time_burst: None,
+            refill_time: 0,
+        };
+        let limiter = limiter.unwrap_or_default();
+        Self {
+            bandwidth: Some(limiter.bandwidth.unwrap_or(DISABLED)),
+            ops: Some(limiter.ops.unwrap_or(DISABLED)),
+        }
+    }
+}
+
+/// Rate limits of a VM, so that a single VM cannot starve the others of disk or network bandwidth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// Limits the writable drives.
+    pub drive: Option<RateLimiterConfig>,
+    /// Limits the traffic received by the guest.
+    pub net_rx: Option<RateLimiterConfig>,
+    /// Limits the traffic sent by the guest.
+    pub net_tx: Option<RateLimiterConfig>,
+    /// Limits the randomness handed to the guest by the entropy device.
+    #[serde(default)]
+    pub entropy: Option<RateLimiterConfig>,
+}
+
+/// Only provided fields will be updated. I.e. if any optional fields are missing, they will not be updated. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/drive.rs.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockDeviceUpdateConfig {
+    /// The drive ID, as provided by the user at creation time.
+    pub drive_id: String,
+    /// New block file path on the host. Only provided data will be updated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_on_host: Option<PathBuf>,
+    /// New rate limiter config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// The data fed into a network iface update request. Currently, only the RX and TX rate limiters can be updated. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/net.rs.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceUpdateConfig {
+    /// The net iface ID, as provided by the user at iface creation time.
+    pub iface_id: String,
+    /// New RX rate limiter config. Only provided data will be updated. I.e. if any optional data
+    /// is missing, it will not be nullified, but left unchanged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rx_rate_limiter: Option<RateLimiterConfig>,
+    /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
+    /// is missing, it will not be nullified, but left unchanged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// The type of snapshot to create. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/snapshot.rs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SnapshotType {
+    /// Diff snapshot.
+    #[allow(dead_code)]
+    Diff,
+    /// Full snapshot.
+    #[default]
+    Full,
+}
+
+/// Stores the configuration that will be used for creating a snapshot. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/snapshot.rs.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateSnapshotParams {
+    /// This field is used to determine the type of snapshot to create.
+    pub snapshot_type: SnapshotType,
+    /// Path to the file that will contain the microVM state.
+    pub snapshot_path: PathBuf,
+    /// Path to the file that will contain the guest memory.
+    pub mem_file_path: PathBuf,
+}
+
+/// Describes the possible backend types for the guest memory. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/snapshot.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MemBackendType {
+    /// Guest memory contents will be loaded from a file.
+    File,
+    /// Guest memory will be served through UFFD by a separate process.
+    #[allow(dead_code)]
+    Uffd,
+}
+
+/// Configuration of the backend of the guest memory when loading a snapshot. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/snapshot.rs.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemBackendConfig {
+    /// Path to the backend used to handle the guest memory.
+    pub backend_path: PathBuf,
+    /// Specifies the guest memory backend type.
+    pub backend_type: MemBackendType,
+}
+
+/// Replaces the host device of a network interface when loading a snapshot (firecracker >= 1.12).
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkOverride {
+    /// ID of the network interface in the snapshot.
+    pub iface_id: String,
+    /// Host level path of the network interface to use instead.
+    pub host_dev_name: String,
+}
+
+/// Stores the configuration that will be used for loading a snapshot. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/snapshot.rs.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadSnapshotParams {
+    /// Path to the file that contains the microVM state to be loaded.
+    pub snapshot_path: PathBuf,
+    /// The backend of the guest memory.
+    pub mem_backend: MemBackendConfig,
+    /// When set to true, the vm is also resumed if the snapshot load is successful.
+    pub resume_vm: bool,
+    /// Network host devices to use instead of the ones in the snapshot.
+    pub network_overrides: Vec<NetworkOverride>,
+}
+
+/// The microVM state options. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/snapshot.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum VmState {
+    /// The microVM is paused, which means that we can create a snapshot of it.
+    Paused,
+    /// The microVM is resumed; this state should be set after we load a snapshot.
+    #[allow(dead_code)]
+    Resumed,
+}
+
+/// Keeps the microVM state necessary in the snapshotting context. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/snapshot.rs.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmUpdateConfig {
+    /// The microVM state, which can be `paused` or `resumed`.
+    pub state: VmState,
+}
+
+/// Configuration of the balloon device, through which guest memory is handed back to the host. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/balloon.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonDeviceConfig {
+    /// Target balloon size in MiB.
+    pub amount_mib: u32,
+    /// Option to deflate the balloon in case the guest is out of memory.
+    pub deflate_on_oom: bool,
+    /// Interval in seconds between refreshing statistics, 0 disables them.
+    pub stats_polling_interval_s: u16,
+}
+
+/// The data fed into a balloon update request. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/balloon.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonUpdateConfig {
+    /// Target balloon size in MiB.
+    pub amount_mib: u32,
+}
+
+/// Statistics reported by the balloon device, memory sizes are in bytes. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/devices/virtio/balloon/device.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BalloonStats {
+    pub target_pages: u32,
+    pub actual_pages: u32,
+    pub target_mib: u32,
+    pub actual_mib: u32,
+    pub swap_in: Option<u64>,
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub free_memory: Option<u64>,
+    pub total_memory: Option<u64>,
+    pub available_memory: Option<u64>,
+    pub disk_caches: Option<u64>,
+    pub hugetlb_allocations: Option<u64>,
+    pub hugetlb_failures: Option<u64>,
+}
+
+/// Configuration of the virtio-rng device, which hands randomness from the host to the guest. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/entropy.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EntropyDeviceConfig {
+    /// Rate Limiter for the randomness handed to the guest.
+    pub rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// Level of the firecracker log. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/logger/logging.rs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[allow(dead_code)]
+pub enum LogLevel {
+    Off,
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+/// Configuration of the firecracker log. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/logger/logging.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggerConfig {
+    /// Named pipe or file used as output for logs.
+    pub log_path: Option<PathBuf>,
+    /// The level of the Logger.
+    pub level: Option<LogLevel>,
+    /// Whether to show the log level in the log.
+    pub show_level: Option<bool>,
+    /// Whether to show the log origin in the log.
+    pub show_log_origin: Option<bool>,
+}
+
+/// Configuration of the firecracker metrics, which are written to the given file every minute and on
+/// `ActionType::FlushMetrics`. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/metrics.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Named pipe or file used as output for metrics.
+    pub metrics_path: PathBuf,
+}
+
+/// Version of the MMDS, version 2 requires a session token for every request. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/mmds/data_store.rs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MmdsVersion {
+    #[allow(dead_code)]
+    V1,
+    #[default]
+    V2,
+}
+
+/// Configuration of the MMDS, which is served on the given network interfaces. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/mmds.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MmdsConfig {
+    /// MMDS version.
+    pub version: MmdsVersion,
+    /// Network interfaces that allow forwarding packets to MMDS.
+    pub network_interfaces: Vec<String>,
+    /// MMDS IPv4 configured address, 169.254.169.254 if not set.
+    pub ipv4_address: Option<Ipv4Addr>,
+}
+
+/// Configuration of the vsock device. The host side of the device is a Unix socket, see
+/// https://github.com/firecracker-microvm/firecracker/blob/main/docs/vsock.md. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/vmm_config/vsock.rs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockDeviceConfig {
+    /// A 32-bit Context Identifier (CID) used to identify the guest.
+    pub guest_cid: u32,
+    /// Path to local unix socket.
+    pub uds_path: PathBuf,
 }
 
 /// Used for configuring a vmm from one single json passed to the Firecracker process. Taken from https://github.com/firecracker-microvm/firecracker/blob/a364da806f8093e8d8ab1a8287be4a0efd4e4658/src/vmm/src/resources.rs#L63C1-L88C2.
 #[derive(Debug, PartialEq, Eq, Serialize)]
 struct VmmConfig {
+    #[serde(rename = "balloon")]
+    balloon_device: Option<BalloonDeviceConfig>,
     #[serde(rename = "drives")]
     block_devices: Vec<BlockDeviceConfig>,
     #[serde(rename = "boot-source")]
     boot_source: BootSourceConfig,
     #[serde(rename = "cpu-config")]
     cpu_config: Option<PathBuf>,
-    // #[serde(rename = "logger")]
-    // logger: Option<crate::logger::LoggerConfig>,
+    #[serde(rename = "logger")]
+    logger: Option<LoggerConfig>,
     #[serde(rename = "machine-config")]
     machine_config: Option<MachineConfig>,
-    // #[serde(rename = "metrics")]
-    // metrics: Option<MetricsConfig>,
-    // #[serde(rename = "mmds-config")]
-    // mmds_config: Option<MmdsConfig>,
+    #[serde(rename = "metrics")]
+    metrics: Option<MetricsConfig>,
+    #[serde(rename = "mmds-config")]
+    mmds_config: Option<MmdsConfig>,
     #[serde(rename = "network-interfaces", default)]
     net_devices: Vec<NetworkInterfaceConfig>,
-    // #[serde(rename = "vsock")]
-    // vsock_device: Option<VsockDeviceConfig>,
-    // #[serde(rename = "entropy")]
-    // entropy_device: Option<EntropyDeviceConfig>,
+    #[serde(rename = "vsock")]
+    vsock_device: Option<VsockDeviceConfig>,
+    #[serde(rename = "entropy")]
+    entropy_device: Option<EntropyDeviceConfig>,
+}
+
+/// ID of the network interface of the guest.
+pub const NETWORK_INTERFACE_ID: &str = "eth0";
+
+/// Extension of the drive clones in the directory of a VM.
+pub const DRIVE_EXTENSION: &str = "img";
+
+/// Context identifier of the guest on the vsock device. Every VM has its own device, so they can all use the same one.
+pub const GUEST_CID: u32 = 3;
+
+/// Name of the Unix socket in the directory of a VM that the host side of the vsock device listens on.
+pub const VSOCK_SOCKET_NAME: &str = "vsock.socket";
+
+/// How often the guest reports memory statistics through the balloon device.
+pub const BALLOON_STATS_INTERVAL_SECS: u16 = 5;
+
+/// Name of the file in the directory of a VM that firecracker writes its metrics to, see `machine::metrics`.
+pub const METRICS_FILE_NAME: &str = "metrics.json";
+
+/// The firecracker log goes to stderr, where it is picked up by `Machine` and kept apart from the serial console on
+/// stdout.
+pub fn logger_config() -> LoggerConfig {
+    LoggerConfig {
+        log_path: Some(PathBuf::from("/dev/stderr")),
+        level: Some(LogLevel::Info),
+        show_level: Some(true),
+        show_log_origin: Some(false),
+    }
+}
+
+/// Metrics are written to `METRICS_FILE_NAME`, relative to the directory of the VM.
+pub fn metrics_config() -> MetricsConfig {
+    MetricsConfig {
+        metrics_path: PathBuf::from(METRICS_FILE_NAME),
+    }
+}
+
+/// What the guest uses as its root filesystem.
+#[derive(Debug, Clone)]
+pub enum RootFs {
+    /// Boot from an ext4 image attached as root drive.
+    Image(PathBuf),
+    /// Boot from an in-memory initrd, with the home directory of the guest user on a separate drive.
+    Initrd {
+        initrd_path: PathBuf,
+        home_image_path: PathBuf,
+    },
 }
 
 pub struct MachineConfigurator(VmmConfig);
 
 impl MachineConfigurator {
-    /// Construct a new configurator from the given config values.
+    /// Construct a new configurator from the given config values, booting the kernel with the default arguments for
+    /// `arch`.
     pub fn new(
+        arch: Arch,
         kernel_image_path: impl AsRef<Path>,
-        rootfs_image_path: impl AsRef<Path>,
-        vcpu_count: u8,
-        mem_size_mib: usize,
-        host_address: Ipv4Addr,
+        root_fs: RootFs,
+        machine_config: MachineConfig,
         host_dev_name: &str,
         guest_mac: &str,
-        ip_address: Ipv4Net,
-        pub_ssh_key: &str,
     ) -> Self {
-        let mut boot_args = BootArgs::from("console=ttyS0 reboot=k panic=1 pci=off".to_owned());
-        boot_args
-            .arg(BootArgs::SSH_KEY_KEY, pub_ssh_key)
-            .arg(BootArgs::STATIC_IP_KEY, &ip_address.to_string())
-            .arg(BootArgs::GATEWAY_IP_KEY, &host_address.to_string());
+        let boot_args = match arch {
+            Arch::X86_64 => "console=ttyS0 reboot=k panic=1 pci=off",
+            // The serial console of aarch64 guests comes from the device tree, keep the boot console until it is up so
+            // that no early output gets lost.
+            Arch::Aarch64 => "keep_bootcon console=ttyS0 reboot=k panic=1 pci=off",
+        };
+        let boot_args = BootArgs::from(boot_args.to_owned());
+
+        let (drive, initrd_path) = match root_fs {
+            RootFs::Image(rootfs_image_path) => (
+                BlockDeviceConfig {
+                    drive_id: "rootfs".to_owned(),
+                    partuuid: None,
+                    is_root_device: true,
+                    is_read_only: Some(false),
+                    path_on_host: Some(rootfs_image_path),
+                    file_engine_type: Some(FileEngineType::Sync),
+                    rate_limiter: None,
+                    socket: None,
+                },
+                None,
+            ),
+            RootFs::Initrd {
+                initrd_path,
+                home_image_path,
+            } => (
+                BlockDeviceConfig {
+                    drive_id: "home".to_owned(),
+                    partuuid: None,
+                    is_root_device: false,
+                    is_read_only: Some(false),
+                    path_on_host: Some(home_image_path),
+                    file_engine_type: Some(FileEngineType::Sync),
+                    rate_limiter: None,
+                    socket: None,
+                },
+                Some(initrd_path),
+            ),
+        };
 
         Self(VmmConfig {
-            block_devices: vec![BlockDeviceConfig {
-                drive_id: "rootfs".to_owned(),
-                partuuid: None,
-                is_root_device: true,
-                is_read_only: Some(false),
-                path_on_host: Some(rootfs_image_path.as_ref().to_owned()),
-                file_engine_type: Some(FileEngineType::Sync),
-                socket: None,
-            }],
+            // Starts deflated, it is inflated while the VM is idle, see `PooledMachine::reclaim_memory`.
+            balloon_device: Some(BalloonDeviceConfig {
+                amount_mib: 0,
+                deflate_on_oom: true,
+                stats_polling_interval_s: BALLOON_STATS_INTERVAL_SECS,
+            }),
+            block_devices: vec![drive],
             boot_source: BootSourceConfig {
                 kernel_image_path: kernel_image_path.as_ref().to_owned(),
                 boot_args,
-                initrd_path: None,
+                initrd_path,
             },
             cpu_config: None,
-            machine_config: Some(MachineConfig {
-                vcpu_count,
-                mem_size_mib,
-                smt: false,
-                track_dirty_pages: false, // Needed for snapshotting
-            }),
+            logger: Some(logger_config()),
+            machine_config: Some(machine_config),
             net_devices: vec![NetworkInterfaceConfig {
-                iface_id: "eth0".to_owned(),
+                iface_id: NETWORK_INTERFACE_ID.to_owned(),
                 host_dev_name: host_dev_name.to_owned(),
                 guest_mac: Some(guest_mac.to_owned()),
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
             }],
+            metrics: Some(metrics_config()),
+            // The guest configures itself from the `Metadata`.
+            mmds_config: Some(MmdsConfig {
+                version: MmdsVersion::V2,
+                network_interfaces: vec![NETWORK_INTERFACE_ID.to_owned()],
+                ipv4_address: None,
+            }),
+            // Relative to the directory of the VM, like the drives.
+            vsock_device: Some(VsockDeviceConfig {
+                guest_cid: GUEST_CID,
+                uds_path: PathBuf::from(VSOCK_SOCKET_NAME),
+            }),
+            // Fresh guests would otherwise wait for enough entropy, e.g. when generating the SSH host keys.
+            entropy_device: Some(EntropyDeviceConfig { rate_limiter: None }),
         })
     }
 
+    /// Limit the writable drives, the network interface and the entropy device of the VM.
+    pub fn set_rate_limits(&mut self, limits: &RateLimits) -> &mut Self {
+        if let Some(entropy_device) = &mut self.0.entropy_device {
+            entropy_device.rate_limiter = limits.entropy;
+        }
+        for drive in &mut self.0.block_devices {
+            if drive.is_read_only != Some(true) {
+                drive.rate_limiter = limits.drive;
+            }
+        }
+        for net_device in &mut self.0.net_devices {
+            net_device.rx_rate_limiter = limits.net_rx;
+            net_device.tx_rate_limiter = limits.net_tx;
+        }
+        self
+    }
+
+    /// Set the IO engine of every drive, given its ID.
+    pub fn set_io_engines(&mut self, engine: impl Fn(&str) -> FileEngineType) -> &mut Self {
+        for drive in &mut self.0.block_devices {
+            drive.file_engine_type = Some(engine(&drive.drive_id));
+        }
+        self
+    }
+
+    /// Filter the CPU features exposed to the guest through the given template.
+    pub fn set_cpu_template(&mut self, template: &CpuTemplate) -> &mut Self {
+        match template {
+            CpuTemplate::Static(template) => {
+                if let Some(machine_config) = &mut self.0.machine_config {
+                    machine_config.cpu_template = Some(*template);
+                }
+            }
+            CpuTemplate::Custom { custom } => self.0.cpu_config = Some(custom.clone()),
+        }
+        self
+    }
+
+    /// Replace every writable drive by a clone inside of `dir`, so that the original images are never modified and
+    /// multiple VMs can use the same images. The clones are removed together with `dir`.
+    ///
+    /// The clones are referenced relative to `dir`, so firecracker has to be run inside of it. That way the drives of
+    /// a snapshot taken of this VM are found in the directory of the VM restored from it.
+    pub fn clone_drives(&mut self, dir: &Path) -> Result<()> {
+        for drive in &mut self.0.block_devices {
+            if drive.is_read_only == Some(true) {
+                continue;
+            }
+            let Some(path_on_host) = &mut drive.path_on_host else {
+                continue;
+            };
+            let clone_name = PathBuf::from(format!("{}.{DRIVE_EXTENSION}", drive.drive_id));
+            let clone_path = dir.join(&clone_name);
+            debug!(
+                "Cloning drive {} from {} to {}",
+                drive.drive_id,
+                path_on_host.display(),
+                clone_path.display()
+            );
+            clone_file(&*path_on_host, &clone_path)
+                .with_context(|| format!("Could not clone drive {}", drive.drive_id))?;
+            *path_on_host = clone_name;
+        }
+        Ok(())
+    }
+
     /// Write the config out so that firecracker can consume it. Note that the file will be destroyed when the returned
     /// handle is dropped, so it should be held until firecracker started up.
-    pub fn store(self) -> Result<File> {
-        let mut file = tempfile()?;
+    pub fn store(self) -> Result<NamedTempFile> {
+        let mut file = NamedTempFile::new()?;
         // Note: writing to a write is often slower than just storing the whole config (which is not that big) on the
         // heap and writing it out in one go.
         let contents = serde_json::to_string(&self.0)?;
@@ -216,7 +667,7 @@ impl MachineConfigurator {
             "Writing machine config {} to temporary config file",
             contents
         );
-        file.write_all(&contents.as_bytes())?;
+        file.write_all(contents.as_bytes())?;
         Ok(file)
     }
 }
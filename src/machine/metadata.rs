@@ -0,0 +1,48 @@
+//! Metadata published to the guest through firecracker's MMDS, from which the guest configures itself.
+//!
+//! The guest reads it with `codepot-metadata <path>`, where the path selects a field of [`Metadata`], e.g.
+//! `network/ip_address`. Unlike the kernel command line, the metadata is not limited in size. It is readable by every
+//! process in the guest once the route to MMDS is set up, so it must not contain secrets.
+
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use serde::Serialize;
+
+use crate::config::InterfaceConfig;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Metadata {
+    /// Id of the VM, filled in once it is started.
+    pub session_id: String,
+    /// Public keys authorized to log into the guest user over SSH.
+    pub ssh_keys: Vec<String>,
+    pub network: NetworkMetadata,
+    /// Seconds without activity after which the VM is torn down.
+    pub idle_timeout_secs: u64,
+    /// Whether the VM is booted to take a snapshot of, which makes it wait to be restored before bringing up
+    /// networking, see `Snapshot`.
+    pub snapshot: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkMetadata {
+    pub ip_address: Ipv4Net,
+    pub gateway: Ipv4Addr,
+    pub mac_address: String,
+    pub nameservers: Vec<Ipv4Addr>,
+}
+
+impl NetworkMetadata {
+    const NAMESERVERS: [Ipv4Addr; 2] = [Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)];
+
+    /// Network settings for a guest using the given interface, with the host as gateway.
+    pub fn new(interface: &InterfaceConfig, gateway: Ipv4Addr) -> Self {
+        Self {
+            ip_address: interface.ip_address,
+            gateway,
+            mac_address: interface.mac_address.clone(),
+            nameservers: Self::NAMESERVERS.to_vec(),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Metrics firecracker writes for every VM, see
+//! https://github.com/firecracker-microvm/firecracker/blob/main/docs/metrics.md.
+//!
+//! Firecracker appends one JSON object per line to the metrics file of a VM, every minute and whenever it is asked to
+//! flush its metrics. The counters in it only cover the time since the previous line, so they are summed up here.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::Context, Result};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use super::{
+    api::{ActionType, ApiClient},
+    config::METRICS_FILE_NAME,
+    Machine,
+};
+
+/// A counter taken from the metrics of firecracker.
+#[derive(Debug)]
+pub struct Counter {
+    /// Name of the counter, without prefix or `_total` suffix.
+    pub name: &'static str,
+    pub help: &'static str,
+    /// Path to the counter in the JSON written by firecracker.
+    path: [&'static str; 2],
+}
+
+/// The counters collected for every VM.
+pub const COUNTERS: [Counter; 13] = [
+    Counter {
+        name: "vcpu_exits_io_in",
+        help: "VM exits for reads from I/O ports.",
+        path: ["vcpu", "exit_io_in"],
+    },
+    Counter {
+        name: "vcpu_exits_io_out",
+        help: "VM exits for writes to I/O ports.",
+        path: ["vcpu", "exit_io_out"],
+    },
+    Counter {
+        name: "vcpu_exits_mmio_read",
+        help: "VM exits for MMIO reads.",
+        path: ["vcpu", "exit_mmio_read"],
+    },
+    Counter {
+        name: "vcpu_exits_mmio_write",
+        help: "VM exits for MMIO writes.",
+        path: ["vcpu", "exit_mmio_write"],
+    },
+    Counter {
+        name: "vcpu_failures",
+        help: "Failures of the vCPUs.",
+        path: ["vcpu", "failures"],
+    },
+    Counter {
+        name: "block_read_bytes",
+        help: "Bytes read from the drives.",
+        path: ["block", "read_bytes"],
+    },
+    Counter {
+        name: "block_write_bytes",
+        help: "Bytes written to the drives.",
+        path: ["block", "write_bytes"],
+    },
+    Counter {
+        name: "block_rate_limited",
+        help: "Drive requests delayed by the rate limiter.",
+        path: ["block", "rate_limiter_throttled_events"],
+    },
+    Counter {
+        name: "net_rx_bytes",
+        help: "Bytes received by the guest.",
+        path: ["net", "rx_bytes_count"],
+    },
+    Counter {
+        name: "net_tx_bytes",
+        help: "Bytes sent by the guest.",
+        path: ["net", "tx_bytes_count"],
+    },
+    Counter {
+        name: "net_rx_rate_limited",
+        help: "Received packets delayed by the rate limiter.",
+        path: ["net", "rx_rate_limiter_throttled"],
+    },
+    Counter {
+        name: "net_tx_rate_limited",
+        help: "Sent packets delayed by the rate limiter.",
+        path: ["net", "tx_rate_limiter_throttled"],
+    },
+    Counter {
+        name: "seccomp_faults",
+        help: "System calls of firecracker denied by its seccomp filters.",
+        path: ["seccomp", "num_faults"],
+    },
+];
+
+/// Totals of the [`COUNTERS`] of a VM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmMetrics([u64; COUNTERS.len()]);
+
+impl VmMetrics {
+    /// Value of the counter at the given index of [`COUNTERS`].
+    pub fn get(&self, index: usize) -> u64 {
+        self.0[index]
+    }
+
+    pub fn add(&mut self, other: &Self) {
+        for (total, value) in self.0.iter_mut().zip(other.0) {
+            *total += value;
+        }
+    }
+
+    /// Add a line written by firecracker, missing counters count as zero.
+    fn add_line(&mut self, line: &Value) {
+        for (total, counter) in self.0.iter_mut().zip(&COUNTERS) {
+            let [group, name] = counter.path;
+            *total += line[group][name].as_u64().unwrap_or_default();
+        }
+    }
+}
+
+/// Reads the metrics file of a VM, remembering how far it was read.
+#[derive(Debug)]
+pub struct MetricsReader {
+    dir: PathBuf,
+    offset: u64,
+    totals: VmMetrics,
+}
+
+impl MetricsReader {
+    /// Reader for the metrics of the VM with the given runtime directory.
+    pub fn new(vm_dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: vm_dir.as_ref().to_owned(),
+            offset: 0,
+            totals: VmMetrics::default(),
+        }
+    }
+
+    /// Totals read so far.
+    pub fn totals(&self) -> &VmMetrics {
+        &self.totals
+    }
+
+    /// Ask firecracker to write its current metrics and add up everything written since the last update.
+    pub fn update(&mut self) -> Result<&VmMetrics> {
+        // The VM may be starting or shutting down, in which case the metrics of the last minute have to do.
+        let api = ApiClient::new(self.dir.join(Machine::API_SOCKET_NAME));
+        if let Err(err) = api.action(ActionType::FlushMetrics) {
+            debug!("Could not flush metrics of {}: {err}", self.dir.display());
+        }
+
+        let path = self.dir.join(METRICS_FILE_NAME);
+        let mut file =
+            File::open(&path).with_context(|| format!("Could not open {}", path.display()))?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+
+        // A line that is not complete yet is read again next time.
+        let Some(end) = data.iter().rposition(|&byte| byte == b'\n') else {
+            return Ok(&self.totals);
+        };
+        for line in data[..end].split(|&byte| byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(line) => self.totals.add_line(&line),
+                Err(err) => warn!("Invalid metrics in {}: {err}", path.display()),
+            }
+        }
+        self.offset += end as u64 + 1;
+        Ok(&self.totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn index(name: &str) -> usize {
+        COUNTERS
+            .iter()
+            .position(|counter| counter.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn adds_up_lines() {
+        let mut metrics = VmMetrics::default();
+        metrics.add_line(&json!({
+            "utc_timestamp_ms": 1,
+            "block": { "read_bytes": 512, "write_bytes": 1024 },
+            "net": { "rx_bytes_count": 10 },
+        }));
+        metrics.add_line(&json!({
+            "block": { "read_bytes": 512 },
+            "seccomp": { "num_faults": 1 },
+            "vcpu": { "exit_io_in": "invalid" },
+        }));
+        assert_eq!(metrics.get(index("block_read_bytes")), 1024);
+        assert_eq!(metrics.get(index("block_write_bytes")), 1024);
+        assert_eq!(metrics.get(index("net_rx_bytes")), 10);
+        assert_eq!(metrics.get(index("seccomp_faults")), 1);
+        assert_eq!(metrics.get(index("vcpu_exits_io_in")), 0);
+        assert_eq!(metrics.get(index("net_tx_bytes")), 0);
+    }
+
+    #[test]
+    fn reads_partial_lines_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = File::create(dir.path().join(METRICS_FILE_NAME)).unwrap();
+        let mut reader = MetricsReader::new(dir.path());
+        let read_bytes = index("block_read_bytes");
+
+        // There is no firecracker to flush the metrics, so only the file is read.
+        assert_eq!(reader.update().unwrap().get(read_bytes), 0);
+
+        write!(
+            file,
+            "{{\"block\":{{\"read_bytes\":1}}}}\n{{\"block\":{{\"read_"
+        )
+        .unwrap();
+        assert_eq!(reader.update().unwrap().get(read_bytes), 1);
+        assert_eq!(reader.update().unwrap().get(read_bytes), 1);
+
+        writeln!(file, "bytes\":2}}}}\nnot json").unwrap();
+        assert_eq!(reader.update().unwrap().get(read_bytes), 3);
+        writeln!(file, "{{\"block\":{{\"read_bytes\":4}}}}").unwrap();
+        assert_eq!(reader.update().unwrap().get(read_bytes), 7);
+        assert_eq!(reader.totals().get(read_bytes), 7);
+    }
+}
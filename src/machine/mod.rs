@@ -1 +1,11 @@
+mod activity;
+pub mod agent;
+pub mod api;
 pub mod config;
+pub mod metadata;
+pub mod metrics;
+mod snapshot;
+mod vm;
+
+pub use snapshot::Snapshot;
+pub use vm::Machine;
@@ -0,0 +1,144 @@
+//! Snapshots of a booted VM, from which new VMs start much faster than by booting them.
+//!
+//! The snapshotted guest is booted with `Metadata::snapshot` set, which makes it stop right before bringing up
+//! networking and wait on the serial console until it is restored. The snapshot is taken at that point. Every VM
+//! restored from it gets the metadata with the network identity (IP, gateway and MAC address) of its own slot before
+//! it is told to continue, so it configures its own identity before it talks to anyone. Its random number generator is
+//! reseeded through the agent, which already runs while the guest waits, before it is told to continue as well.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::{
+    eyre::{ensure, Context},
+    Result,
+};
+use scopeguard::guard;
+use tracing::{debug, info};
+
+use super::{
+    config::{
+        CreateSnapshotParams, LoadSnapshotParams, MemBackendConfig, MemBackendType,
+        NetworkOverride, SnapshotType, VmState, DRIVE_EXTENSION, NETWORK_INTERFACE_ID,
+    },
+    Machine,
+};
+use crate::util::clone_file;
+
+#[derive(Debug)]
+pub struct Snapshot {
+    dir: PathBuf,
+}
+
+impl Snapshot {
+    const STATE_FILE_NAME: &str = "vmstate";
+    const MEMORY_FILE_NAME: &str = "memory";
+    /// Printed by the guest once it waits to be restored.
+    pub const READY_MESSAGE: &str = "codepot: waiting to be restored";
+    /// Sent to a restored guest once its metadata is in place.
+    pub const RESTORED_MESSAGE: &str = "restored";
+
+    /// Open the snapshot at the given directory.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        for file in [Self::STATE_FILE_NAME, Self::MEMORY_FILE_NAME] {
+            ensure!(
+                dir.join(file).try_exists()?,
+                "No snapshot at {}, please run `codepot snapshot`",
+                dir.display()
+            );
+        }
+        Ok(Self { dir })
+    }
+
+    /// Pause the given VM, which has to be waiting to be restored, and store a snapshot of it in `dir`
+    /// together with its drives.
+    pub fn create(machine: &Machine, dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        ensure!(
+            !dir.try_exists()?,
+            "Snapshot already exists at {}, remove it first",
+            dir.display()
+        );
+
+        // Build the snapshot in a temporary directory first, so that no partial snapshot is left behind.
+        let mut temp_dir = dir.as_os_str().to_owned();
+        temp_dir.push(".tmp");
+        let temp_dir = PathBuf::from(temp_dir);
+        let _ = std::fs::remove_dir_all(&temp_dir);
+        std::fs::create_dir_all(&temp_dir)?;
+        let temp_dir = guard(temp_dir, |temp_dir| {
+            let _ = std::fs::remove_dir_all(temp_dir);
+        });
+
+        info!("Creating snapshot of VM {}", machine.id());
+        let api = machine.api();
+        api.set_state(VmState::Paused)?;
+        api.create_snapshot(&CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: temp_dir.join(Self::STATE_FILE_NAME),
+            mem_file_path: temp_dir.join(Self::MEMORY_FILE_NAME),
+        })?;
+
+        // The drives have to be copied while the VM is paused, so that they match the memory of the snapshot.
+        for drive in drives(machine.dir())? {
+            let name = drive.file_name().unwrap();
+            debug!("Storing drive {} in snapshot", drive.display());
+            clone_file(&drive, temp_dir.join(name))
+                .with_context(|| format!("Could not store drive {}", drive.display()))?;
+        }
+
+        std::fs::rename(&*temp_dir, dir)
+            .with_context(|| format!("Could not move snapshot to {}", dir.display()))?;
+        info!("Created snapshot at {}", dir.display());
+
+        Ok(Self {
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// Clone the drives of the snapshot into the directory of a VM that is restored from it.
+    pub(super) fn clone_drives(&self, vm_dir: &Path) -> Result<()> {
+        for drive in drives(&self.dir)? {
+            let name = drive.file_name().unwrap();
+            clone_file(&drive, vm_dir.join(name))
+                .with_context(|| format!("Could not clone drive {}", drive.display()))?;
+        }
+        Ok(())
+    }
+
+    /// IDs of the drives of the snapshot.
+    pub(super) fn drive_ids(&self) -> Result<Vec<String>> {
+        Ok(drives(&self.dir)?
+            .iter()
+            .filter_map(|drive| Some(drive.file_stem()?.to_str()?.to_owned()))
+            .collect())
+    }
+
+    /// Parameters to load the snapshot into a VM that uses the given tap device.
+    pub(super) fn load_params(&self, host_dev_name: &str) -> LoadSnapshotParams {
+        LoadSnapshotParams {
+            snapshot_path: self.dir.join(Self::STATE_FILE_NAME),
+            mem_backend: MemBackendConfig {
+                backend_path: self.dir.join(Self::MEMORY_FILE_NAME),
+                backend_type: MemBackendType::File,
+            },
+            resume_vm: true,
+            network_overrides: vec![NetworkOverride {
+                iface_id: NETWORK_INTERFACE_ID.to_owned(),
+                host_dev_name: host_dev_name.to_owned(),
+            }],
+        }
+    }
+}
+
+/// List the drive clones in the given directory.
+fn drives(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut drives = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == DRIVE_EXTENSION) {
+            drives.push(path);
+        }
+    }
+    Ok(drives)
+}
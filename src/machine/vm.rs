@@ -0,0 +1,514 @@
+//! Spawn and supervise firecracker processes.
+
+use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Condvar, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{bail, Context, OptionExt},
+    Result,
+};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+    RngCore,
+};
+use scopeguard::{guard, ScopeGuard};
+use tempfile::NamedTempFile;
+use tracing::{debug, error, info, warn};
+
+use super::{
+    activity::{Activity, ActivityEvents},
+    agent::AgentClient,
+    api::{ActionType, ApiClient},
+    config::{
+        logger_config, metrics_config, BalloonUpdateConfig, BlockDeviceUpdateConfig,
+        MachineConfigurator, NetworkInterfaceUpdateConfig, RateLimiterConfig, RateLimits,
+        METRICS_FILE_NAME, NETWORK_INTERFACE_ID, VSOCK_SOCKET_NAME,
+    },
+    metadata::Metadata,
+    metrics::{MetricsReader, VmMetrics},
+    snapshot::Snapshot,
+};
+use crate::{
+    host::{self, Arch},
+    slots::SlotLease,
+};
+
+/// Prefix of the status messages the guest prints on the serial console.
+const CONSOLE_MESSAGE_PREFIX: &str = "codepot: ";
+
+/// Status messages printed by the guest, collected from the console output.
+#[derive(Debug, Default)]
+struct ConsoleMessages {
+    messages: Mutex<HashSet<String>>,
+    changed: Condvar,
+}
+
+/// A running firecracker microVM.
+///
+/// Every VM gets its own runtime directory (holding e.g. the API socket and the drives) which is removed together with
+/// the VM. The drop implementation shuts down the firecracker process if it is still running and reaps it before
+/// returning the network slot of the VM, so holding a `Machine` is equivalent to holding the VM itself.
+#[derive(Debug)]
+pub struct Machine {
+    id: String,
+    child: Child,
+    dir: PathBuf,
+    console: Arc<ConsoleMessages>,
+    /// Config file firecracker was started with, kept alive for as long as the VM runs.
+    _config_file: Option<NamedTempFile>,
+    slot: SlotLease,
+    activity: Activity,
+    events: Arc<ActivityEvents>,
+}
+
+impl Machine {
+    pub(super) const API_SOCKET_NAME: &str = "firecracker.socket";
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+    const API_TIMEOUT: Duration = Duration::from_secs(5);
+    const METADATA_FILE_NAME: &str = "metadata.json";
+    /// How long the agent of a restored VM may take to answer.
+    const AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Size of the seed for the random number generator of a restored guest.
+    const SEED_LEN: usize = 64;
+    /// Printed by the guest once it finished booting.
+    pub const READY_MESSAGE: &str = "codepot: ready";
+
+    /// Start a new firecracker process from the given configuration, which has to use the network interface of the
+    /// given slot, publishing `metadata` to the guest. The runtime directory of the VM is created inside of
+    /// `vms_path`, it also holds the clones of the writable drives of the VM.
+    pub fn spawn(
+        firecracker_path: &Path,
+        vms_path: &Path,
+        mut configurator: MachineConfigurator,
+        mut metadata: Metadata,
+        slot: SlotLease,
+    ) -> Result<Self> {
+        let (id, dir) = create_vm_dir(vms_path)?;
+
+        // Every VM works on its own copy of the images, so that VMs cannot interfere with each other and every VM
+        // starts from a clean state.
+        configurator.clone_drives(&dir)?;
+        let config_file = configurator.store()?;
+
+        // The metadata has to be in place before the guest boots, so it is passed on the command line instead of
+        // through the API.
+        metadata.session_id.clone_from(&id);
+        let metadata_path = dir.join(Self::METADATA_FILE_NAME);
+        std::fs::write(&metadata_path, serde_json::to_string(&metadata)?)
+            .context("Could not write metadata")?;
+
+        Self::start(
+            firecracker_path,
+            id,
+            dir,
+            Some((config_file, metadata_path)),
+            slot,
+        )
+    }
+
+    /// Start a new firecracker process by restoring the given snapshot. As the snapshotted guest waits to be restored
+    /// before bringing up networking, it configures itself from `metadata`, which has to use the given slot. The rate
+    /// limits of the snapshotted VM are replaced by `rate_limits`, except for the one of the entropy device, which
+    /// firecracker cannot update. The random number generator of the guest is reseeded, as it would otherwise continue
+    /// like the one of every other VM restored from the snapshot.
+    pub fn restore(
+        firecracker_path: &Path,
+        vms_path: &Path,
+        snapshot: &Snapshot,
+        mut metadata: Metadata,
+        rate_limits: &RateLimits,
+        slot: SlotLease,
+    ) -> Result<Self> {
+        let (id, dir) = create_vm_dir(vms_path)?;
+        snapshot.clone_drives(&dir)?;
+
+        let mut machine = Self::start(firecracker_path, id, dir, None, slot)?;
+        let api = machine.api();
+        api.wait_until_ready(Self::API_TIMEOUT)?;
+        // Neither the log nor the metrics are part of the snapshot, and they can only be configured before loading it.
+        api.put_logger(&logger_config())?;
+        api.put_metrics(&metrics_config())?;
+        api.load_snapshot(&snapshot.load_params(&machine.slot.interface().if_name))
+            .with_context(|| format!("Could not restore VM {}", machine.id))?;
+
+        for drive_id in snapshot.drive_ids()? {
+            api.patch_drive(&BlockDeviceUpdateConfig {
+                drive_id,
+                path_on_host: None,
+                rate_limiter: Some(RateLimiterConfig::replacing(rate_limits.drive)),
+            })?;
+        }
+        api.patch_network_interface(&NetworkInterfaceUpdateConfig {
+            iface_id: NETWORK_INTERFACE_ID.to_owned(),
+            rx_rate_limiter: Some(RateLimiterConfig::replacing(rate_limits.net_rx)),
+            tx_rate_limiter: Some(RateLimiterConfig::replacing(rate_limits.net_tx)),
+        })?;
+
+        // The contents of the MMDS are not part of the snapshot.
+        metadata.session_id.clone_from(&machine.id);
+        api.put_mmds(&metadata)
+            .context("Could not publish metadata of restored VM")?;
+
+        // Every guest restored from the snapshot continues with the same state of its random number generator, so it
+        // is reseeded while the guest still waits to be restored, before it generates anything like keys.
+        let mut seed = [0; Self::SEED_LEN];
+        OsRng.fill_bytes(&mut seed);
+        let agent = machine.agent();
+        agent
+            .wait_until_ready(Self::AGENT_TIMEOUT)
+            .and_then(|()| agent.reseed(&seed))
+            .with_context(|| format!("Could not reseed restored VM {}", machine.id))?;
+
+        machine
+            .write_console(Snapshot::RESTORED_MESSAGE)
+            .context("Could not resume restored VM")?;
+        info!("Restored VM {} from snapshot", machine.id);
+
+        Ok(machine)
+    }
+
+    /// Start firecracker inside of the VM directory, optionally with a config and a metadata file.
+    fn start(
+        firecracker_path: &Path,
+        id: String,
+        dir: VmDirGuard,
+        config: Option<(NamedTempFile, PathBuf)>,
+        slot: SlotLease,
+    ) -> Result<Self> {
+        debug!("Starting firecracker for VM {id}");
+        // Firecracker does not create the metrics file itself.
+        std::fs::File::create(dir.join(METRICS_FILE_NAME))
+            .context("Could not create metrics file")?;
+        let mut command = Command::new(firecracker_path);
+        command
+            .current_dir(&*dir)
+            .arg("--api-sock")
+            .arg(dir.join(Self::API_SOCKET_NAME))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let config_file = config.map(|(config_file, metadata_path)| {
+            command
+                .arg("--config-file")
+                .arg(config_file.path())
+                .arg("--metadata")
+                .arg(metadata_path);
+            config_file
+        });
+        let mut child = command.spawn().with_context(|| {
+            format!(
+                "Could not start firecracker at {}",
+                firecracker_path.display()
+            )
+        })?;
+
+        // The serial console ends up on stdout, firecracker's own log on stderr.
+        let console = Arc::new(ConsoleMessages::default());
+        if let Some(stdout) = child.stdout.take() {
+            forward_output(&id, "console", stdout, Some(console.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_output(&id, "firecracker", stderr, None);
+        }
+
+        info!("Started VM {id}");
+
+        Ok(Self {
+            id,
+            child,
+            dir: ScopeGuard::into_inner(dir),
+            console,
+            _config_file: config_file,
+            slot,
+            activity: Activity::new(),
+            events: Arc::new(ActivityEvents::default()),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Network slot used by this VM.
+    pub fn slot(&self) -> &SlotLease {
+        &self.slot
+    }
+
+    /// Path of the runtime directory of this VM.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Client for the firecracker API of this VM.
+    pub fn api(&self) -> ApiClient {
+        ApiClient::new(self.dir.join(Self::API_SOCKET_NAME))
+    }
+
+    /// Client for the agent running inside of this VM.
+    pub fn agent(&self) -> AgentClient {
+        AgentClient::new(self.dir.join(VSOCK_SOCKET_NAME), self.events.clone())
+    }
+
+    /// Block until the guest printed the given status message on the serial console.
+    pub fn wait_for_console(&self, message: &str, timeout: Duration) -> Result<()> {
+        let messages = self.console.messages.lock().unwrap();
+        let (_messages, result) = self
+            .console
+            .changed
+            .wait_timeout_while(messages, timeout, |messages| !messages.contains(message))
+            .unwrap();
+        if result.timed_out() {
+            bail!(
+                "VM {} did not print \"{message}\" within {timeout:?}",
+                self.id
+            );
+        }
+        Ok(())
+    }
+
+    /// Send a line of input to the serial console of the guest.
+    pub fn write_console(&mut self, line: &str) -> Result<()> {
+        let stdin = self
+            .child
+            .stdin
+            .as_mut()
+            .ok_or_eyre("Console input already closed")?;
+        stdin.write_all(format!("{line}\n").as_bytes())?;
+        stdin.flush()?;
+        Ok(())
+    }
+
+    /// Show a message to all users logged into the guest.
+    pub fn notify_users(&mut self, message: &str) -> Result<()> {
+        // Commands are separated by newlines.
+        let message = message.replace('\n', " ");
+        self.write_console(&format!("wall {message}"))
+            .with_context(|| format!("Could not send message to VM {}", self.id))
+    }
+
+    /// Handle on the balloon of this VM, which can be used without holding on to the VM.
+    pub fn balloon(&self) -> Balloon {
+        Balloon {
+            id: self.id.clone(),
+            api: self.api(),
+        }
+    }
+
+    /// Deflate the balloon of the VM, giving the guest all of its memory back.
+    pub fn deflate_balloon(&self) -> Result<()> {
+        self.balloon().deflate()
+    }
+
+    /// Totals of the metrics of the VM, after asking firecracker to write its current ones.
+    pub fn metrics(&self) -> Result<VmMetrics> {
+        MetricsReader::new(&self.dir).update().copied()
+    }
+
+    /// Count the idle time of the VM from now on, ignoring the time it was not in use yet.
+    pub fn reset_idle_time(&mut self) {
+        self.activity.reset();
+    }
+
+    /// Check the activity of the VM and return for how long it has been idle. Has to be called regularly to detect
+    /// activity.
+    pub fn idle_time(&mut self) -> Result<Duration> {
+        self.activity
+            .update(
+                self.child.id(),
+                &self.slot.interface().if_name,
+                &self.events,
+            )
+            .with_context(|| format!("Could not check activity of VM {}", self.id))
+    }
+
+    /// Return the exit status of firecracker if the VM already exited.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.child
+            .try_wait()
+            .with_context(|| format!("Could not get status of VM {}", self.id))
+    }
+
+    /// Forcefully stop the VM.
+    pub fn kill(&mut self) -> Result<()> {
+        if self.try_wait()?.is_none() {
+            debug!("Killing VM {}", self.id);
+            self.child
+                .kill()
+                .with_context(|| format!("Could not kill VM {}", self.id))?;
+        }
+        Ok(())
+    }
+
+    /// Ask the guest to shut down cleanly, killing the VM if it did not exit within `timeout`.
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<()> {
+        if self.try_wait()?.is_some() {
+            return Ok(());
+        }
+
+        debug!("Shutting down VM {}", self.id);
+        // Firecracker only emulates the keyboard controller for Ctrl+Alt+Del on x86_64, so aarch64 guests are asked to
+        // reboot over the console, which makes firecracker exit as well.
+        let result = match host::ARCH {
+            Arch::X86_64 => self.api().action(ActionType::SendCtrlAltDel),
+            Arch::Aarch64 => self.write_console("reboot"),
+        };
+        if let Err(err) = result {
+            warn!("Could not shut down VM {} cleanly: {err}", self.id);
+            return self.kill();
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if self.try_wait()?.is_some() {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(100));
+        }
+
+        warn!(
+            "VM {} did not shut down within {timeout:?}, killing it",
+            self.id
+        );
+        self.kill()
+    }
+}
+
+impl Drop for Machine {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown(Self::SHUTDOWN_TIMEOUT) {
+            error!("{err}");
+        }
+        // Reap the process so that no zombies are left behind.
+        if let Err(err) = self.child.wait() {
+            error!("Could not reap VM {}: {err}", self.id);
+        }
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            error!(
+                "Could not remove VM directory {}: {err}",
+                self.dir.display()
+            );
+        }
+    }
+}
+
+/// The balloon device of a VM, through which memory the guest does not use is handed back to the host.
+#[derive(Debug, Clone)]
+pub struct Balloon {
+    id: String,
+    api: ApiClient,
+}
+
+impl Balloon {
+    /// Memory left to the guest when inflating the balloon, so that it can pick up work without waiting for the
+    /// balloon to deflate.
+    const RESERVE_MIB: u32 = 64;
+    /// Smallest change of the balloon size worth the effort of the guest.
+    const STEP_MIB: u32 = 16;
+
+    /// Inflate the balloon to take the memory the guest does not use away from it, so that the host can give it to
+    /// other VMs. Returns the new target size of the balloon in MiB.
+    ///
+    /// The guest reports its memory usage only periodically, so this has to be called repeatedly to take away all of
+    /// the unused memory. The guest deflates the balloon by itself when it runs out of memory.
+    pub fn inflate(&self) -> Result<u32> {
+        const MIB: u64 = 1024 * 1024;
+        let stats = self
+            .api
+            .balloon_statistics()
+            .with_context(|| format!("Could not get memory statistics of VM {}", self.id))?;
+        let Some(available) = stats.available_memory else {
+            // The guest did not report its memory usage yet.
+            return Ok(stats.target_mib);
+        };
+        // Memory in the balloon does not count as available to the guest.
+        let unused_mib = u32::try_from(available / MIB).unwrap_or(u32::MAX);
+        let target_mib = stats
+            .actual_mib
+            .saturating_add(unused_mib.saturating_sub(Self::RESERVE_MIB));
+        if target_mib < stats.target_mib + Self::STEP_MIB {
+            return Ok(stats.target_mib);
+        }
+
+        debug!("Inflating balloon of VM {} to {target_mib} MiB", self.id);
+        self.api
+            .patch_balloon(&BalloonUpdateConfig {
+                amount_mib: target_mib,
+            })
+            .with_context(|| format!("Could not inflate balloon of VM {}", self.id))?;
+        Ok(target_mib)
+    }
+
+    /// Memory the balloon actually took back from the guest, in MiB. It lags behind the target size, as the guest
+    /// hands over its memory gradually.
+    pub fn reclaimed_mib(&self) -> Result<u32> {
+        let stats = self
+            .api
+            .balloon_statistics()
+            .with_context(|| format!("Could not get memory statistics of VM {}", self.id))?;
+        Ok(stats.actual_mib)
+    }
+
+    /// Deflate the balloon, giving the guest all of its memory back.
+    pub fn deflate(&self) -> Result<()> {
+        debug!("Deflating balloon of VM {}", self.id);
+        self.api
+            .patch_balloon(&BalloonUpdateConfig { amount_mib: 0 })
+            .with_context(|| format!("Could not deflate balloon of VM {}", self.id))
+    }
+}
+
+/// Runtime directory of a VM that is removed again if the VM could not be started.
+type VmDirGuard = ScopeGuard<PathBuf, fn(PathBuf)>;
+
+/// Create a new runtime directory for a VM with a random id.
+fn create_vm_dir(vms_path: &Path) -> Result<(String, VmDirGuard)> {
+    let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+    let dir = vms_path.join(&id);
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Could not create VM directory {}", dir.display()))?;
+    let dir = guard(
+        dir,
+        (|dir| {
+            debug!("Removing VM directory because VM could not be started");
+            let _ = std::fs::remove_dir_all(dir);
+        }) as fn(PathBuf),
+    );
+    Ok((id, dir))
+}
+
+/// Log the output of firecracker line by line from a background thread. If `console` is given, status messages of the
+/// guest are collected. Console output does not count as activity, as the guest also prints to it on its own (e.g.
+/// kernel messages or the idle warnings written by the host).
+fn forward_output(
+    id: &str,
+    stream: &'static str,
+    output: impl Read + Send + 'static,
+    console: Option<Arc<ConsoleMessages>>,
+) {
+    let id = id.to_owned();
+    std::thread::spawn(move || {
+        for line in BufReader::new(output).split(b'\n') {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            debug!(vm = id, stream, "{line}");
+
+            if let Some(console) = &console {
+                if line.starts_with(CONSOLE_MESSAGE_PREFIX) {
+                    console.messages.lock().unwrap().insert(line.to_owned());
+                    console.changed.notify_all();
+                }
+            }
+        }
+    });
+}
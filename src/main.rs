@@ -1,29 +1,60 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use argh::FromArgs;
 use color_eyre::{
-    eyre::{ensure, Context},
+    eyre::{ensure, Context, OptionExt},
     Result,
 };
 
-use config::Config;
-use init::{init_images, init_networking};
+use config::{Config, Profile};
+use host::Arch;
+use init::{deinit_networking, init_images, init_networking, InitrdImages};
 use ipnet::Ipv4Net;
-use machine::config::MachineConfigurator;
+use machine::{
+    config::{MachineConfigurator, RootFs},
+    metadata::{Metadata, NetworkMetadata},
+    Machine, Snapshot,
+};
+use pool::{Launcher, Pool};
 use rand::distributions::{Alphanumeric, DistString};
-use tracing::warn;
+use reaper::SessionEnd;
+use slots::{SlotAllocator, SlotLease};
+use tracing::{error, info, warn};
+use util::{remove_dir_if_exists, remove_file_if_exists};
 
 mod config;
+mod diagnostics;
+mod emit;
+mod execution;
+mod host;
 mod init;
+mod languages;
 mod machine;
+mod metrics;
+mod playground;
+mod pool;
+mod reaper;
+mod server;
+mod slots;
 mod util;
 
 fn default_vm_assets_path() -> PathBuf {
     Path::new("vm/").to_owned()
 }
 
+/// The toolchains take up most of the space.
 fn default_rootfs_size_mb() -> u64 {
-    800
+    4096
+}
+
+fn default_home_size_mb() -> u64 {
+    256
 }
 
 fn default_max_parallel_vm_count() -> usize {
@@ -42,6 +73,34 @@ fn default_guest_password() -> String {
     Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
 }
 
+fn default_warm_pool_size() -> usize {
+    2
+}
+
+fn default_sessions() -> usize {
+    1
+}
+
+fn default_metrics_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9464))
+}
+
+fn default_api_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8080))
+}
+
+/// The agent is built together with codepot, so look for it next to the codepot binary.
+fn default_agent_path() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join("codepot-agent")))
+        .unwrap_or_else(|| Path::new("codepot-agent").to_owned())
+}
+
+fn default_firecracker_path() -> PathBuf {
+    Path::new("firecracker").to_owned()
+}
+
 #[derive(FromArgs)]
 /// Reach new heights.
 struct Codepot {
@@ -57,6 +116,8 @@ struct Codepot {
 #[argh(subcommand)]
 enum Subcommand {
     Init(Init),
+    Deinit(Deinit),
+    Snapshot(CreateSnapshot),
     Run(Run),
 }
 
@@ -68,6 +129,23 @@ struct Init {
     #[argh(option, default = "default_rootfs_size_mb()")]
     rootfs_size: u64,
 
+    /// also build an initrd to boot the VMs from, with the home directory on a separate image.
+    #[argh(switch)]
+    initrd: bool,
+
+    /// size of the home directory image when booting from an initrd, in MB.
+    #[argh(option, default = "default_home_size_mb()")]
+    home_size: u64,
+
+    /// path to the statically linked guest agent to install into the images.
+    #[argh(option, default = "default_agent_path()")]
+    agent: PathBuf,
+
+    /// option of the toolchain of a language installed into the images, as `<language>.<option>=<value>`, e.g.
+    /// `rust.toolchain=nightly`, can be repeated.
+    #[argh(option)]
+    toolchain_option: Vec<String>,
+
     /// maximum number of VMs allowed to coexist at the same time.
     #[argh(option, default = "default_max_parallel_vm_count()")]
     max_parallel_vm_count: usize,
@@ -87,12 +165,202 @@ struct Init {
     /// password for the user account inside the guest.
     #[argh(option, default = "default_guest_password()")]
     password: String,
+
+    /// seconds without activity after which a VM is torn down, overriding the idle timeout of every profile.
+    #[argh(option)]
+    idle_timeout: Option<u64>,
+
+    /// public SSH key authorized to log into the guest user, can be given multiple times.
+    #[argh(option)]
+    ssh_key: Vec<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Tear down networking and optionally remove images and config.
+#[argh(subcommand, name = "deinit")]
+struct Deinit {
+    /// also remove the rootfs image, the initrd, the home directory image and the snapshot.
+    #[argh(switch)]
+    rootfs: bool,
+
+    /// also remove the kernel image.
+    #[argh(switch)]
+    kernel: bool,
+
+    /// also remove the config and the slot locks, so that the next `codepot init` sets up networking again.
+    #[argh(switch)]
+    config: bool,
+
+    /// remove images, config and the directories of VMs left behind, in addition to `--rootfs --kernel --config`.
+    #[argh(switch)]
+    all: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Boot a VM and store a snapshot of it, from which `codepot run --snapshot` starts VMs.
+#[argh(subcommand, name = "snapshot")]
+struct CreateSnapshot {
+    /// path to the firecracker binary.
+    #[argh(option, default = "default_firecracker_path()")]
+    firecracker: PathBuf,
+
+    /// boot the VM from the initrd built by `codepot init --initrd` instead of the rootfs image.
+    #[argh(switch)]
+    initrd: bool,
+
+    /// machine profile of the VM, the default profile of the config if not given. Every profile needs its own
+    /// snapshot.
+    #[argh(option)]
+    profile: Option<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Start the server.
 #[argh(subcommand, name = "run")]
-struct Run {}
+struct Run {
+    /// path to the firecracker binary.
+    #[argh(option, default = "default_firecracker_path()")]
+    firecracker: PathBuf,
+
+    /// boot the VMs from the initrd built by `codepot init --initrd` instead of the rootfs image.
+    #[argh(switch)]
+    initrd: bool,
+
+    /// start the VMs from the snapshots created by `codepot snapshot` instead of booting them. VMs with profiles
+    /// without a snapshot are still booted.
+    #[argh(switch)]
+    snapshot: bool,
+
+    /// machine profile of the sessions and the warm pool, the default profile of the config if not given.
+    #[argh(option)]
+    profile: Option<String>,
+
+    /// number of booted VMs to keep ready for new sessions.
+    #[argh(option, default = "default_warm_pool_size()")]
+    warm_pool_size: usize,
+
+    /// number of VMs handed out to interactive sessions at the same time, each of them is replaced by a fresh VM once
+    /// its session ended.
+    #[argh(option, default = "default_sessions()")]
+    sessions: usize,
+
+    /// address to serve the metrics of the VMs and the host at, in the Prometheus text format.
+    #[argh(option, default = "default_metrics_address()")]
+    metrics_address: SocketAddr,
+
+    /// address to serve the HTTP API for executing code at.
+    #[argh(option, default = "default_api_address()")]
+    api_address: SocketAddr,
+}
+
+/// How long the VM for a snapshot may take to boot.
+const SNAPSHOT_BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait before handing out the next VM if getting one failed.
+const SESSION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Check that the images for the chosen boot method exist.
+fn root_fs(
+    initrd: bool,
+    rootfs_image_path: &Path,
+    initrd_image_path: &Path,
+    home_image_path: &Path,
+) -> Result<RootFs> {
+    if !initrd {
+        ensure!(rootfs_image_path.try_exists()?, "Not inited yet, please run `codepot init` to create necessary images and setup networking");
+        return Ok(RootFs::Image(rootfs_image_path.to_owned()));
+    }
+    for p in &[initrd_image_path, home_image_path] {
+        ensure!(
+            p.try_exists()?,
+            "Initrd not built yet, please run `codepot init --initrd`"
+        );
+    }
+    Ok(RootFs::Initrd {
+        initrd_path: initrd_image_path.to_owned(),
+        home_image_path: home_image_path.to_owned(),
+    })
+}
+
+/// Check that `codepot init` ran for the architecture of the host and read the config.
+fn read_config(kernel_image_path: &Path, config_path: &Path) -> Result<Config> {
+    for p in &[kernel_image_path, config_path] {
+        ensure!(p.try_exists()?, "Not inited yet, please run `codepot init` to create necessary images and setup networking");
+    }
+    let config = Config::read(config_path)
+        .with_context(|| format!("Could not read config from {}", config_path.display()))?;
+    config.check_arch()?;
+    Ok(config)
+}
+
+/// Configure a VM with the given profile using the network interface of the given slot.
+fn machine_configurator(
+    arch: Arch,
+    kernel_image_path: &Path,
+    root_fs: RootFs,
+    profile: &Profile,
+    slot: &SlotLease,
+) -> MachineConfigurator {
+    let iface = slot.interface();
+    let mut configurator = MachineConfigurator::new(
+        arch,
+        kernel_image_path,
+        root_fs,
+        profile.machine_config(),
+        &iface.if_name,
+        &iface.mac_address,
+    );
+    configurator
+        .set_io_engines(|drive_id| profile.io_engine(drive_id))
+        .set_rate_limits(&profile.rate_limits);
+    if let Some(template) = &profile.cpu_template {
+        configurator.set_cpu_template(template);
+    }
+    configurator
+}
+
+/// Metadata for a VM with the given profile using the network interface of the given slot.
+fn metadata(config: &Config, profile: &Profile, slot: &SlotLease) -> Metadata {
+    Metadata {
+        session_id: String::new(),
+        ssh_keys: config.ssh_keys.clone(),
+        network: NetworkMetadata::new(slot.interface(), config.host_address.addr()),
+        idle_timeout_secs: profile.idle_timeout_secs,
+        snapshot: false,
+    }
+}
+
+/// Hand out VMs with the named profile to interactive sessions one after the other, each until it exits or was idle
+/// for `idle_timeout`. A failing VM only ends its own session, the API and the metrics keep being served.
+fn serve_sessions(pool: &Pool, profile_name: &str, idle_timeout: Duration) {
+    loop {
+        let mut machine = match pool.acquire(profile_name) {
+            Ok(machine) => machine,
+            Err(err) => {
+                error!("Could not get a VM for the next session: {err:?}");
+                std::thread::sleep(SESSION_RETRY_INTERVAL);
+                continue;
+            }
+        };
+        info!(
+            "VM {} is reachable at {}",
+            machine.id(),
+            machine.slot().interface().ip_address.addr()
+        );
+
+        // A guest reboot makes firecracker exit successfully, in which case the session ends and the VM is destroyed,
+        // just like after it was idle for too long.
+        match reaper::supervise(&mut machine, idle_timeout) {
+            Ok(SessionEnd::Exited(status)) if !status.success() => {
+                error!("VM {} exited unexpectedly with {status}", machine.id());
+            }
+            Ok(_) => {}
+            Err(err) => error!("Could not supervise VM {}: {err:?}", machine.id()),
+        }
+        drop(machine);
+        info!("Session ended, handing out the next VM");
+    }
+}
 
 fn main() -> Result<()> {
     color_eyre::install()?;
@@ -105,24 +373,46 @@ fn main() -> Result<()> {
         "VM assets path at {} does not exist, please create it and run `codepot init`",
         args.vm_assets.display(),
     );
-    let kernel_image_path = args.vm_assets.join("kernel.img");
-    let rootfs_image_path = args.vm_assets.join("rootfs.ext4");
-    let config_path = args.vm_assets.join("config.json");
+    // Firecracker runs inside of the directory of the VM, so all paths passed to it have to be absolute.
+    let vm_assets = std::path::absolute(&args.vm_assets)?;
+    let kernel_image_path = vm_assets.join("kernel.img");
+    let rootfs_image_path = vm_assets.join("rootfs.ext4");
+    let initrd_image_path = vm_assets.join("initrd.cpio");
+    let home_image_path = vm_assets.join("home.ext4");
+    let snapshots_path = vm_assets.join("snapshots");
+    let config_path = vm_assets.join("config.json");
+    let vms_path = vm_assets.join("vms");
+    let slots_path = vm_assets.join("slots");
 
     match args.subcommand {
         Subcommand::Init(Init {
             rootfs_size,
+            initrd,
+            home_size,
+            agent,
+            toolchain_option,
             max_parallel_vm_count,
             host_interface,
             net,
             username,
             password,
+            idle_timeout,
+            ssh_key,
         }) => {
             let rootfs_size = rootfs_size * 1024 * 1024;
-            init_images(
+            let initrd = initrd.then_some(InitrdImages {
+                initrd_image_path: &initrd_image_path,
+                home_image_path: &home_image_path,
+                home_size: home_size * 1024 * 1024,
+            });
+            let toolchains = languages::toolchains(&toolchain_option)?;
+            let versions = init_images(
                 &kernel_image_path,
                 &rootfs_image_path,
                 rootfs_size,
+                initrd,
+                &agent,
+                &toolchains,
                 username,
                 password,
             )
@@ -132,39 +422,218 @@ fn main() -> Result<()> {
                 let (interfaces, host_address) =
                     init_networking(max_parallel_vm_count, &host_interface, net)
                         .context("Could not setup networking")?;
-                Config::new(
+                let mut config = Config::new(
                     max_parallel_vm_count,
                     net,
                     host_interface,
                     host_address,
                     interfaces,
-                )
-                .write(&config_path)
-                .with_context(|| format!("Could not write config to {}", config_path.display()))?;
+                    idle_timeout,
+                    ssh_key,
+                );
+                // The toolchains and their versions are only known if the images were built just now.
+                match versions {
+                    Some(versions) => {
+                        config.toolchains = toolchains;
+                        config.language_versions = versions;
+                    }
+                    None => warn!(
+                        "Images were built before, not recording their toolchains and versions in the config"
+                    ),
+                }
+                config.write(&config_path).with_context(|| {
+                    format!("Could not write config to {}", config_path.display())
+                })?;
             } else {
-                warn!("Config already present at {}, skipping network setup (note that this could lead to inconsistencies, best run `codepot deinit` and `codepot init` to get consistent network and image configuration)", config_path.display());
+                warn!("Config already present at {}, skipping network setup (note that this could lead to inconsistencies, best run `codepot deinit --all` and `codepot init` to get consistent network and image configuration)", config_path.display());
             }
         }
-        Subcommand::Run(Run {}) => {
-            for p in &[&kernel_image_path, &rootfs_image_path, &config_path] {
-                ensure!(p.try_exists()?, "Not inited yet, please run `codepot init` to create necessary images and setup networking");
+        Subcommand::Deinit(Deinit {
+            rootfs,
+            kernel,
+            config,
+            all,
+        }) => {
+            let config_present = config_path.try_exists()?;
+            let parsed_config = if config_present {
+                Some(Config::read(&config_path).with_context(|| {
+                    format!("Could not read config from {}", config_path.display())
+                })?)
+            } else {
+                None
+            };
+            deinit_networking(parsed_config.as_ref()).context("Could not tear down networking")?;
+
+            for (remove, path) in [
+                (rootfs, &rootfs_image_path),
+                (rootfs, &initrd_image_path),
+                (rootfs, &home_image_path),
+                (kernel, &kernel_image_path),
+                (config, &config_path),
+            ] {
+                if remove || all {
+                    remove_file_if_exists(path)?;
+                }
             }
-            let config = Config::read(&config_path)
-                .with_context(|| format!("Could not read config from {}", config_path.display()))?;
-
-            let iface = &config.interfaces[0];
-            let configurator = MachineConfigurator::new(
-                kernel_image_path,
-                rootfs_image_path,
-                2,
-                512,
-                config.host_address.addr(),
-                &iface.if_name,
-                &iface.mac_address,
-                iface.ip_address,
-                "foo",
+            if rootfs || all {
+                remove_dir_if_exists(&snapshots_path)?;
+            }
+            // The slot locks are named after the interfaces in the config.
+            if config || all {
+                remove_dir_if_exists(&slots_path)?;
+            }
+            if all {
+                remove_dir_if_exists(&vms_path)?;
+            }
+        }
+        Subcommand::Snapshot(CreateSnapshot {
+            firecracker,
+            initrd,
+            profile,
+        }) => {
+            let root_fs = root_fs(
+                initrd,
+                &rootfs_image_path,
+                &initrd_image_path,
+                &home_image_path,
+            )?;
+            let mut config = read_config(&kernel_image_path, &config_path)?;
+            config.fall_back_to_sync_io();
+            let (profile_name, profile) = config.profile(profile.as_deref())?;
+            profile
+                .validate(&root_fs)
+                .with_context(|| format!("Invalid profile {profile_name}"))?;
+            let slots = SlotAllocator::new(&slots_path, config.interfaces.clone())?;
+
+            let slot = slots
+                .lease()?
+                .ok_or_eyre("All VM slots are in use by other codepot processes")?;
+            let configurator =
+                machine_configurator(config.arch, &kernel_image_path, root_fs, profile, &slot);
+            let metadata = Metadata {
+                snapshot: true,
+                ..metadata(&config, profile, &slot)
+            };
+            let mut machine = Machine::spawn(&firecracker, &vms_path, configurator, metadata, slot)
+                .context("Could not start VM")?;
+            machine
+                .wait_for_console(Snapshot::READY_MESSAGE, SNAPSHOT_BOOT_TIMEOUT)
+                .context("VM did not become ready for the snapshot")?;
+            std::fs::create_dir_all(&snapshots_path)?;
+            Snapshot::create(&machine, snapshots_path.join(profile_name))
+                .context("Could not create snapshot")?;
+            // The VM is paused, so it cannot be shut down cleanly.
+            machine.kill()?;
+        }
+        Subcommand::Run(Run {
+            firecracker,
+            initrd,
+            snapshot,
+            warm_pool_size,
+            sessions,
+            metrics_address,
+            api_address,
+            profile,
+        }) => {
+            let root_fs = root_fs(
+                initrd,
+                &rootfs_image_path,
+                &initrd_image_path,
+                &home_image_path,
+            )?;
+            let mut config = read_config(&kernel_image_path, &config_path)?;
+            config.fall_back_to_sync_io();
+            config.retain_valid_profiles(profile.as_deref(), &root_fs)?;
+            ensure!(
+                warm_pool_size <= config.max_parallel_vm_count,
+                "Warm pool size exceeds the maximum number of parallel VMs"
+            );
+            ensure!(
+                (1..=config.max_parallel_vm_count).contains(&sessions),
+                "The number of sessions has to be between 1 and the maximum number of parallel VMs"
             );
-            configurator.store()?;
+            let (profile_name, profile) = config.profile(profile.as_deref())?;
+            let profile_name = profile_name.to_owned();
+            let idle_timeout = Duration::from_secs(profile.idle_timeout_secs);
+
+            let mut snapshots = HashMap::new();
+            if snapshot {
+                for name in config.profiles.keys() {
+                    let path = snapshots_path.join(name);
+                    if path.try_exists()? {
+                        snapshots.insert(name.clone(), Snapshot::open(&path)?);
+                    }
+                }
+                ensure!(
+                    !snapshots.is_empty(),
+                    "No snapshots at {}, please run `codepot snapshot`",
+                    snapshots_path.display()
+                );
+            }
+
+            let slots = SlotAllocator::new(&slots_path, config.interfaces.clone())?;
+            let profiles = config.profiles.clone();
+            let toolchains = config.toolchains.clone();
+            let language_versions = config.language_versions.clone();
+            // There are never more VMs for executions than slots.
+            let max_parallel_vm_count = config.max_parallel_vm_count;
+            let metrics_vms_path = vms_path.clone();
+            let launch: Launcher = Box::new(move |slot, profile_name| {
+                let (_, profile) = config.profile(Some(profile_name))?;
+                let metadata = metadata(&config, profile, &slot);
+                match snapshots.get(profile_name) {
+                    Some(snapshot) => Machine::restore(
+                        &firecracker,
+                        &vms_path,
+                        snapshot,
+                        metadata,
+                        &profile.rate_limits,
+                        slot,
+                    ),
+                    None => {
+                        let configurator = machine_configurator(
+                            config.arch,
+                            &kernel_image_path,
+                            root_fs.clone(),
+                            profile,
+                            &slot,
+                        );
+                        Machine::spawn(&firecracker, &vms_path, configurator, metadata, slot)
+                    }
+                }
+                .context("Could not start VM")
+            });
+            let pool = Arc::new(Pool::new(
+                slots,
+                warm_pool_size,
+                profile_name.clone(),
+                &profiles,
+                launch,
+            ));
+            metrics::serve(metrics_address, &metrics_vms_path, pool.monitor())?;
+            server::serve(
+                api_address,
+                pool.clone(),
+                max_parallel_vm_count,
+                profile_name.clone(),
+                profiles.into_keys().collect(),
+                toolchains,
+                language_versions,
+            )?;
+
+            // Every session is served by its own thread, the pool bounds how many VMs run at once.
+            let sessions: Vec<_> = (0..sessions)
+                .map(|_| {
+                    let pool = pool.clone();
+                    let profile_name = profile_name.clone();
+                    std::thread::spawn(move || serve_sessions(&pool, &profile_name, idle_timeout))
+                })
+                .collect();
+            for session in sessions {
+                if session.join().is_err() {
+                    error!("Session thread panicked");
+                }
+            }
         }
     }
 
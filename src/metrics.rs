@@ -0,0 +1,242 @@
+//! Prometheus endpoint of `codepot run`, exposing the metrics of the VMs and of the host in the text format, see
+//! https://prometheus.io/docs/instrumenting/exposition_formats/.
+//!
+//! The VMs are found through their runtime directories, so the metrics of every VM are read when scraped, no matter
+//! whether it is idle in the pool or in use by a session. The pool collects the final metrics of every VM before it is
+//! torn down, so that the counters of VMs that exited between two scrapes are not lost.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use tiny_http::{Header, Method, Response, Server};
+use tracing::{error, info, warn};
+
+use crate::{
+    machine::metrics::{MetricsReader, VmMetrics, COUNTERS},
+    pool::PoolMonitor,
+};
+
+const PREFIX: &str = "codepot";
+
+/// Collects the metrics of all VMs in a directory.
+struct Exporter {
+    vms_path: PathBuf,
+    pool: PoolMonitor,
+    vms: HashMap<String, MetricsReader>,
+    /// Totals of the VMs that are gone, so that the aggregated counters never decrease.
+    finished: VmMetrics,
+    /// Final metrics reported by the pool for VMs whose directories might not be removed yet.
+    reported: HashMap<String, VmMetrics>,
+}
+
+/// Serve the metrics of the VMs in `vms_path` and of `pool` at `/metrics` from a background thread.
+pub fn serve(address: SocketAddr, vms_path: &Path, pool: PoolMonitor) -> Result<()> {
+    let server = Server::http(address)
+        .map_err(|err| eyre!(err))
+        .with_context(|| format!("Could not listen on {address}"))?;
+    info!("Serving metrics at http://{address}/metrics");
+
+    let mut exporter = Exporter {
+        vms_path: vms_path.to_owned(),
+        pool,
+        vms: HashMap::new(),
+        finished: VmMetrics::default(),
+        reported: HashMap::new(),
+    };
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") => match exporter.render() {
+                    Ok(metrics) => Response::from_string(metrics).with_header(
+                        "Content-Type: text/plain; version=0.0.4"
+                            .parse::<Header>()
+                            .unwrap(),
+                    ),
+                    Err(err) => {
+                        error!("Could not collect metrics: {err:?}");
+                        Response::from_string(err.to_string()).with_status_code(500)
+                    }
+                },
+                _ => Response::from_string("Not found").with_status_code(404),
+            };
+            if let Err(err) = request.respond(response) {
+                warn!("Could not send metrics: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Exporter {
+    /// Collect all metrics in the text format.
+    fn render(&mut self) -> Result<String> {
+        self.update_vms()?;
+        let mut out = String::new();
+
+        let stats = self.pool.stats();
+        let pool_labels = format!("{{profile=\"{}\"}}", stats.warm_profile);
+        let gauges = [
+            (
+                "slots_leased",
+                "Network slots leased to VMs by this process, not counting other codepot processes.",
+                "",
+                stats.slots_leased,
+            ),
+            (
+                "slots",
+                "Network slots in the config.",
+                "",
+                stats.slots_total,
+            ),
+            (
+                "pool_idle_vms",
+                "Booted VMs ready to be handed out.",
+                &pool_labels,
+                stats.idle,
+            ),
+            (
+                "pool_committed_memory_bytes",
+                "Memory of the VMs started by the pool that are not gone yet.",
+                "",
+                stats.committed_mib * 1024 * 1024,
+            ),
+            (
+                "pool_warm_size",
+                "Booted VMs the pool tries to keep ready.",
+                &pool_labels,
+                stats.warm_size,
+            ),
+        ];
+        for (name, help, labels, value) in gauges {
+            header(&mut out, name, help, "gauge");
+            writeln!(out, "{PREFIX}_{name}{labels} {value}")?;
+        }
+
+        header(
+            &mut out,
+            "boot_duration_seconds",
+            "Time until a VM was ready to be handed out.",
+            "summary",
+        );
+        writeln!(
+            out,
+            "{PREFIX}_boot_duration_seconds_sum {}",
+            stats.boots.total.as_secs_f64()
+        )?;
+        writeln!(
+            out,
+            "{PREFIX}_boot_duration_seconds_count {}",
+            stats.boots.count
+        )?;
+        if let Some(last) = stats.boots.last {
+            header(
+                &mut out,
+                "last_boot_duration_seconds",
+                "Time the most recently started VM took to become ready.",
+                "gauge",
+            );
+            writeln!(
+                out,
+                "{PREFIX}_last_boot_duration_seconds {}",
+                last.as_secs_f64()
+            )?;
+        }
+
+        let mut ids: Vec<_> = self.vms.keys().collect();
+        ids.sort();
+        let mut aggregate = self.finished;
+        for reader in self.vms.values() {
+            aggregate.add(reader.totals());
+        }
+        for (index, counter) in COUNTERS.iter().enumerate() {
+            let name = format!("vm_{}_total", counter.name);
+            header(&mut out, &name, counter.help, "counter");
+            for id in &ids {
+                let value = self.vms[*id].totals().get(index);
+                writeln!(out, "{PREFIX}_{name}{{vm=\"{id}\"}} {value}")?;
+            }
+
+            let name = format!("{}_total", counter.name);
+            let help = format!("{} Summed up over all VMs.", counter.help);
+            header(&mut out, &name, &help, "counter");
+            let value = aggregate.get(index);
+            writeln!(out, "{PREFIX}_{name} {value}")?;
+        }
+
+        Ok(out)
+    }
+
+    /// Pick up new VMs, read the metrics of all VMs and forget about the VMs that are gone.
+    fn update_vms(&mut self) -> Result<()> {
+        let mut ids = Vec::new();
+        let entries = match std::fs::read_dir(&self.vms_path) {
+            Ok(entries) => entries,
+            // Created when the first VM starts.
+            Err(err) if err.kind() == ErrorKind::NotFound => return self.forget_vms(&ids),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!(
+                        "Could not list VM directories in {}",
+                        self.vms_path.display()
+                    )
+                })
+            }
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                ids.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        for id in &ids {
+            let reader = self
+                .vms
+                .entry(id.clone())
+                .or_insert_with(|| MetricsReader::new(self.vms_path.join(id)));
+            // The VM might just have been created or removed.
+            if let Err(err) = reader.update() {
+                warn!("Could not read metrics of VM {id}: {err}");
+            }
+        }
+
+        self.forget_vms(&ids)
+    }
+
+    /// Forget about the VMs that are not in `ids` anymore, keeping their totals. The final metrics reported by the
+    /// pool replace what was read of a VM, as they include everything written after the last scrape.
+    fn forget_vms(&mut self, ids: &[String]) -> Result<()> {
+        self.reported.extend(self.pool.take_finished_metrics());
+        let finished = &mut self.finished;
+        let reported = &mut self.reported;
+        self.vms.retain(|id, reader| {
+            let alive = ids.contains(id);
+            if !alive && !reported.contains_key(id) {
+                finished.add(reader.totals());
+            }
+            alive
+        });
+        reported.retain(|id, metrics| {
+            let alive = ids.contains(id);
+            if !alive {
+                finished.add(metrics);
+            }
+            alive
+        });
+        Ok(())
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+}
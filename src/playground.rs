@@ -0,0 +1,468 @@
+//! The JSON protocol of the Rust playground (https://play.rust-lang.org), so that its clients can use codepot instead.
+//!
+//! Requests are translated into executions of a single Rust file. Unlike on the playground, there are no crates from
+//! crates.io, and only the channels whose toolchains were installed into the images are available.
+
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    emit::{AsmSyntax, Emit, EmitOptions},
+    execution::{ExecutionRequest, ExecutionResult, Limits, SourceFile, StepResult},
+    languages::{
+        rust::{CrateType, Rust, RustOptions, RustToolchains},
+        Language, Toolchains,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Debug,
+    Release,
+}
+
+/// Options shared by all requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Crate {
+    pub channel: Channel,
+    pub mode: Mode,
+    pub edition: String,
+    pub crate_type: CrateType,
+    pub tests: bool,
+    #[serde(default)]
+    pub backtrace: bool,
+    pub code: String,
+}
+
+/// Body of `POST /execute`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExecuteRequest {
+    #[serde(flatten)]
+    pub krate: Crate,
+}
+
+/// Answer to `POST /execute`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecuteResponse {
+    pub success: bool,
+    pub exit_detail: String,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Output of the compiler the playground offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Target {
+    Asm,
+    LlvmIr,
+    Mir,
+    Hir,
+    Wasm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AssemblyFlavor {
+    Att,
+    Intel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DemangleAssembly {
+    Demangle,
+    Mangle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessAssembly {
+    Filter,
+    Raw,
+}
+
+/// Body of `POST /compile`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompileRequest {
+    pub target: Target,
+    #[serde(default)]
+    pub assembly_flavor: Option<AssemblyFlavor>,
+    #[serde(default)]
+    pub demangle_assembly: Option<DemangleAssembly>,
+    #[serde(default)]
+    pub process_assembly: Option<ProcessAssembly>,
+    #[serde(flatten)]
+    pub krate: Crate,
+}
+
+/// Answer to `POST /compile`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompileResponse {
+    pub success: bool,
+    pub exit_detail: String,
+    /// Output of the compiler.
+    pub code: String,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Crate {
+    /// Execution of the crate with the toolchain of its channel out of the installed Rust toolchains, the default one
+    /// first.
+    fn execution(&self, toolchains: &Toolchains) -> Result<ExecutionRequest> {
+        let installed =
+            RustToolchains::installed(toolchains.get(Rust.name()).unwrap_or(&Value::Null))?;
+        let options = RustOptions {
+            toolchain: Some(toolchain(self.channel, &installed.names())?.to_owned()),
+            edition: self.edition.clone(),
+            release: self.mode == Mode::Release,
+            crate_type: self.crate_type,
+            tests: self.tests,
+            backtrace: self.backtrace,
+        };
+        let name = match self.crate_type {
+            CrateType::Bin => "main.rs",
+            CrateType::Lib => "lib.rs",
+        };
+        Ok(ExecutionRequest {
+            language: Rust.name().to_owned(),
+            files: vec![SourceFile {
+                name: name.to_owned(),
+                contents: self.code.clone(),
+            }],
+            stdin: String::new(),
+            args: Vec::new(),
+            limits: Limits::default(),
+            profile: None,
+            options: serde_json::to_value(options)?,
+            emit: None,
+            emit_options: EmitOptions::default(),
+        })
+    }
+}
+
+impl ExecuteRequest {
+    pub fn execution(&self, toolchains: &Toolchains) -> Result<ExecutionRequest> {
+        self.krate.execution(toolchains)
+    }
+}
+
+impl ExecuteResponse {
+    pub fn new(result: &ExecutionResult) -> Self {
+        let last = result.run.as_ref().unwrap_or(&result.compile);
+        let mut stderr = result.compile.stderr.clone();
+        if let Some(run) = &result.run {
+            stderr.push_str(&run.stderr);
+        }
+        Self {
+            success: result.compile.success && result.run.as_ref().is_none_or(|run| run.success),
+            exit_detail: exit_detail(last),
+            stdout: result
+                .run
+                .as_ref()
+                .map(|run| run.stdout.clone())
+                .unwrap_or_default(),
+            stderr,
+        }
+    }
+}
+
+impl CompileRequest {
+    pub fn execution(&self, toolchains: &Toolchains) -> Result<ExecutionRequest> {
+        let emit = match self.target {
+            Target::Asm => Emit::Asm,
+            Target::LlvmIr => Emit::LlvmIr,
+            Target::Mir => Emit::Mir,
+            Target::Hir | Target::Wasm => bail!("Target {:?} is not supported", self.target),
+        };
+        // The playground sends its assembly options with every target.
+        let syntax = match self.assembly_flavor {
+            Some(AssemblyFlavor::Intel) if emit == Emit::Asm => Some(AsmSyntax::Intel),
+            Some(AssemblyFlavor::Att) if emit == Emit::Asm => Some(AsmSyntax::Att),
+            _ => None,
+        };
+        Ok(ExecutionRequest {
+            emit: Some(emit),
+            emit_options: EmitOptions {
+                syntax,
+                demangle: self.demangle_assembly != Some(DemangleAssembly::Mangle),
+                filter: self.process_assembly != Some(ProcessAssembly::Raw),
+            },
+            ..self.krate.execution(toolchains)?
+        })
+    }
+}
+
+impl CompileResponse {
+    pub fn new(result: &ExecutionResult) -> Self {
+        Self {
+            success: result.compile.success,
+            exit_detail: exit_detail(&result.compile),
+            code: result
+                .emitted
+                .iter()
+                .flatten()
+                .map(|line| format!("{}\n", line.text))
+                .collect(),
+            stdout: result.compile.stdout.clone(),
+            stderr: result.compile.stderr.clone(),
+        }
+    }
+}
+
+/// Pick the installed toolchain of a channel. Stable toolchains may also be installed by their version, e.g. `1.81.0`,
+/// and the others by their date, e.g. `nightly-2024-10-01`.
+fn toolchain(channel: Channel, toolchains: &[String]) -> Result<&str> {
+    let matches = |toolchain: &str| match channel {
+        Channel::Stable => {
+            toolchain == "stable" || toolchain.starts_with(|c: char| c.is_ascii_digit())
+        }
+        Channel::Beta => toolchain == "beta" || toolchain.starts_with("beta-"),
+        Channel::Nightly => toolchain == "nightly" || toolchain.starts_with("nightly-"),
+    };
+    toolchains
+        .iter()
+        .map(String::as_str)
+        .find(|toolchain| matches(toolchain))
+        .ok_or_else(|| eyre!("No toolchain for the {channel:?} channel is installed"))
+}
+
+/// Describe how a step ended, like the playground does.
+fn exit_detail(step: &StepResult) -> String {
+    if step.timed_out {
+        return "Timed out".to_owned();
+    }
+    match step.exit_status {
+        Some(status) => match (status.code, status.signal) {
+            (Some(code), _) => format!("Exited with status {code}"),
+            (None, Some(signal)) => format!("Exited with signal {signal}"),
+            (None, None) => String::new(),
+        },
+        None if step.truncated => "Output too long".to_owned(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        emit::EmittedLine,
+        execution::{ExecutionResult, Timings},
+        languages,
+        machine::agent::ExitStatus,
+    };
+
+    fn compile_request(extra: Value) -> CompileRequest {
+        let mut request = json!({
+            "channel": "stable",
+            "mode": "debug",
+            "edition": "2021",
+            "crateType": "bin",
+            "tests": false,
+            "code": "fn main() {}",
+            "target": "asm",
+        });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn toolchains() -> Toolchains {
+        languages::toolchains(&["rust.extra-toolchains=nightly".to_owned()]).unwrap()
+    }
+
+    fn step(code: Option<i32>, signal: Option<i32>) -> StepResult {
+        StepResult {
+            success: code == Some(0),
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_status: Some(ExitStatus { code, signal }),
+            timed_out: false,
+            truncated: false,
+            duration_ms: 0,
+        }
+    }
+
+    fn result(compile: StepResult, run: Option<StepResult>) -> ExecutionResult {
+        ExecutionResult {
+            compile,
+            diagnostics: Vec::new(),
+            run,
+            emitted: None,
+            timings: Timings::default(),
+        }
+    }
+
+    #[test]
+    fn picks_toolchain_of_channel() {
+        let toolchains = ["1.81.0", "beta-2024-10-01", "nightly"].map(str::to_owned);
+        assert_eq!(toolchain(Channel::Stable, &toolchains).unwrap(), "1.81.0");
+        assert_eq!(
+            toolchain(Channel::Beta, &toolchains).unwrap(),
+            "beta-2024-10-01"
+        );
+        assert_eq!(toolchain(Channel::Nightly, &toolchains).unwrap(), "nightly");
+
+        let toolchains = ["stable".to_owned(), "nightly-2024-10-01".to_owned()];
+        assert_eq!(toolchain(Channel::Stable, &toolchains).unwrap(), "stable");
+        assert_eq!(
+            toolchain(Channel::Nightly, &toolchains).unwrap(),
+            "nightly-2024-10-01"
+        );
+        assert!(toolchain(Channel::Beta, &toolchains).is_err());
+        // Neither a toolchain merely containing the channel nor another channel's date counts.
+        assert!(toolchain(Channel::Beta, &["nightly-beta".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn translates_compile_requests() {
+        let request = compile_request(json!({ "assemblyFlavor": "intel" }))
+            .execution(&toolchains())
+            .unwrap();
+        assert_eq!(request.emit, Some(Emit::Asm));
+        assert_eq!(request.emit_options.syntax, Some(AsmSyntax::Intel));
+        assert!(request.emit_options.demangle);
+        assert!(request.emit_options.filter);
+        assert_eq!(request.options["toolchain"], "stable");
+        assert_eq!(request.files[0].name, "main.rs");
+
+        let request = compile_request(json!({
+            "channel": "nightly",
+            "crateType": "lib",
+            "assemblyFlavor": "att",
+            "demangleAssembly": "mangle",
+            "processAssembly": "raw",
+        }))
+        .execution(&toolchains())
+        .unwrap();
+        assert_eq!(request.emit_options.syntax, Some(AsmSyntax::Att));
+        assert!(!request.emit_options.demangle);
+        assert!(!request.emit_options.filter);
+        assert_eq!(request.options["toolchain"], "nightly");
+        assert_eq!(request.files[0].name, "lib.rs");
+
+        // The assembly flavor only applies to assembly.
+        for (target, emit) in [("llvm-ir", Emit::LlvmIr), ("mir", Emit::Mir)] {
+            let request = compile_request(json!({ "target": target, "assemblyFlavor": "intel" }))
+                .execution(&toolchains())
+                .unwrap();
+            assert_eq!(request.emit, Some(emit));
+            assert_eq!(request.emit_options.syntax, None);
+        }
+
+        for target in ["hir", "wasm"] {
+            assert!(compile_request(json!({ "target": target }))
+                .execution(&toolchains())
+                .is_err());
+        }
+        assert!(compile_request(json!({ "channel": "beta" }))
+            .execution(&toolchains())
+            .is_err());
+    }
+
+    #[test]
+    fn describes_exits() {
+        assert_eq!(exit_detail(&step(Some(0), None)), "Exited with status 0");
+        assert_eq!(exit_detail(&step(None, Some(9))), "Exited with signal 9");
+        let timed_out = StepResult {
+            timed_out: true,
+            ..step(None, Some(9))
+        };
+        assert_eq!(exit_detail(&timed_out), "Timed out");
+        let truncated = StepResult {
+            exit_status: None,
+            truncated: true,
+            ..step(None, None)
+        };
+        assert_eq!(exit_detail(&truncated), "Output too long");
+        let unreported = StepResult {
+            exit_status: None,
+            ..step(None, None)
+        };
+        assert_eq!(exit_detail(&unreported), "");
+    }
+
+    #[test]
+    fn builds_execute_responses() {
+        let compile = StepResult {
+            stderr: "Compiling\n".to_owned(),
+            ..step(Some(0), None)
+        };
+        let run = StepResult {
+            stdout: "Hello\n".to_owned(),
+            stderr: "panicked\n".to_owned(),
+            ..step(Some(101), None)
+        };
+        let response = ExecuteResponse::new(&result(compile.clone(), Some(run)));
+        assert!(!response.success);
+        assert_eq!(response.exit_detail, "Exited with status 101");
+        assert_eq!(response.stdout, "Hello\n");
+        assert_eq!(response.stderr, "Compiling\npanicked\n");
+
+        let response = ExecuteResponse::new(&result(compile, None));
+        assert!(response.success);
+        assert_eq!(response.exit_detail, "Exited with status 0");
+        assert_eq!(response.stdout, "");
+
+        let failed = StepResult {
+            stderr: "error[E0425]\n".to_owned(),
+            ..step(Some(1), None)
+        };
+        let response = ExecuteResponse::new(&result(failed, None));
+        assert!(!response.success);
+        assert_eq!(response.exit_detail, "Exited with status 1");
+        assert_eq!(response.stderr, "error[E0425]\n");
+    }
+
+    #[test]
+    fn builds_compile_responses() {
+        let compile = StepResult {
+            stdout: "out".to_owned(),
+            stderr: "warning".to_owned(),
+            ..step(Some(0), None)
+        };
+        let response = CompileResponse::new(&ExecutionResult {
+            emitted: Some(
+                ["main:", "\tret"]
+                    .map(|text| EmittedLine {
+                        text: text.to_owned(),
+                        source: None,
+                    })
+                    .to_vec(),
+            ),
+            ..result(compile, None)
+        });
+        assert!(response.success);
+        assert_eq!(response.exit_detail, "Exited with status 0");
+        assert_eq!(response.code, "main:\n\tret\n");
+        assert_eq!(response.stdout, "out");
+        assert_eq!(response.stderr, "warning");
+
+        let response = CompileResponse::new(&result(step(Some(1), None), None));
+        assert!(!response.success);
+        assert_eq!(response.code, "");
+    }
+}
@@ -0,0 +1,560 @@
+//! Keep a number of booted VMs ready, so that sessions do not have to wait for a VM to boot.
+//!
+//! VMs are never returned to the pool: once handed out, a VM belongs to its session and is destroyed afterwards, so no
+//! state can leak from one session to the next.
+//!
+//! The memory of every VM the pool started is committed until the VM is gone, so that concurrent starts cannot
+//! overcommit the host. As fresh VMs hardly touch most of their memory, the refill thread inflates the balloons of the
+//! idle VMs, and sessions inflate the balloons of VMs their users left alone, see `PooledMachine::reclaim_memory`. Only
+//! the memory the balloons actually took back from their guests may be given to other VMs, until they are deflated
+//! again. A VM is only started once its memory fits in addition to the committed memory and the host has enough memory
+//! available to back it, otherwise the pool waits like it does for a free slot. The balloon of a VM is deflated when it
+//! is handed out.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    ops::{Deref, DerefMut},
+    sync::{Arc, Condvar, Mutex},
+    thread::{sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::Context, Result};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::Profile,
+    host,
+    machine::{config::BALLOON_STATS_INTERVAL_SECS, metrics::VmMetrics, Machine},
+    slots::{SlotAllocator, SlotLease},
+};
+
+/// Starts a new VM with the named profile using the given slot.
+pub type Launcher = Box<dyn Fn(SlotLease, &str) -> Result<Machine> + Send + Sync>;
+
+pub struct Pool {
+    shared: Arc<Shared>,
+    refill: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    slots: SlotAllocator,
+    launch: Launcher,
+    warm_size: usize,
+    /// Profile of the idle VMs.
+    warm_profile: String,
+    /// Memory size of the VMs of every profile.
+    memory_mib: BTreeMap<String, usize>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    idle: VecDeque<PooledMachine>,
+    stopped: bool,
+    boots: BootStats,
+    /// Memory of the VMs started by the pool that are not gone yet, whether they are starting, idle or handed out.
+    committed_mib: usize,
+    /// Part of the committed memory the balloons of the VMs took back from their guests.
+    reclaimed_mib: usize,
+    /// Final metrics of the VMs that were dropped since the exporter last took them, by their id.
+    finished_metrics: HashMap<String, VmMetrics>,
+}
+
+/// A VM started by the pool, which keeps its memory committed until it is dropped.
+pub struct PooledMachine {
+    machine: Machine,
+    memory: Commitment,
+}
+
+impl PooledMachine {
+    /// Inflate the balloon of the VM, see `Balloon::inflate`, and let the pool give the memory the balloon took
+    /// back so far to other VMs.
+    pub fn reclaim_memory(&mut self) -> Result<()> {
+        let balloon = self.machine.balloon();
+        let reclaimed = balloon.inflate().and_then(|_| balloon.reclaimed_mib());
+        let mib = reclaimed.as_ref().map_or(0, |&mib| mib as usize);
+        let shared = self.memory.shared.clone();
+        self.memory
+            .set_reclaimed(&mut shared.state.lock().unwrap().reclaimed_mib, mib);
+        shared.changed.notify_all();
+        reclaimed.map(|_| ())
+    }
+
+    /// Deflate the balloon of the VM, taking the memory it reclaimed away from the other VMs first.
+    pub fn return_memory(&mut self) -> Result<()> {
+        let shared = self.memory.shared.clone();
+        self.memory
+            .set_reclaimed(&mut shared.state.lock().unwrap().reclaimed_mib, 0);
+        self.machine.deflate_balloon()
+    }
+}
+
+impl Deref for PooledMachine {
+    type Target = Machine;
+
+    fn deref(&self) -> &Machine {
+        &self.machine
+    }
+}
+
+impl DerefMut for PooledMachine {
+    fn deref_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+}
+
+impl Drop for PooledMachine {
+    /// Firecracker only writes its metrics every minute, so the final metrics of the VM are collected before it is
+    /// torn down. Must not be dropped while holding the lock of the state, like `Commitment`.
+    fn drop(&mut self) {
+        match self.machine.metrics() {
+            Ok(metrics) => {
+                let shared = &self.memory.shared;
+                let mut state = shared.state.lock().unwrap();
+                state
+                    .finished_metrics
+                    .insert(self.machine.id().to_owned(), metrics);
+            }
+            Err(err) => warn!(
+                "Could not collect final metrics of VM {}: {err:?}",
+                self.machine.id()
+            ),
+        }
+    }
+}
+
+/// Memory committed to a VM, which is released when dropped. Must not be dropped while holding the lock of the state.
+struct Commitment {
+    shared: Arc<Shared>,
+    mib: usize,
+    /// Part of the memory the balloon of the VM took back, which is counted in `State::reclaimed_mib`.
+    reclaimed_mib: usize,
+}
+
+impl Commitment {
+    /// Record that the balloon of the VM took back `mib`, updating `total`, the `State::reclaimed_mib` of the locked
+    /// state. More than the memory of the VM is never counted.
+    fn set_reclaimed(&mut self, total: &mut usize, mib: usize) {
+        let mib = mib.min(self.mib);
+        *total = *total - self.reclaimed_mib + mib;
+        self.reclaimed_mib = mib;
+    }
+}
+
+impl Drop for Commitment {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.committed_mib -= self.mib;
+        state.reclaimed_mib -= self.reclaimed_mib;
+        drop(state);
+        // Someone might be waiting for memory.
+        self.shared.changed.notify_all();
+    }
+}
+
+/// How long VMs took to become ready.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BootStats {
+    pub count: u64,
+    pub total: Duration,
+    pub last: Option<Duration>,
+}
+
+/// Current state of a pool, for monitoring.
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    /// Number of VMs ready to be handed out.
+    pub idle: usize,
+    pub warm_size: usize,
+    pub warm_profile: String,
+    /// Slots leased by this process, slots of other codepot processes are not included.
+    pub slots_leased: usize,
+    pub slots_total: usize,
+    pub boots: BootStats,
+    /// Memory of the VMs started by the pool that are not gone yet.
+    pub committed_mib: usize,
+}
+
+/// Read-only view of a pool that can be handed to other threads without keeping the VMs of the pool alive.
+#[derive(Clone)]
+pub struct PoolMonitor(Arc<Shared>);
+
+impl Pool {
+    /// How long a VM may take until it is ready.
+    const BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+    /// How long the agent may take to answer once the VM is ready.
+    const AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+    /// How long to wait before trying again if no slot is free or starting a VM failed.
+    const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+    /// Memory kept free for the host itself.
+    const HOST_MEMORY_RESERVE_MIB: usize = 512;
+    /// How often the balloons of the idle VMs are inflated, matching how often the guests report their memory usage.
+    const RECLAIM_INTERVAL: Duration = Duration::from_secs(BALLOON_STATS_INTERVAL_SECS as u64);
+
+    /// Create a pool that keeps `warm_size` idle VMs with the profile `warm_profile` started by `launch`, as far as the
+    /// slots allow.
+    pub fn new(
+        slots: SlotAllocator,
+        warm_size: usize,
+        warm_profile: String,
+        profiles: &BTreeMap<String, Profile>,
+        launch: Launcher,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            slots,
+            launch,
+            warm_size,
+            warm_profile,
+            memory_mib: profiles
+                .iter()
+                .map(|(name, profile)| (name.clone(), profile.mem_size_mib))
+                .collect(),
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        let refill = {
+            let shared = shared.clone();
+            std::thread::spawn(move || refill(shared))
+        };
+        Self {
+            shared,
+            refill: Some(refill),
+        }
+    }
+
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor(self.shared.clone())
+    }
+
+    /// Take a ready VM with the named profile from the pool, starting one if none is idle. Only VMs with the profile
+    /// of the pool are kept idle, VMs with other profiles are always started on demand. Blocks until a slot is free and
+    /// the memory of the VM fits.
+    pub fn acquire(&self, profile: &str) -> Result<PooledMachine> {
+        loop {
+            if profile == self.shared.warm_profile {
+                while let Some(mut machine) = self.shared.take_idle() {
+                    if machine.try_wait()?.is_none() {
+                        debug!("Handing out idle VM {}", machine.id());
+                        if let Err(err) = machine.return_memory() {
+                            debug!("Leaving the balloon alone: {err:?}");
+                        }
+                        return Ok(machine);
+                    }
+                    warn!("Idle VM {} exited, discarding it", machine.id());
+                }
+            }
+
+            if let Some(memory) = self.shared.commit_memory(profile)? {
+                if let Some(slot) = self.shared.slots.lease()? {
+                    debug!("No idle VM with profile {profile} available, starting one");
+                    return self.shared.start(slot, profile, memory);
+                }
+            } else {
+                debug!("Not enough memory for a VM with profile {profile}, waiting");
+            }
+
+            let state = self.shared.state.lock().unwrap();
+            let _ = self
+                .shared
+                .changed
+                .wait_timeout(state, Self::RETRY_INTERVAL)
+                .unwrap();
+        }
+    }
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        let state = self.0.state.lock().unwrap();
+        PoolStats {
+            idle: state.idle.len(),
+            warm_size: self.0.warm_size,
+            warm_profile: self.0.warm_profile.clone(),
+            slots_leased: self.0.slots.in_use(),
+            slots_total: self.0.slots.len(),
+            boots: state.boots,
+            committed_mib: state.committed_mib,
+        }
+    }
+
+    /// Take the final metrics of the VMs that were dropped since the last call, by their id.
+    pub fn take_finished_metrics(&self) -> HashMap<String, VmMetrics> {
+        std::mem::take(&mut self.0.state.lock().unwrap().finished_metrics)
+    }
+}
+
+impl Shared {
+    /// Take the oldest idle VM out of the pool, letting the refill thread know that there is room for another one.
+    fn take_idle(&self) -> Option<PooledMachine> {
+        let machine = self.state.lock().unwrap().idle.pop_front();
+        self.changed.notify_all();
+        machine
+    }
+
+    /// Commit the memory of a VM with the named profile if it fits, see the module docs.
+    fn commit_memory(self: &Arc<Self>, profile: &str) -> Result<Option<Commitment>> {
+        let mib = self.memory_mib.get(profile).copied().unwrap_or_default();
+        let needed_mib = mib + Pool::HOST_MEMORY_RESERVE_MIB;
+        let memory_mib = host::memory_mib()?;
+        if host::available_memory_mib()? < needed_mib {
+            return Ok(None);
+        }
+
+        Ok(self.try_commit(mib, memory_mib))
+    }
+
+    /// Commit `mib` if they fit on a host with `memory_mib` next to the memory committed so far.
+    fn try_commit(self: &Arc<Self>, mib: usize, memory_mib: usize) -> Option<Commitment> {
+        let mut state = self.state.lock().unwrap();
+        if !fits(mib, state.committed_mib, state.reclaimed_mib, memory_mib) {
+            return None;
+        }
+        state.committed_mib += mib;
+        Some(Commitment {
+            shared: self.clone(),
+            mib,
+            reclaimed_mib: 0,
+        })
+    }
+
+    /// Inflate the balloons of the idle VMs and record how much memory they took back. The firecracker API is only
+    /// called outside of the lock, so that a hanging VM does not hold up everyone else.
+    fn reclaim_idle_memory(&self) {
+        let balloons: Vec<_> = self
+            .state
+            .lock()
+            .unwrap()
+            .idle
+            .iter()
+            .map(|machine| (machine.id().to_owned(), machine.balloon()))
+            .collect();
+        let reclaimed: Vec<_> = balloons
+            .into_iter()
+            .map(|(id, balloon)| {
+                let mib = balloon
+                    .inflate()
+                    .and_then(|_| balloon.reclaimed_mib())
+                    .unwrap_or_else(|err| {
+                        debug!("Counting all memory of VM {id} as used: {err:?}");
+                        0
+                    });
+                (id, mib as usize)
+            })
+            .collect();
+
+        let mut state = self.state.lock().unwrap();
+        let State {
+            idle,
+            reclaimed_mib,
+            ..
+        } = &mut *state;
+        for (id, mib) in reclaimed {
+            // The VM might have been handed out in the meantime.
+            if let Some(machine) = idle.iter_mut().find(|machine| machine.id() == id) {
+                machine.memory.set_reclaimed(reclaimed_mib, mib);
+            }
+        }
+        // Someone might be waiting for memory.
+        self.changed.notify_all();
+    }
+
+    /// Start a VM with the named profile and wait until it booted and its agent answers.
+    fn start(&self, slot: SlotLease, profile: &str, memory: Commitment) -> Result<PooledMachine> {
+        let start = Instant::now();
+        let machine = PooledMachine {
+            machine: (self.launch)(slot, profile)?,
+            memory,
+        };
+        machine.wait_for_console(Machine::READY_MESSAGE, Pool::BOOT_TIMEOUT)?;
+        machine
+            .agent()
+            .wait_until_ready(Pool::AGENT_TIMEOUT)
+            .with_context(|| format!("Agent of VM {} is not reachable", machine.id()))?;
+
+        let boot_time = start.elapsed();
+        debug!("VM {} became ready after {boot_time:?}", machine.id());
+        let boots = &mut self.state.lock().unwrap().boots;
+        boots.count += 1;
+        boots.total += boot_time;
+        boots.last = Some(boot_time);
+        Ok(machine)
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.changed.notify_all();
+        if let Some(refill) = self.refill.take() {
+            if refill.join().is_err() {
+                error!("Pool refill thread panicked");
+            }
+        }
+        // Shut down the idle VMs outside of the lock.
+        let idle = std::mem::take(&mut self.shared.state.lock().unwrap().idle);
+        drop(idle);
+    }
+}
+
+/// Whether `mib` of another VM fit on a host with `memory_mib` next to the `committed_mib` of the other VMs, of which
+/// their balloons reclaimed `reclaimed_mib`, leaving memory for the host itself.
+fn fits(mib: usize, committed_mib: usize, reclaimed_mib: usize, memory_mib: usize) -> bool {
+    committed_mib.saturating_sub(reclaimed_mib) + mib + Pool::HOST_MEMORY_RESERVE_MIB <= memory_mib
+}
+
+/// Keep the pool filled up until it is stopped, reclaiming the memory of the idle VMs in the meantime.
+fn refill(shared: Arc<Shared>) {
+    loop {
+        let full = {
+            let state = shared.state.lock().unwrap();
+            let (state, _) = shared
+                .changed
+                .wait_timeout_while(state, Pool::RECLAIM_INTERVAL, |state| {
+                    !state.stopped && state.idle.len() >= shared.warm_size
+                })
+                .unwrap();
+            if state.stopped {
+                return;
+            }
+            state.idle.len() >= shared.warm_size
+        };
+
+        shared.reclaim_idle_memory();
+        if full {
+            continue;
+        }
+
+        let memory = match shared.commit_memory(&shared.warm_profile) {
+            Ok(Some(memory)) => memory,
+            Ok(None) => {
+                debug!("Not enough memory to refill pool");
+                sleep(Pool::RETRY_INTERVAL);
+                continue;
+            }
+            Err(err) => {
+                error!("Could not check available memory: {err:?}");
+                sleep(Pool::RETRY_INTERVAL);
+                continue;
+            }
+        };
+
+        let slot = match shared.slots.lease() {
+            Ok(Some(slot)) => slot,
+            Ok(None) => {
+                debug!("All slots in use, not refilling pool");
+                sleep(Pool::RETRY_INTERVAL);
+                continue;
+            }
+            Err(err) => {
+                error!("Could not lease slot: {err:?}");
+                sleep(Pool::RETRY_INTERVAL);
+                continue;
+            }
+        };
+
+        match shared.start(slot, &shared.warm_profile, memory) {
+            Ok(machine) => {
+                info!("VM {} is ready", machine.id());
+                let mut state = shared.state.lock().unwrap();
+                if state.stopped {
+                    // Release the memory of the VM outside of the lock.
+                    drop(state);
+                    return;
+                }
+                state.idle.push_back(machine);
+                shared.changed.notify_all();
+            }
+            Err(err) => {
+                error!("Could not start VM for pool: {err:?}");
+                sleep(Pool::RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::bail;
+
+    use super::*;
+
+    fn shared() -> (tempfile::TempDir, Arc<Shared>) {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = Arc::new(Shared {
+            slots: SlotAllocator::new(dir.path(), Vec::new()).unwrap(),
+            launch: Box::new(|_, _| bail!("No VMs in tests")),
+            warm_size: 0,
+            warm_profile: "small".to_owned(),
+            memory_mib: BTreeMap::new(),
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        (dir, shared)
+    }
+
+    fn memory(shared: &Shared) -> (usize, usize) {
+        let state = shared.state.lock().unwrap();
+        (state.committed_mib, state.reclaimed_mib)
+    }
+
+    fn set_reclaimed(shared: &Shared, commitment: &mut Commitment, mib: usize) {
+        commitment.set_reclaimed(&mut shared.state.lock().unwrap().reclaimed_mib, mib);
+    }
+
+    #[test]
+    fn fits_next_to_unreclaimed_memory() {
+        let reserve = Pool::HOST_MEMORY_RESERVE_MIB;
+        assert!(fits(512, 0, 0, 512 + reserve));
+        assert!(!fits(512, 0, 0, 511 + reserve));
+        assert!(!fits(512, 1024, 0, 1024 + reserve));
+        assert!(fits(512, 1024, 512, 1024 + reserve));
+        // More reclaimed memory than committed does not make room for anything beyond the host memory.
+        assert!(!fits(2048, 512, 1024, 1024 + reserve));
+    }
+
+    #[test]
+    fn commits_memory_until_dropped() {
+        let (_dir, shared) = shared();
+        let host_mib = 1024 + Pool::HOST_MEMORY_RESERVE_MIB;
+        let first = shared.try_commit(512, host_mib).unwrap();
+        let second = shared.try_commit(512, host_mib).unwrap();
+        assert_eq!(memory(&shared), (1024, 0));
+        assert!(shared.try_commit(512, host_mib).is_none());
+
+        drop(first);
+        assert_eq!(memory(&shared), (512, 0));
+        let third = shared.try_commit(512, host_mib).unwrap();
+        drop((second, third));
+        assert_eq!(memory(&shared), (0, 0));
+    }
+
+    #[test]
+    fn credits_reclaimed_memory_until_returned() {
+        let (_dir, shared) = shared();
+        let host_mib = 1024 + Pool::HOST_MEMORY_RESERVE_MIB;
+        let mut first = shared.try_commit(512, host_mib).unwrap();
+        let mut second = shared.try_commit(512, host_mib).unwrap();
+        assert!(shared.try_commit(256, host_mib).is_none());
+
+        set_reclaimed(&shared, &mut first, 128);
+        set_reclaimed(&shared, &mut second, 256);
+        assert_eq!(memory(&shared), (1024, 384));
+        // A balloon that shrank only counts with its new size, and never with more than the memory of its VM.
+        set_reclaimed(&shared, &mut second, 200);
+        set_reclaimed(&shared, &mut first, 4096);
+        assert_eq!(memory(&shared), (1024, 712));
+        let third = shared.try_commit(512, host_mib).unwrap();
+        assert_eq!(memory(&shared), (1536, 712));
+
+        // Deflating takes the credit back, dropping releases the memory along with its credit.
+        set_reclaimed(&shared, &mut first, 0);
+        assert_eq!(memory(&shared), (1536, 200));
+        assert!(shared.try_commit(1, host_mib).is_none());
+        drop(second);
+        assert_eq!(memory(&shared), (1024, 0));
+        drop((first, third));
+        assert_eq!(memory(&shared), (0, 0));
+    }
+}
@@ -0,0 +1,100 @@
+//! Tear down VMs that were idle for too long, as the motd promises the users.
+//!
+//! Long before that, the memory an idle VM does not use is reclaimed through its balloon and handed back once the VM
+//! is used again, so that VMs waiting for their users to come back take up little memory on the host.
+
+use std::{process::ExitStatus, thread::sleep, time::Duration};
+
+use color_eyre::Result;
+use tracing::{debug, info, warn};
+
+use crate::pool::PooledMachine;
+
+/// How long before tearing down an idle VM its users are warned.
+const WARNING_PERIOD: Duration = Duration::from_secs(60);
+/// How often the activity of a VM is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a VM has to be idle before its unused memory is reclaimed.
+const RECLAIM_AFTER: Duration = Duration::from_secs(30);
+
+/// State of the balloon of a supervised VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Balloon {
+    Deflated,
+    Inflated,
+    /// The balloon could not be used, e.g. because the VM was restored from a snapshot without one.
+    Unavailable,
+}
+
+/// Why the session of a VM ended.
+#[derive(Debug, Clone, Copy)]
+pub enum SessionEnd {
+    /// Firecracker exited, e.g. because the guest rebooted.
+    Exited(ExitStatus),
+    /// The VM was idle for longer than the timeout.
+    Idle,
+}
+
+/// Watch the given VM until it exits or was idle for `idle_timeout`, warning its users inside of the guest a minute
+/// before. The idle time is counted from the start of the supervision, not from the boot of the VM, which may have
+/// waited in the pool. An idle VM is left running, dropping it tears it down, frees its slot and discards its disk.
+///
+/// The memory reclaimed from the VM while it is idle is handed to the pool for other VMs, until it is used again.
+pub fn supervise(machine: &mut PooledMachine, idle_timeout: Duration) -> Result<SessionEnd> {
+    machine.reset_idle_time();
+    let mut warned = false;
+    let mut balloon = Balloon::Deflated;
+    loop {
+        if let Some(status) = machine.try_wait()? {
+            info!("VM {} exited with {status}", machine.id());
+            return Ok(SessionEnd::Exited(status));
+        }
+
+        let idle_time = machine.idle_time()?;
+        if idle_time >= idle_timeout {
+            info!(
+                "VM {} was idle for {}s, tearing it down",
+                machine.id(),
+                idle_time.as_secs()
+            );
+            return Ok(SessionEnd::Idle);
+        }
+
+        balloon = match balloon {
+            Balloon::Deflated | Balloon::Inflated if idle_time >= RECLAIM_AFTER => {
+                match machine.reclaim_memory() {
+                    Ok(()) => Balloon::Inflated,
+                    Err(err) => {
+                        warn!("Could not reclaim memory, leaving the balloon alone: {err:?}");
+                        Balloon::Unavailable
+                    }
+                }
+            }
+            Balloon::Inflated => match machine.return_memory() {
+                Ok(()) => Balloon::Deflated,
+                Err(err) => {
+                    warn!("Could not give back memory, leaving the balloon alone: {err:?}");
+                    Balloon::Unavailable
+                }
+            },
+            balloon => balloon,
+        };
+
+        if idle_time + WARNING_PERIOD >= idle_timeout {
+            if !warned {
+                let remaining = idle_timeout - idle_time;
+                debug!("Warning users of idle VM {}", machine.id());
+                machine.notify_users(&format!(
+                    "This VM is idle and will be reset in {}s, discarding all changes. Use it to keep it alive.",
+                    remaining.as_secs()
+                ))?;
+                warned = true;
+            }
+        } else {
+            // The VM was used again, so warn again the next time it becomes idle.
+            warned = false;
+        }
+
+        sleep(CHECK_INTERVAL);
+    }
+}
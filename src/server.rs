@@ -0,0 +1,208 @@
+//! HTTP API of `codepot run`, through which clients compile and run code in fresh VMs, see `execution`, and list the
+//! available `languages`. The endpoints of the Rust playground are served as well, see `playground`.
+//!
+//! Executions block until a VM is available and the code ran, so they are handled by a fixed number of workers. Requests
+//! that find the queue in front of the workers full are answered with 503, all other requests are answered right away.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Read,
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Receiver, TrySendError},
+        Arc, Mutex,
+    },
+};
+
+use color_eyre::{
+    eyre::{ensure, eyre, Context},
+    Result,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{error, info, warn};
+
+use crate::{
+    emit::Emit,
+    execution::{self, ExecutionRequest, ExecutionResult},
+    languages::{self, Toolchains},
+    playground::{CompileRequest, CompileResponse, ExecuteRequest, ExecuteResponse},
+    pool::Pool,
+};
+
+/// Upper bound for the body of a request.
+const MAX_BODY_BYTES: usize = 8 * 1024 * 1024;
+/// Executions waiting for a worker beyond this are rejected.
+const MAX_QUEUED_REQUESTS: usize = 64;
+
+type JsonResponse = Response<std::io::Cursor<Vec<u8>>>;
+
+/// Entry of `GET /api/languages`.
+#[derive(Debug, Serialize)]
+struct LanguageInfo {
+    name: &'static str,
+    display_name: &'static str,
+    /// Version of the toolchain, if the images were built by this version of codepot.
+    version: Option<String>,
+    extensions: &'static [&'static str],
+    emits: &'static [Emit],
+}
+
+/// State shared by the request handlers.
+struct Api {
+    pool: Arc<Pool>,
+    /// Profile of executions that do not ask for a specific one.
+    default_profile: String,
+    profiles: BTreeSet<String>,
+    /// Toolchains installed into the images by the names of their languages.
+    toolchains: Toolchains,
+    /// Versions of the toolchains by the names of their languages.
+    language_versions: BTreeMap<String, String>,
+}
+
+/// Serve the API at `address` from a background thread, executing code in VMs from `pool` on `workers` threads.
+pub fn serve(
+    address: SocketAddr,
+    pool: Arc<Pool>,
+    workers: usize,
+    default_profile: String,
+    profiles: BTreeSet<String>,
+    toolchains: Toolchains,
+    language_versions: BTreeMap<String, String>,
+) -> Result<()> {
+    let server = Server::http(address)
+        .map_err(|err| eyre!(err))
+        .with_context(|| format!("Could not listen on {address}"))?;
+    info!("Serving API at http://{address}/api");
+
+    let api = Arc::new(Api {
+        pool,
+        default_profile,
+        profiles,
+        toolchains,
+        language_versions,
+    });
+    let (queue, queued) = mpsc::sync_channel(MAX_QUEUED_REQUESTS);
+    let queued = Arc::new(Mutex::new(queued));
+    for _ in 0..workers.max(1) {
+        let api = api.clone();
+        let queued = queued.clone();
+        std::thread::spawn(move || work(&api, &queued));
+    }
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // Only executions are posted.
+            if *request.method() != Method::Post {
+                api.handle(request);
+                continue;
+            }
+            if let Err(TrySendError::Full(request) | TrySendError::Disconnected(request)) =
+                queue.try_send(request)
+            {
+                warn!("Too many executions queued, rejecting request");
+                let response =
+                    error_response(503, &eyre!("Too many executions queued, try again later"));
+                if let Err(err) = request.respond(response) {
+                    warn!("Could not send response: {err}");
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Handle queued requests one after the other.
+fn work(api: &Api, queued: &Mutex<Receiver<Request>>) {
+    loop {
+        // Only wait for the next request while holding the lock, not while handling it.
+        let request = queued.lock().unwrap().recv();
+        match request {
+            Ok(request) => api.handle(request),
+            Err(_) => return,
+        }
+    }
+}
+
+impl Api {
+    fn handle(&self, mut request: Request) {
+        let response = match (request.method(), request.url()) {
+            (Method::Get, "/api/languages") => Ok(json_response(200, &self.languages())),
+            (Method::Post, "/api/execute") => read_json(&mut request)
+                .map_err(|err| error_response(400, &err))
+                .and_then(|request| self.execute(&request))
+                .map(|result| json_response(200, &result)),
+            (Method::Post, "/execute") => read_json::<ExecuteRequest>(&mut request)
+                .and_then(|request| request.execution(&self.toolchains))
+                .map_err(|err| error_response(400, &err))
+                .and_then(|request| self.execute(&request))
+                .map(|result| json_response(200, &ExecuteResponse::new(&result))),
+            (Method::Post, "/compile") => read_json::<CompileRequest>(&mut request)
+                .and_then(|request| request.execution(&self.toolchains))
+                .map_err(|err| error_response(400, &err))
+                .and_then(|request| self.execute(&request))
+                .map(|result| json_response(200, &CompileResponse::new(&result))),
+            _ => Err(error_response(404, &eyre!("Not found"))),
+        };
+        if let Err(err) = request.respond(response.unwrap_or_else(|response| response)) {
+            warn!("Could not send response: {err}");
+        }
+    }
+
+    fn languages(&self) -> Vec<LanguageInfo> {
+        languages::all()
+            .iter()
+            .map(|language| LanguageInfo {
+                name: language.name(),
+                display_name: language.display_name(),
+                version: self.language_versions.get(language.name()).cloned(),
+                extensions: language.extensions(),
+                emits: language.emits(),
+            })
+            .collect()
+    }
+
+    /// Compile and run the code of the request, answering invalid requests and failures with an error response.
+    fn execute(&self, request: &ExecutionRequest) -> Result<ExecutionResult, JsonResponse> {
+        let profile = request.profile.as_ref().unwrap_or(&self.default_profile);
+        let valid = request.validate(&self.toolchains).and_then(|()| {
+            ensure!(
+                self.profiles.contains(profile),
+                "No profile named {profile}"
+            );
+            Ok(())
+        });
+        valid.map_err(|err| error_response(400, &err))?;
+        execution::execute(&self.pool, profile, request).map_err(|err| {
+            error!("Could not execute code: {err:?}");
+            error_response(500, &err)
+        })
+    }
+}
+
+/// Parse the JSON body of a request.
+fn read_json<T: DeserializeOwned>(request: &mut Request) -> Result<T> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES as u64 + 1)
+        .read_to_end(&mut body)
+        .context("Could not read request")?;
+    ensure!(
+        body.len() <= MAX_BODY_BYTES,
+        "Request larger than {MAX_BODY_BYTES} bytes"
+    );
+    serde_json::from_slice(&body).context("Invalid request")
+}
+
+fn json_response(status: u16, body: &impl Serialize) -> JsonResponse {
+    // Serializing the plain data types of the API cannot fail.
+    let body = serde_json::to_vec(body).unwrap();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header("Content-Type: application/json".parse::<Header>().unwrap())
+}
+
+fn error_response(status: u16, err: &color_eyre::Report) -> JsonResponse {
+    json_response(status, &json!({ "error": format!("{err:#}") }))
+}
@@ -0,0 +1,199 @@
+//! Lease the network slots (tap interface, IP and MAC address) from the pool in the config to VMs.
+//!
+//! Every slot is guarded by an exclusive lock on a file in the lock directory. The lock is released by the operating
+//! system once the lease is dropped or the process holding it exits, so multiple codepot processes can share the pool
+//! and a crashed process does not leak its slots.
+
+use std::{
+    fs::{File, TryLockError},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use color_eyre::{eyre::Context, Result};
+use tracing::{debug, warn};
+
+use crate::config::InterfaceConfig;
+
+#[derive(Debug)]
+pub struct SlotAllocator {
+    lock_dir: PathBuf,
+    interfaces: Vec<InterfaceConfig>,
+    /// Number of slots currently leased by this allocator.
+    leased: Arc<AtomicUsize>,
+}
+
+impl SlotAllocator {
+    /// Create an allocator over the given interfaces, creating the lock directory if necessary.
+    pub fn new(lock_dir: impl AsRef<Path>, interfaces: Vec<InterfaceConfig>) -> Result<Self> {
+        let lock_dir = lock_dir.as_ref().to_owned();
+        std::fs::create_dir_all(&lock_dir).with_context(|| {
+            format!(
+                "Could not create slot lock directory {}",
+                lock_dir.display()
+            )
+        })?;
+        Ok(Self {
+            lock_dir,
+            interfaces,
+            leased: Arc::default(),
+        })
+    }
+
+    /// Number of slots in the pool.
+    pub fn len(&self) -> usize {
+        self.interfaces.len()
+    }
+
+    /// Number of slots leased through this allocator. Slots leased by other codepot processes are not included.
+    pub fn in_use(&self) -> usize {
+        self.leased.load(Ordering::Relaxed)
+    }
+
+    /// Lease a free slot, returning `None` if all slots are taken.
+    pub fn lease(&self) -> Result<Option<SlotLease>> {
+        for interface in &self.interfaces {
+            let lock_path = self.lock_dir.join(format!("{}.lock", interface.if_name));
+            let lock = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_path)
+                .with_context(|| format!("Could not open lock file {}", lock_path.display()))?;
+            match lock.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => continue,
+                Err(TryLockError::Error(err)) => {
+                    return Err(err)
+                        .with_context(|| format!("Could not lock {}", lock_path.display()))
+                }
+            }
+
+            // VMs are not killed together with the process that started them, so a crashed codepot process might
+            // have left a VM behind that still uses the tap.
+            if tap_in_use(&interface.if_name) {
+                warn!(
+                    "Tap interface {} is still in use although its slot is free, skipping it",
+                    interface.if_name
+                );
+                continue;
+            }
+
+            debug!("Leased slot {}", interface.if_name);
+            self.leased.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(SlotLease {
+                interface: interface.clone(),
+                _lock: lock,
+                leased: self.leased.clone(),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// A leased slot, which is returned to the pool when dropped.
+#[derive(Debug)]
+pub struct SlotLease {
+    interface: InterfaceConfig,
+    _lock: File,
+    leased: Arc<AtomicUsize>,
+}
+
+impl SlotLease {
+    pub fn interface(&self) -> &InterfaceConfig {
+        &self.interface
+    }
+}
+
+impl Drop for SlotLease {
+    fn drop(&mut self) {
+        self.leased.fetch_sub(1, Ordering::Relaxed);
+        debug!("Released slot {}", self.interface.if_name);
+    }
+}
+
+/// A tap has carrier as long as some process (i.e. firecracker) is attached to it.
+fn tap_in_use(if_name: &str) -> bool {
+    std::fs::read_to_string(format!("/sys/class/net/{if_name}/carrier"))
+        .is_ok_and(|carrier| carrier.trim() == "1")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ipnet::Ipv4Net;
+
+    use super::*;
+
+    /// Interfaces with names that do not exist on the host, so their taps never count as in use.
+    fn interfaces(count: u8) -> Vec<InterfaceConfig> {
+        (0..count)
+            .map(|i| {
+                InterfaceConfig::new(
+                    format!("cdpttest{i}"),
+                    Ipv4Net::new([10, 0, 0, i + 2].into(), 24).unwrap(),
+                    format!("06:00:0A:00:00:{:02X}", i + 2),
+                )
+            })
+            .collect()
+    }
+
+    fn if_name(lease: &SlotLease) -> String {
+        lease.interface().if_name.clone()
+    }
+
+    #[test]
+    fn never_leases_a_slot_twice_across_allocators() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = SlotAllocator::new(dir.path(), interfaces(3)).unwrap();
+        let b = SlotAllocator::new(dir.path(), interfaces(3)).unwrap();
+
+        let mut leases = Vec::new();
+        for allocator in [&a, &b, &a, &b] {
+            leases.extend(allocator.lease().unwrap());
+        }
+        assert_eq!(leases.len(), 3);
+        let if_names: HashSet<_> = leases.iter().map(if_name).collect();
+        assert_eq!(if_names.len(), 3);
+        assert_eq!(a.in_use() + b.in_use(), 3);
+        assert!(a.lease().unwrap().is_none());
+        assert!(b.lease().unwrap().is_none());
+    }
+
+    #[test]
+    fn frees_slots_once_their_lease_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = SlotAllocator::new(dir.path(), interfaces(2)).unwrap();
+        let b = SlotAllocator::new(dir.path(), interfaces(2)).unwrap();
+
+        let first = a.lease().unwrap().unwrap();
+        let _second = a.lease().unwrap().unwrap();
+        assert_eq!(a.in_use(), 2);
+        assert!(b.lease().unwrap().is_none());
+
+        let freed = if_name(&first);
+        drop(first);
+        assert_eq!(a.in_use(), 1);
+        let lease = b.lease().unwrap().unwrap();
+        assert_eq!(if_name(&lease), freed);
+        assert_eq!(b.in_use(), 1);
+    }
+
+    #[test]
+    fn leases_slots_left_behind_by_a_crashed_process() {
+        let dir = tempfile::tempdir().unwrap();
+        // A crashed process leaves its lock files behind, but the operating system released their locks.
+        for interface in interfaces(2) {
+            File::create(dir.path().join(format!("{}.lock", interface.if_name))).unwrap();
+        }
+
+        let allocator = SlotAllocator::new(dir.path(), interfaces(2)).unwrap();
+        let leases: Vec<_> = std::iter::from_fn(|| allocator.lease().unwrap()).collect();
+        assert_eq!(leases.len(), 2);
+        assert_eq!(allocator.in_use(), 2);
+    }
+}
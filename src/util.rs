@@ -1,6 +1,10 @@
-use std::{ffi::OsStr, process::Command};
+use std::{ffi::OsStr, io, path::Path, process::Command};
 
-use color_eyre::{eyre::bail, Result};
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
+use tracing::{debug, info};
 
 pub fn run_sudo(command: impl AsRef<OsStr>) -> Result<()> {
     let output = Command::new("sudo")
@@ -18,3 +22,64 @@ pub fn run_sudo(command: impl AsRef<OsStr>) -> Result<()> {
     }
     Ok(())
 }
+
+/// Remove a file, doing nothing if it does not exist.
+pub fn remove_file_if_exists(path: impl AsRef<Path>) -> Result<()> {
+    match std::fs::remove_file(path.as_ref()) {
+        Ok(()) => {
+            info!("Removed {}", path.as_ref().display());
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            debug!(
+                "{} does not exist, not removing it",
+                path.as_ref().display()
+            );
+            Ok(())
+        }
+        Err(err) => {
+            Err(err).with_context(|| format!("Could not remove {}", path.as_ref().display()))
+        }
+    }
+}
+
+/// Remove a directory and its contents, doing nothing if it does not exist.
+pub fn remove_dir_if_exists(path: impl AsRef<Path>) -> Result<()> {
+    match std::fs::remove_dir_all(path.as_ref()) {
+        Ok(()) => {
+            info!("Removed {}", path.as_ref().display());
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            debug!(
+                "{} does not exist, not removing it",
+                path.as_ref().display()
+            );
+            Ok(())
+        }
+        Err(err) => {
+            Err(err).with_context(|| format!("Could not remove {}", path.as_ref().display()))
+        }
+    }
+}
+
+/// Create a copy of a file, sharing the data blocks with the original if the filesystem supports it (copy-on-write) and
+/// creating a sparse file otherwise.
+pub fn clone_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    let output = Command::new("cp")
+        .arg("--reflink=auto")
+        .arg("--sparse=always")
+        .arg(from.as_ref())
+        .arg(to.as_ref())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Could not clone {} to {}: {}",
+            from.as_ref().display(),
+            to.as_ref().display(),
+            stderr.trim()
+        );
+    }
+    Ok(())
+}